@@ -1,11 +1,15 @@
+#[cfg(windows)]
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
+#[cfg(windows)]
 mod misc;
 
+#[cfg(windows)]
 static RUNNING: AtomicBool = AtomicBool::new(true);
 
+#[cfg(windows)]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenvy::dotenv().ok();
     env_logger::init();
@@ -54,3 +58,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Shutdown complete");
     Ok(())
 }
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("This example requires Windows");
+}
@@ -3,6 +3,7 @@
 //! You can see packets being received by wintun by runnig: `nc -u 10.28.13.100 4321`
 //! and sending lines of text.
 
+#[cfg(windows)]
 use std::{
     net::{IpAddr, SocketAddr},
     sync::{
@@ -11,12 +12,15 @@ use std::{
         Arc,
     },
 };
+#[cfg(windows)]
 use windows_sys::Win32::{
     Foundation::FALSE,
     Security::Cryptography::{CryptAcquireContextW, CryptGenRandom, CryptReleaseContext, PROV_RSA_FULL},
 };
+#[cfg(windows)]
 mod misc;
 
+#[cfg(windows)]
 #[derive(Debug)]
 struct NaiveUdpPacket {
     src_addr: SocketAddr,
@@ -24,6 +28,7 @@ struct NaiveUdpPacket {
     data: Vec<u8>,
 }
 
+#[cfg(windows)]
 impl NaiveUdpPacket {
     fn new(src_addr: SocketAddr, dst_addr: SocketAddr, data: &[u8]) -> Self {
         Self {
@@ -34,6 +39,7 @@ impl NaiveUdpPacket {
     }
 }
 
+#[cfg(windows)]
 impl std::fmt::Display for NaiveUdpPacket {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -46,6 +52,7 @@ impl std::fmt::Display for NaiveUdpPacket {
     }
 }
 
+#[cfg(windows)]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenvy::dotenv().ok();
     env_logger::init();
@@ -171,35 +178,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let v = generate_random_bytes(2)?;
                 let id = u16::from_ne_bytes([v[0], v[1]]);
 
-                // build response IP packet
-                use packet::Builder;
-                let ip_packet = packet::ip::v4::Builder::default()
-                    .id(id)?
-                    .ttl(64)?
-                    .source(src_addr)?
-                    .destination(dst_addr)?
-                    .udp()?
-                    .source(resp.src_addr.port())?
-                    .destination(resp.dst_addr.port())?
-                    .payload(&resp.data)?
-                    .build()?;
-
-                // // The following code will be better than above, the `ipv4_udp_build` function link is
-                // //
-                // // https://github.com/pysrc/study-udp/blob/59d7ba210a022d207c60ad5370de37110fefaefb/src/protocol.rs#L157-L252
-                // //
-                // let mut ip_packet = vec![0u8; 28 + resp.data.len()];
-                // protocol::ipv4_udp_build(
-                //     &mut ip_packet,
-                //     &src_addr.octets(),
-                //     resp.src_addr.port(),
-                //     &dst_addr.octets(),
-                //     resp.dst_addr.port(),
-                //     &resp.data,
-                // );
-
-                let mut write_pack = writer_session.allocate_send_packet(ip_packet.len() as u16)?;
-                write_pack.bytes_mut().copy_from_slice(ip_packet.as_ref());
+                // build the response IP packet directly in the send ring
+                let write_pack = wintun::PacketBuilder::ipv4(src_addr, dst_addr)
+                    .identification(id)
+                    .ttl(64)
+                    .udp(resp.src_addr.port(), resp.dst_addr.port())
+                    .payload(&resp.data)
+                    .build(&writer_session)?;
 
                 // Send the response packet
                 writer_session.send_packet(write_pack);
@@ -223,40 +208,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[cfg(windows)]
 fn extract_udp_packet(packet: &[u8]) -> Result<NaiveUdpPacket, wintun::Error> {
-    use packet::{ip, udp, AsPacket, Packet};
-    let packet: ip::Packet<_> = packet.as_packet().map_err(|err| format!("{}", err))?;
-    let info: String;
-    match packet {
-        ip::Packet::V4(a) => {
-            let src_addr = a.source();
-            let dst_addr = a.destination();
-            let protocol = a.protocol();
-            let payload = a.payload();
-            match protocol {
-                ip::Protocol::Udp => {
-                    let udp = udp::Packet::new(payload).map_err(|err| format!("{}", err))?;
-                    let src_port = udp.source();
-                    let dst_port = udp.destination();
-                    let src_addr = SocketAddr::new(src_addr.into(), src_port);
-                    let dst_addr = SocketAddr::new(dst_addr.into(), dst_port);
-                    let data = udp.payload();
-                    let udp_packet = NaiveUdpPacket::new(src_addr, dst_addr, data);
-                    log::trace!("{protocol:?} {}", udp_packet);
-                    return Ok(udp_packet);
-                }
-                _ => {
-                    info = format!("{:?} src={}, dst={}", protocol, src_addr, dst_addr);
-                }
-            }
-        }
-        ip::Packet::V6(a) => {
-            info = format!("{:?}", a);
-        }
+    use wintun::ip::{IpView, UdpView, PROTOCOL_UDP};
+    let ip = IpView::new(packet)?;
+    let protocol = ip.protocol();
+    if protocol != PROTOCOL_UDP {
+        return Err(format!("Protocol {} src={}, dst={}", protocol, ip.source(), ip.destination()).into());
     }
-    Err(info.into())
+    let udp = UdpView::new(ip.payload())?;
+    let src_addr = SocketAddr::new(ip.source(), udp.source_port());
+    let dst_addr = SocketAddr::new(ip.destination(), udp.destination_port());
+    let udp_packet = NaiveUdpPacket::new(src_addr, dst_addr, udp.payload());
+    log::trace!("UDP {}", udp_packet);
+    Ok(udp_packet)
 }
 
+#[cfg(windows)]
 fn generate_random_bytes(len: usize) -> std::io::Result<Vec<u8>> {
     let mut buf = vec![0u8; len];
     unsafe {
@@ -274,3 +242,8 @@ fn generate_random_bytes(len: usize) -> std::io::Result<Vec<u8>> {
     };
     Ok(buf)
 }
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("This example requires Windows");
+}
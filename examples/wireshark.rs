@@ -2,7 +2,7 @@
 //! writes all routed packets to a pcap file for analysis in Wireshark
 //! Must be run as Administrator
 
-use packet::Builder;
+#[cfg(windows)]
 use std::{
     fs::File,
     net::IpAddr,
@@ -12,17 +12,23 @@ use std::{
     },
     time::{SystemTime, UNIX_EPOCH},
 };
+#[cfg(windows)]
 use subprocess::{Popen, PopenConfig, Redirection};
+#[cfg(windows)]
 use windows_sys::Win32::{
     Foundation::NO_ERROR,
     NetworkManagement::IpHelper::{GetBestRoute, MIB_IPFORWARDROW},
     Networking::WinSock::{AF_INET, AF_INET6, SOCKADDR_INET},
 };
+#[cfg(windows)]
 use wintun::{format_message, Error};
+#[cfg(windows)]
 mod misc;
 
+#[cfg(windows)]
 static RUNNING: AtomicBool = AtomicBool::new(true);
 
+#[cfg(windows)]
 /// Converts a rust ip addr to a SOCKADDR_INET
 fn _ip_addr_to_win_addr(addr: IpAddr) -> SOCKADDR_INET {
     let mut result: SOCKADDR_INET = unsafe { std::mem::zeroed() };
@@ -40,16 +46,19 @@ fn _ip_addr_to_win_addr(addr: IpAddr) -> SOCKADDR_INET {
     result
 }
 
+#[cfg(windows)]
 pub enum RouteCmdKind {
     Add,
     Set,
 }
 
+#[cfg(windows)]
 pub struct RouteCmd {
     pub kind: RouteCmdKind,
     pub cmd: String,
 }
 
+#[cfg(windows)]
 impl RouteCmd {
     pub fn add(cmd: String) -> Self {
         Self {
@@ -66,6 +75,7 @@ impl RouteCmd {
     }
 }
 
+#[cfg(windows)]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenvy::dotenv().ok();
     env_logger::init();
@@ -196,9 +206,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 log::info!("Got error while reading: {}", err);
                 break;
             }
-            let mut packet = packet?;
+            let packet = packet?;
             packet_count += 1;
-            let bytes = packet.bytes_mut();
+            let bytes = packet.bytes();
             let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
             let packet = pcap_file::pcap::PcapPacket::new(now, bytes.len() as u32, bytes);
             writer
@@ -215,25 +225,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             _ => panic!("Address must be ipv4"),
         };
         while RUNNING.load(Ordering::Relaxed) {
-            let mut packet = writer_session.allocate_send_packet(28).unwrap();
-            let buf = packet::buffer::Slice::new(packet.bytes_mut());
-
-            //Send random ICMP request
-            let _ = packet::ip::v4::Builder::with(buf)?
-                .id(0x2d87)?
-                .ttl(64)?
-                .source("10.6.7.8".parse().unwrap())?
-                .destination(v4_dest)?
-                .icmp()?
-                .echo()?
-                .request()?
-                .identifier(42)?
-                .sequence(2)?
-                .build()?;
+            //Send random ICMP echo request with identifier 42 and sequence number 2
+            let packet = wintun::PacketBuilder::ipv4("10.6.7.8".parse().unwrap(), v4_dest)
+                .identification(0x2d87)
+                .ttl(64)
+                .icmp(8, 0, [0, 42, 0, 2])
+                .build(&writer_session)?;
             writer_session.send_packet(packet);
             std::thread::sleep(std::time::Duration::from_secs(1));
         }
-        Ok::<(), packet::Error>(())
+        Ok::<(), Error>(())
     });
 
     std::thread::sleep(std::time::Duration::from_secs(1));
@@ -288,3 +289,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     //`main_session` and `adapter` are both dropped
     Ok(())
 }
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("This example requires Windows");
+}
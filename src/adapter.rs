@@ -4,48 +4,52 @@
 /// The [`Adapter::create`] and [`Adapter::open`] functions serve as the entry point to using
 /// wintun functionality
 use crate::{
-    error::{Error, OutOfRangeData},
-    session,
+    api::AdapterHandle,
+    error::Error,
+    event, metrics, ring, session,
+    stats::Counters,
     util::{self, UnsafeHandle},
-    wintun_raw, Wintun,
+    UnsentPacketPolicy, Wintun,
 };
 use std::{
-    ffi::OsStr,
-    net::{IpAddr, Ipv4Addr},
-    os::windows::prelude::OsStrExt,
-    process::Command,
     ptr,
+    sync::atomic::{AtomicBool, AtomicU8},
     sync::Arc,
     sync::OnceLock,
 };
 use windows_sys::{
     core::GUID,
     Win32::{
-        Foundation::FALSE,
-        NetworkManagement::{
-            IpHelper::{ConvertLengthToIpv4Mask, IP_ADAPTER_ADDRESSES_LH},
-            Ndis::NET_LUID_LH,
-        },
-        System::{Com::CLSIDFromString, Threading::CreateEventA},
+        Foundation::{ERROR_FILE_NOT_FOUND, ERROR_NOT_FOUND},
+        NetworkManagement::Ndis::NET_LUID_LH,
+    },
+};
+#[cfg(windows)]
+use {
+    std::{
+        net::{IpAddr, Ipv4Addr},
+        process::Command,
+    },
+    windows_sys::Win32::{
+        NetworkManagement::IpHelper::{ConvertLengthToIpv4Mask, IP_ADAPTER_ADDRESSES_LH},
+        System::Com::CLSIDFromString,
     },
 };
 
 /// Wrapper around a <https://git.zx2c4.com/wintun/about/#wintun_adapter_handle>
 pub struct Adapter {
-    adapter: UnsafeHandle<wintun_raw::WINTUN_ADAPTER_HANDLE>,
+    adapter: UnsafeHandle<AdapterHandle>,
     wintun: Wintun,
     guid: u128,
-}
-
-fn get_adapter_luid(wintun: &Wintun, adapter: wintun_raw::WINTUN_ADAPTER_HANDLE) -> NET_LUID_LH {
-    let mut luid: wintun_raw::NET_LUID = unsafe { std::mem::zeroed() };
-    unsafe { wintun.WintunGetAdapterLUID(adapter, &mut luid as *mut wintun_raw::NET_LUID) };
-    unsafe { std::mem::transmute(luid) }
+    /// The name this adapter was created or opened with, used where the friendly name cannot be
+    /// looked up
+    name: String,
 }
 
 impl Adapter {
     /// Returns the `Friendly Name` of this adapter,
     /// which is the human readable name shown in Windows
+    #[cfg(windows)]
     pub fn get_name(&self) -> Result<String, Error> {
         let name = util::guid_to_win_style_string(&GUID::from_u128(self.guid))?;
         let mut friendly_name = None;
@@ -64,6 +68,7 @@ impl Adapter {
     /// which is the human readable name shown in Windows
     ///
     /// Note: This is different from `Adapter Name`, which is a GUID.
+    #[cfg(windows)]
     pub fn set_name(&self, name: &str) -> Result<(), Error> {
         // use command `netsh interface set interface name="oldname" newname="mynewname"`
 
@@ -83,6 +88,16 @@ impl Adapter {
         self.guid
     }
 
+    /// Returns the friendly name of this adapter, or the name it was created or opened with if
+    /// that cannot be looked up
+    pub(crate) fn display_name(&self) -> String {
+        #[cfg(windows)]
+        if let Ok(name) = self.get_name() {
+            return name;
+        }
+        self.name.clone()
+    }
+
     /// Creates a new wintun adapter inside the name `name` with tunnel type `tunnel_type`
     ///
     /// Optionally a GUID can be specified that will become the GUID of this adapter once created.
@@ -92,71 +107,85 @@ impl Adapter {
         let name_utf16: Vec<_> = name.encode_utf16().chain(std::iter::once(0)).collect();
         let tunnel_type_utf16: Vec<u16> = tunnel_type.encode_utf16().chain(std::iter::once(0)).collect();
 
-        let guid = match guid {
-            Some(guid) => guid,
-            None => {
-                let mut guid: GUID = unsafe { std::mem::zeroed() };
-                unsafe { windows_sys::Win32::System::Rpc::UuidCreate(&mut guid as *mut GUID) };
-                util::win_guid_to_u128(&guid)
-            }
-        };
+        let guid = guid.unwrap_or_else(util::new_guid);
 
         crate::log::set_default_logger_if_unset(wintun);
 
-        let guid_struct: wintun_raw::GUID = unsafe { std::mem::transmute(GUID::from_u128(guid)) };
-        let guid_ptr = &guid_struct as *const wintun_raw::GUID;
+        let guid_struct = GUID::from_u128(guid);
+        let guid_ptr = &guid_struct as *const GUID;
 
-        let result = unsafe { wintun.WintunCreateAdapter(name_utf16.as_ptr(), tunnel_type_utf16.as_ptr(), guid_ptr) };
+        let result = unsafe { wintun.create_adapter(name_utf16.as_ptr(), tunnel_type_utf16.as_ptr(), guid_ptr) };
 
-        if result.is_null() {
-            Err("Failed to create adapter".into())
-        } else {
-            Ok(Arc::new(Adapter {
+        match result {
+            Err(e) => Err(Error::wintun("WintunCreateAdapter", e)),
+            Ok(result) => Ok(Arc::new(Adapter {
                 adapter: UnsafeHandle(result),
                 wintun: wintun.clone(),
                 guid,
-            }))
+                name: name.to_owned(),
+            })),
         }
     }
 
-    /// Attempts to open an existing wintun interface name `name`.
+    /// Attempts to open an existing wintun interface name `name`. Returns Err([`Error::AdapterNotFound`])
+    /// if there is none.
     ///
     /// Adapters opened via this call will have an unknown GUID meaning [`Adapter::get_adapter_index`]
     /// will always fail because knowing the adapter's GUID is required to determine its index.
+    /// On platforms other than Windows [`Adapter::get_guid`] returns 0 for them.
     /// Currently a workaround is to delete and re-create a new adapter every time one is needed so
     /// that it gets created with a known GUID, allowing [`Adapter::get_adapter_index`] to works as
     /// expected. There is likely a way to get the GUID of our adapter using the Windows Registry
     /// or via the Win32 API, so PR's that solve this issue are always welcome!
     pub fn open(wintun: &Wintun, name: &str) -> Result<Arc<Adapter>, Error> {
-        let name_utf16: Vec<u16> = OsStr::new(name).encode_wide().chain(std::iter::once(0)).collect();
+        let name_utf16: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
 
         crate::log::set_default_logger_if_unset(wintun);
 
-        let result = unsafe { wintun.WintunOpenAdapter(name_utf16.as_ptr()) };
-
-        if result.is_null() {
-            Err("WintunOpenAdapter failed".into())
-        } else {
-            let mut guid = None;
-            util::get_adapters_addresses(|address: IP_ADAPTER_ADDRESSES_LH| {
-                let frindly_name = unsafe { util::win_pwstr_to_string(address.FriendlyName)? };
-                if frindly_name == name {
-                    let adapter_name = unsafe { util::win_pstr_to_string(address.AdapterName) }?;
-                    let adapter_name_utf16: Vec<u16> = adapter_name.encode_utf16().chain(std::iter::once(0)).collect();
-                    let adapter_name_ptr: *const u16 = adapter_name_utf16.as_ptr();
-                    let mut adapter: GUID = unsafe { std::mem::zeroed() };
-                    unsafe { CLSIDFromString(adapter_name_ptr, &mut adapter as *mut GUID) };
-                    guid = Some(adapter);
-                }
-                Ok(())
-            })?;
-            let guid = util::win_guid_to_u128(&guid.ok_or("Unable to find matching GUID")?);
-            Ok(Arc::new(Adapter {
-                adapter: UnsafeHandle(result),
-                wintun: wintun.clone(),
-                guid,
-            }))
-        }
+        let result = match unsafe { wintun.open_adapter(name_utf16.as_ptr()) } {
+            Ok(result) => result,
+            Err(code @ (ERROR_FILE_NOT_FOUND | ERROR_NOT_FOUND)) => {
+                return Err(Error::AdapterNotFound {
+                    operation: "WintunOpenAdapter",
+                    code,
+                })
+            }
+            Err(e) => return Err(Error::wintun("WintunOpenAdapter", e)),
+        };
+        let mut adapter = Adapter {
+            adapter: UnsafeHandle(result),
+            wintun: wintun.clone(),
+            guid: 0,
+            name: name.to_owned(),
+        };
+        //Dropping the adapter closes the handle if the lookup fails
+        adapter.guid = Self::find_guid(name)?;
+        Ok(Arc::new(adapter))
+    }
+
+    /// Looks up the GUID of the adapter with the friendly name `name`
+    #[cfg(windows)]
+    fn find_guid(name: &str) -> Result<u128, Error> {
+        let mut guid = None;
+        util::get_adapters_addresses(|address: IP_ADAPTER_ADDRESSES_LH| {
+            let frindly_name = unsafe { util::win_pwstr_to_string(address.FriendlyName)? };
+            if frindly_name == name {
+                let adapter_name = unsafe { util::win_pstr_to_string(address.AdapterName) }?;
+                let adapter_name_utf16: Vec<u16> = adapter_name.encode_utf16().chain(std::iter::once(0)).collect();
+                let adapter_name_ptr: *const u16 = adapter_name_utf16.as_ptr();
+                let mut adapter: GUID = unsafe { std::mem::zeroed() };
+                unsafe { CLSIDFromString(adapter_name_ptr, &mut adapter as *mut GUID) };
+                guid = Some(adapter);
+            }
+            Ok(())
+        })?;
+        Ok(util::win_guid_to_u128(&guid.ok_or("Unable to find matching GUID")?))
+    }
+
+    /// Other platforms have no network stack to look the GUID up in, so it is reported as 0
+    #[cfg(not(windows))]
+    fn find_guid(_name: &str) -> Result<u128, Error> {
+        Ok(0)
     }
 
     /// Delete an adapter, consuming it in the process
@@ -173,42 +202,41 @@ impl Adapter {
     /// Capacity is the size in bytes of the ring buffer used internally by the driver. Must be
     /// a power of two between [`crate::MIN_RING_CAPACITY`] and [`crate::MAX_RING_CAPACITY`] inclusive.
     pub fn start_session(self: &Arc<Self>, capacity: u32) -> Result<session::Session, Error> {
-        let range = crate::MIN_RING_CAPACITY..=crate::MAX_RING_CAPACITY;
-        if !range.contains(&capacity) {
-            return Err(Error::CapacityOutOfRange(OutOfRangeData { range, value: capacity }));
-        }
-        if !capacity.is_power_of_two() {
-            return Err(Error::CapacityNotPowerOfTwo(capacity));
-        }
-
-        let result = unsafe { self.wintun.WintunStartSession(self.adapter.0, capacity) };
-
-        if result.is_null() {
-            Err("WintunStartSession failed".into())
-        } else {
-            let shutdown_event = unsafe { CreateEventA(std::ptr::null_mut(), FALSE, FALSE, std::ptr::null_mut()) };
-            Ok(session::Session {
-                session: UnsafeHandle(result),
-                wintun: self.wintun.clone(),
-                read_event: OnceLock::new(),
-                shutdown_event,
-                adapter: Arc::clone(self),
-            })
-        }
+        ring::check_capacity(capacity)?;
+
+        let result = unsafe { self.wintun.start_session(self.adapter.0, capacity) }
+            .map_err(|e| Error::wintun("WintunStartSession", e))?;
+
+        //Manual reset so that once signaled, every current and future waiter is released
+        let shutdown_event = event::create(true, false)?;
+        let counters = Arc::new(Counters::default());
+        metrics::register(&counters, self);
+        Ok(session::Session {
+            session: UnsafeHandle(result),
+            wintun: self.wintun.clone(),
+            read_event: OnceLock::new(),
+            shutdown_event,
+            shut_down: AtomicBool::new(false),
+            unsent_policy: AtomicU8::new(UnsentPacketPolicy::default() as u8),
+            counters,
+            adapter: Arc::clone(self),
+        })
     }
 
     /// Returns the Win32 LUID for this adapter
     pub fn get_luid(&self) -> NET_LUID_LH {
-        get_adapter_luid(&self.wintun, self.adapter.0)
+        unsafe { self.wintun.get_adapter_luid(self.adapter.0) }
     }
 
     /// Set `MTU` of this adapter
+    #[cfg(windows)]
     pub fn set_mtu(&self, mtu: usize) -> Result<(), Error> {
         let name = self.get_name()?;
         Ok(util::set_adapter_mtu(&name, mtu)?)
     }
 
     /// Returns `MTU` of this adapter
+    #[cfg(windows)]
     pub fn get_mtu(&self) -> Result<usize, Error> {
         let luid = self.get_luid();
         Ok(util::get_adapter_mtu(&luid)?)
@@ -216,6 +244,7 @@ impl Adapter {
 
     /// Returns the Win32 interface index of this adapter. Useful for specifying the interface
     /// when executing `netsh interface ip` commands
+    #[cfg(windows)]
     pub fn get_adapter_index(&self) -> Result<u32, Error> {
         let name = util::guid_to_win_style_string(&GUID::from_u128(self.guid))?;
         let mut adapter_index = None;
@@ -232,6 +261,7 @@ impl Adapter {
     }
 
     /// Sets the IP address for this adapter, using command `netsh`.
+    #[cfg(windows)]
     pub fn set_address(&self, address: Ipv4Addr) -> Result<(), Error> {
         let binding = self.get_addresses()?;
         let old_address = binding.iter().find(|addr| matches!(addr, IpAddr::V4(_)));
@@ -249,6 +279,7 @@ impl Adapter {
     }
 
     /// Sets the gateway for this adapter, using command `netsh`.
+    #[cfg(windows)]
     pub fn set_gateway(&self, gateway: Option<Ipv4Addr>) -> Result<(), Error> {
         let binding = self.get_addresses()?;
         let address = binding.iter().find(|addr| matches!(addr, IpAddr::V4(_)));
@@ -263,6 +294,7 @@ impl Adapter {
     }
 
     /// Sets the subnet mask for this adapter, using command `netsh`.
+    #[cfg(windows)]
     pub fn set_netmask(&self, mask: Ipv4Addr) -> Result<(), Error> {
         let binding = self.get_addresses()?;
         let address = binding.iter().find(|addr| matches!(addr, IpAddr::V4(_)));
@@ -280,6 +312,7 @@ impl Adapter {
     }
 
     /// Sets the DNS servers for this adapter
+    #[cfg(windows)]
     pub fn set_dns_servers(&self, dns_servers: &[IpAddr]) -> Result<(), Error> {
         let interface = GUID::from_u128(self.get_guid());
         if let Err(err) = util::set_interface_dns_servers(interface, dns_servers) {
@@ -290,6 +323,7 @@ impl Adapter {
     }
 
     /// Sets the network addresses of this adapter, including network address, subnet mask, and gateway
+    #[cfg(windows)]
     pub fn set_network_addresses_tuple(
         &self,
         address: IpAddr,
@@ -321,6 +355,7 @@ impl Adapter {
     }
 
     /// Returns the IP addresses of this adapter, including IPv4 and IPv6 addresses
+    #[cfg(windows)]
     pub fn get_addresses(&self) -> Result<Vec<IpAddr>, Error> {
         let name = util::guid_to_win_style_string(&GUID::from_u128(self.guid))?;
 
@@ -350,6 +385,7 @@ impl Adapter {
     }
 
     /// Returns the gateway addresses of this adapter, including IPv4 and IPv6 addresses
+    #[cfg(windows)]
     pub fn get_gateways(&self) -> Result<Vec<IpAddr>, Error> {
         let name = util::guid_to_win_style_string(&GUID::from_u128(self.guid))?;
         let mut gateways = vec![];
@@ -376,6 +412,7 @@ impl Adapter {
     }
 
     /// Returns the subnet mask of the given address
+    #[cfg(windows)]
     pub fn get_netmask_of_address(&self, target_address: &IpAddr) -> Result<IpAddr, Error> {
         let name = util::guid_to_win_style_string(&GUID::from_u128(self.guid))?;
         let mut subnet_mask = None;
@@ -423,7 +460,65 @@ impl Drop for Adapter {
     fn drop(&mut self) {
         //Close adapter on drop
         //This is why we need an Arc of wintun
-        unsafe { self.wintun.WintunCloseAdapter(self.adapter.0) };
+        unsafe { self.wintun.close_adapter(self.adapter.0) };
         self.adapter = UnsafeHandle(ptr::null_mut());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FakeCall, FakeWintun};
+    use windows_sys::Win32::Foundation::ERROR_ACCESS_DENIED;
+
+    fn fake() -> (Arc<FakeWintun>, Wintun) {
+        let fake = Arc::new(FakeWintun::new());
+        let wintun: Wintun = fake.clone();
+        (fake, wintun)
+    }
+
+    #[test]
+    #[cfg_attr(windows, ignore = "the GUID is looked up among the adapters of the system")]
+    fn open_existing_adapter() {
+        let (fake, wintun) = fake();
+        let created = Adapter::create(&wintun, "Test", "Test", None).unwrap();
+        let opened = Adapter::open(&wintun, "Test").unwrap();
+        assert_eq!(unsafe { opened.get_luid().Value }, unsafe { created.get_luid().Value });
+        assert_eq!(fake.open_adapters(), 2);
+    }
+
+    #[test]
+    fn open_missing_adapter() {
+        let (fake, wintun) = fake();
+        let err = Adapter::open(&wintun, "Missing").err().unwrap();
+        assert!(matches!(
+            err,
+            Error::AdapterNotFound {
+                operation: "WintunOpenAdapter",
+                code: ERROR_FILE_NOT_FOUND
+            }
+        ));
+        assert_eq!(fake.open_adapters(), 0);
+    }
+
+    #[test]
+    fn open_maps_errors() {
+        let (fake, wintun) = fake();
+        let _adapter = Adapter::create(&wintun, "Test", "Test", None).unwrap();
+
+        fake.fail_next(FakeCall::OpenAdapter, ERROR_NOT_FOUND);
+        let err = Adapter::open(&wintun, "Test").err().unwrap();
+        assert!(matches!(
+            err,
+            Error::AdapterNotFound {
+                code: ERROR_NOT_FOUND,
+                ..
+            }
+        ));
+
+        fake.fail_next(FakeCall::OpenAdapter, ERROR_ACCESS_DENIED);
+        let err = Adapter::open(&wintun, "Test").err().unwrap();
+        assert!(matches!(err, Error::AccessDenied { .. }));
+        assert_eq!(err.operation(), Some("WintunOpenAdapter"));
+    }
+}
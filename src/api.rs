@@ -0,0 +1,225 @@
+//! The set of Wintun* entry points that [`crate::Adapter`] and [`crate::Session`] are built on.
+//!
+//! [`WintunApi`] is implemented for the function table loaded from wintun.dll, and for
+//! [`crate::FakeWintun`], a pure rust driver that needs neither Windows nor administrator rights.
+//! Each method maps one to one onto a function documented at <https://git.zx2c4.com/wintun/about/#reference>,
+//! except that failures are returned as the Win32 error code instead of through `GetLastError`.
+
+use std::ffi::c_void;
+use windows_sys::{
+    core::GUID,
+    Win32::{
+        Foundation::{HANDLE, WIN32_ERROR},
+        NetworkManagement::Ndis::NET_LUID_LH,
+    },
+};
+#[cfg(windows)]
+use {
+    crate::wintun_raw,
+    windows_sys::Win32::Foundation::{GetLastError, FALSE},
+};
+
+/// Opaque handle to an adapter. Maps to <https://git.zx2c4.com/wintun/about/#wintun_adapter_handle>
+pub type AdapterHandle = *mut c_void;
+
+/// Opaque handle to a session. Maps to <https://git.zx2c4.com/wintun/about/#wintun_session_handle>
+pub type SessionHandle = *mut c_void;
+
+/// Level of a message passed to a [`LoggerCallback`]. Maps to WINTUN_LOGGER_LEVEL
+pub type LoggerLevel = i32;
+
+/// Informational. Maps to WINTUN_LOG_INFO
+pub const LOG_INFO: LoggerLevel = 0;
+
+/// Warning. Maps to WINTUN_LOG_WARN
+pub const LOG_WARN: LoggerLevel = 1;
+
+/// Error. Maps to WINTUN_LOG_ERR
+pub const LOG_ERR: LoggerLevel = 2;
+
+/// Called by wintun to report diagnostic messages. Maps to WINTUN_LOGGER_CALLBACK
+///
+/// `timestamp` is in 100ns intervals since 1601-01-01 UTC, and `message` is a null terminated UTF-16 string
+pub type LoggerCallback = Option<unsafe extern "system" fn(level: LoggerLevel, timestamp: u64, message: *const u16)>;
+
+/// The functions exported by wintun.dll.
+///
+/// # Safety
+/// Implementors must uphold the contracts described in the wintun reference, most importantly that
+/// packets returned by [`WintunApi::receive_packet`] and [`WintunApi::allocate_send_packet`] point to
+/// non overlapping memory that stays valid until the packet is released, sent, or the session ends.
+pub unsafe trait WintunApi: Send + Sync {
+    /// Maps to WintunCreateAdapter
+    ///
+    /// # Safety
+    /// `name` and `tunnel_type` must be null terminated UTF-16 strings and `requested_guid` must be
+    /// null or point to a valid GUID
+    unsafe fn create_adapter(
+        &self,
+        name: *const u16,
+        tunnel_type: *const u16,
+        requested_guid: *const GUID,
+    ) -> Result<AdapterHandle, WIN32_ERROR>;
+
+    /// Maps to WintunCloseAdapter
+    ///
+    /// # Safety
+    /// `adapter` must be a handle returned by this api that has not been closed yet
+    unsafe fn close_adapter(&self, adapter: AdapterHandle);
+
+    /// Maps to WintunOpenAdapter
+    ///
+    /// # Safety
+    /// `name` must be a null terminated UTF-16 string
+    unsafe fn open_adapter(&self, name: *const u16) -> Result<AdapterHandle, WIN32_ERROR>;
+
+    /// Maps to WintunGetAdapterLUID
+    ///
+    /// # Safety
+    /// `adapter` must be a handle returned by this api that has not been closed yet
+    unsafe fn get_adapter_luid(&self, adapter: AdapterHandle) -> NET_LUID_LH;
+
+    /// Maps to WintunGetRunningDriverVersion
+    fn get_running_driver_version(&self) -> Result<u32, WIN32_ERROR>;
+
+    /// Maps to WintunDeleteDriver
+    fn delete_driver(&self) -> Result<(), WIN32_ERROR>;
+
+    /// Maps to WintunSetLogger
+    fn set_logger(&self, callback: LoggerCallback);
+
+    /// Maps to WintunStartSession
+    ///
+    /// # Safety
+    /// `adapter` must be a handle returned by this api that has not been closed yet
+    unsafe fn start_session(&self, adapter: AdapterHandle, capacity: u32) -> Result<SessionHandle, WIN32_ERROR>;
+
+    /// Maps to WintunEndSession
+    ///
+    /// # Safety
+    /// `session` must be a handle returned by this api that has not been ended yet
+    unsafe fn end_session(&self, session: SessionHandle);
+
+    /// Maps to WintunGetReadWaitEvent. The returned event must not be closed by the caller
+    ///
+    /// # Safety
+    /// `session` must be a handle returned by this api that has not been ended yet
+    unsafe fn get_read_wait_event(&self, session: SessionHandle) -> HANDLE;
+
+    /// Maps to WintunReceivePacket, returning the packet and its size in bytes
+    ///
+    /// # Safety
+    /// `session` must be a handle returned by this api that has not been ended yet
+    unsafe fn receive_packet(&self, session: SessionHandle) -> Result<(*mut u8, u32), WIN32_ERROR>;
+
+    /// Maps to WintunReleaseReceivePacket
+    ///
+    /// # Safety
+    /// `packet` must have been returned by [`WintunApi::receive_packet`] on `session` and not released yet
+    unsafe fn release_receive_packet(&self, session: SessionHandle, packet: *const u8);
+
+    /// Maps to WintunAllocateSendPacket
+    ///
+    /// # Safety
+    /// `session` must be a handle returned by this api that has not been ended yet
+    unsafe fn allocate_send_packet(&self, session: SessionHandle, size: u32) -> Result<*mut u8, WIN32_ERROR>;
+
+    /// Maps to WintunSendPacket
+    ///
+    /// # Safety
+    /// `packet` must have been returned by [`WintunApi::allocate_send_packet`] on `session` and not sent yet
+    unsafe fn send_packet(&self, session: SessionHandle, packet: *const u8);
+}
+
+#[cfg(windows)]
+fn last_error() -> WIN32_ERROR {
+    unsafe { GetLastError() }
+}
+
+#[cfg(windows)]
+fn non_null<T>(ptr: *mut T) -> Result<*mut T, WIN32_ERROR> {
+    if ptr.is_null() {
+        Err(last_error())
+    } else {
+        Ok(ptr)
+    }
+}
+
+/// SAFETY: The function table forwards every call to wintun.dll, which upholds the wintun contracts
+#[cfg(windows)]
+unsafe impl WintunApi for wintun_raw::wintun {
+    unsafe fn create_adapter(
+        &self,
+        name: *const u16,
+        tunnel_type: *const u16,
+        requested_guid: *const GUID,
+    ) -> Result<AdapterHandle, WIN32_ERROR> {
+        non_null(self.WintunCreateAdapter(name, tunnel_type, requested_guid as *const wintun_raw::GUID) as _)
+    }
+
+    unsafe fn close_adapter(&self, adapter: AdapterHandle) {
+        self.WintunCloseAdapter(adapter as _)
+    }
+
+    unsafe fn open_adapter(&self, name: *const u16) -> Result<AdapterHandle, WIN32_ERROR> {
+        non_null(self.WintunOpenAdapter(name) as _)
+    }
+
+    unsafe fn get_adapter_luid(&self, adapter: AdapterHandle) -> NET_LUID_LH {
+        let mut luid: wintun_raw::NET_LUID = std::mem::zeroed();
+        self.WintunGetAdapterLUID(adapter as _, &mut luid as *mut wintun_raw::NET_LUID);
+        std::mem::transmute(luid)
+    }
+
+    fn get_running_driver_version(&self) -> Result<u32, WIN32_ERROR> {
+        match unsafe { self.WintunGetRunningDriverVersion() } {
+            0 => Err(last_error()),
+            version => Ok(version),
+        }
+    }
+
+    fn delete_driver(&self) -> Result<(), WIN32_ERROR> {
+        match unsafe { self.WintunDeleteDriver() } {
+            FALSE => Err(last_error()),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_logger(&self, callback: LoggerCallback) {
+        //SAFETY: "system" is "stdcall" on 32 bit x86 and the C calling convention on every other
+        //windows target, which is the same as what "stdcall" means there
+        unsafe {
+            self.WintunSetLogger(std::mem::transmute::<LoggerCallback, wintun_raw::WINTUN_LOGGER_CALLBACK>(callback))
+        }
+    }
+
+    unsafe fn start_session(&self, adapter: AdapterHandle, capacity: u32) -> Result<SessionHandle, WIN32_ERROR> {
+        non_null(self.WintunStartSession(adapter as _, capacity) as _)
+    }
+
+    unsafe fn end_session(&self, session: SessionHandle) {
+        self.WintunEndSession(session as _)
+    }
+
+    unsafe fn get_read_wait_event(&self, session: SessionHandle) -> HANDLE {
+        self.WintunGetReadWaitEvent(session as _) as _
+    }
+
+    unsafe fn receive_packet(&self, session: SessionHandle) -> Result<(*mut u8, u32), WIN32_ERROR> {
+        let mut size = 0u32;
+        let ptr = non_null(self.WintunReceivePacket(session as _, &mut size as *mut u32))?;
+        Ok((ptr, size))
+    }
+
+    unsafe fn release_receive_packet(&self, session: SessionHandle, packet: *const u8) {
+        self.WintunReleaseReceivePacket(session as _, packet)
+    }
+
+    unsafe fn allocate_send_packet(&self, session: SessionHandle, size: u32) -> Result<*mut u8, WIN32_ERROR> {
+        non_null(self.WintunAllocateSendPacket(session as _, size))
+    }
+
+    unsafe fn send_packet(&self, session: SessionHandle, packet: *const u8) {
+        self.WintunSendPacket(session as _, packet)
+    }
+}
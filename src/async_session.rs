@@ -0,0 +1,136 @@
+//! Async packet I/O for a [`Session`].
+//!
+//! Pending receives wait for the session's read event (see [`Session::get_read_wait_event`])
+//! through [`crate::Readiness`], so no runtime specific reactor is involved and the futures work on
+//! any executor.
+
+use crate::{session::SEND_RETRY_INTERVAL, timer, Error, RecvPacket, SendPacket, Session};
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Async wrapper around a <https://git.zx2c4.com/wintun/about/#wintun_session_handle>
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), wintun::Error> {
+/// let (a, b) = wintun::Session::pair()?;
+/// let (a, b) = (wintun::AsyncSession::new(a), Arc::new(wintun::AsyncSession::new(b)));
+///
+/// a.send(&[0x45, 0, 0, 20]).await?;
+/// assert_eq!(b.recv().await?.bytes(), &[0x45, 0, 0, 20]);
+///
+/// //Shutting down wakes pending receives
+/// let reader = tokio::spawn({
+///     let b = b.clone();
+///     async move { b.recv().await.map(|_| ()) }
+/// });
+/// b.shutdown()?;
+/// assert!(matches!(reader.await.unwrap(), Err(wintun::Error::ShuttingDown)));
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncSession {
+    session: Arc<Session>,
+}
+
+impl AsyncSession {
+    pub fn new(session: Arc<Session>) -> Self {
+        Self { session }
+    }
+
+    /// Returns the underlying blocking session
+    pub fn session(&self) -> &Arc<Session> {
+        &self.session
+    }
+
+    /// Waits for the next packet in the receive queue. Returns Err([`Error::ShuttingDown`]) once
+    /// [`AsyncSession::shutdown`] is called
+    pub async fn recv(&self) -> Result<RecvPacket, Error> {
+        let mut readiness = self.session.readiness();
+        std::future::poll_fn(|cx| readiness.poll_recv(cx)).await
+    }
+
+    /// Allocates a send packet of the specified size like [`Session::allocate_send_packet`], waiting
+    /// for space to free up if the send ring is full
+    pub async fn allocate_send_packet(&self, size: u16) -> Result<SendPacket, Error> {
+        std::future::poll_fn(|cx| self.poll_allocate_send_packet(cx, size)).await
+    }
+
+    fn poll_allocate_send_packet(&self, cx: &mut Context<'_>, size: u16) -> Poll<Result<SendPacket, Error>> {
+        match self.session.allocate_send_packet(size) {
+            Err(Error::RingFull { .. }) => {
+                timer::wake_after(SEND_RETRY_INTERVAL, cx.waker().clone());
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    /// Sends a packet with the contents of `bytes`, waiting for space to free up if the send ring
+    /// is full
+    pub async fn send(&self, bytes: &[u8]) -> Result<(), Error> {
+        let size = self.session.packet_size(bytes.len())?;
+        let mut packet = self.allocate_send_packet(size).await?;
+        packet.bytes_mut().copy_from_slice(bytes);
+        self.session.send_packet(packet);
+        Ok(())
+    }
+
+    /// Wakes up a pending [`AsyncSession::recv`], making it return Err([`Error::ShuttingDown`])
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.session.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FakeWintun, Wintun, MAX_IP_PACKET_SIZE, MIN_RING_CAPACITY};
+    use futures::executor::block_on;
+    use std::time::Duration;
+
+    /// Returns a session on a fake adapter whose send ring is too full for another packet of the
+    /// largest size
+    fn full_session() -> (Arc<FakeWintun>, AsyncSession) {
+        let fake = Arc::new(FakeWintun::new());
+        let wintun: Wintun = fake.clone();
+        let adapter = crate::Adapter::create(&wintun, "Test", "Test", None).unwrap();
+        let session = Arc::new(adapter.start_session(MIN_RING_CAPACITY).unwrap());
+        session.send_packet(session.allocate_send_packet(MAX_IP_PACKET_SIZE as u16).unwrap());
+        (fake, AsyncSession::new(session))
+    }
+
+    #[test]
+    fn allocate_send_packet_waits_for_space() {
+        let (fake, session) = full_session();
+        let driver = std::thread::spawn({
+            let (fake, session) = (fake.clone(), session.session().clone());
+            move || {
+                std::thread::sleep(Duration::from_millis(50));
+                fake.take_sent_packets(&session).unwrap().len()
+            }
+        });
+        block_on(session.send(&[7; MAX_IP_PACKET_SIZE as usize])).unwrap();
+        assert_eq!(driver.join().unwrap(), 1);
+        let sent = fake.take_sent_packets(session.session()).unwrap();
+        assert_eq!(sent, vec![vec![7; MAX_IP_PACKET_SIZE as usize]]);
+    }
+
+    #[test]
+    fn allocate_send_packet_shutdown() {
+        let (_fake, session) = full_session();
+        let session = Arc::new(session);
+        let waiter = std::thread::spawn({
+            let session = session.clone();
+            move || block_on(session.allocate_send_packet(MAX_IP_PACKET_SIZE as u16)).map(drop)
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        session.shutdown().unwrap();
+        assert!(matches!(waiter.join().unwrap(), Err(Error::ShuttingDown)));
+    }
+}
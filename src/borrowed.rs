@@ -0,0 +1,223 @@
+//! Packets that borrow their session instead of sharing ownership of it.
+//!
+//! Every [`crate::SendPacket`] and [`crate::RecvPacket`] holds an `Arc<Session>`, so creating and
+//! dropping one costs two atomic operations. [`BorrowedSendPacket`] and [`BorrowedRecvPacket`] are
+//! tied to a `&Session` instead, which makes them free to create on hot paths that receive and
+//! send on the thread that owns the session.
+
+use crate::{packet, BufferPool, Error, OwnedPacket, Session};
+use std::{
+    mem::ManuallyDrop,
+    slice,
+    time::{Duration, Instant},
+};
+
+/// A send packet that cannot outlive the borrow of the [`Session`] that allocated it
+///
+/// Created with [`Session::allocate_send_packet_borrowed`] and consumed by
+/// [`Session::send_borrowed_packet`]. Use [`crate::SendPacket`] for packets that have to be stored
+/// or moved to other threads independently of the session
+///
+/// # Example
+/// ```
+/// let (a, b) = wintun::Session::pair().unwrap();
+///
+/// let mut packet = a.allocate_send_packet_borrowed(4).unwrap();
+/// packet.bytes_mut().copy_from_slice(&[0x45, 0, 0, 4]);
+/// a.send_borrowed_packet(packet);
+///
+/// let received = b.receive_blocking_borrowed().unwrap();
+/// assert_eq!(received.bytes(), &[0x45, 0, 0, 4]);
+/// ```
+pub struct BorrowedSendPacket<'s> {
+    bytes: &'s mut [u8],
+    session: &'s Session,
+}
+
+impl BorrowedSendPacket<'_> {
+    /// Returns the bytes this packet holds as &mut
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        self.bytes
+    }
+
+    /// Returns an immutable reference to the bytes this packet holds
+    pub fn bytes(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+impl Drop for BorrowedSendPacket<'_> {
+    fn drop(&mut self) {
+        packet::drop_unsent(self.session, self.bytes);
+    }
+}
+
+/// A read only received packet that cannot outlive the borrow of its [`Session`]
+///
+/// Created with [`Session::try_receive_borrowed`] and [`Session::receive_blocking_borrowed`]. Use
+/// [`crate::RecvPacket`] for packets that have to be stored or moved to other threads
+/// independently of the session
+pub struct BorrowedRecvPacket<'s> {
+    bytes: &'s [u8],
+    session: &'s Session,
+}
+
+impl BorrowedRecvPacket<'_> {
+    /// Returns an immutable reference to the bytes this packet holds
+    pub fn bytes(&self) -> &[u8] {
+        self.bytes
+    }
+
+    /// Copies the contents of this packet into a new vector and releases the packet
+    pub fn into_vec(self) -> Vec<u8> {
+        self.bytes.to_vec()
+    }
+
+    /// Copies the contents of this packet into an [`OwnedPacket`] from [`BufferPool::global`] and
+    /// releases the packet before returning
+    pub fn into_owned(self) -> OwnedPacket {
+        BufferPool::global().copy(self.bytes)
+    }
+}
+
+impl Drop for BorrowedRecvPacket<'_> {
+    fn drop(&mut self) {
+        //SAFETY: The borrow keeps the session alive and bytes was handed out by its receive ring
+        unsafe { packet::release_receive(self.session, self.bytes) };
+    }
+}
+
+impl Session {
+    /// Like [`Session::try_receive`], but returns a packet that borrows this session
+    pub fn try_receive_borrowed(&self) -> Result<Option<BorrowedRecvPacket<'_>>, Error> {
+        if self.is_shut_down() {
+            return Err(Error::ShuttingDown);
+        }
+        Ok(self.receive_raw()?.map(|(ptr, size)| BorrowedRecvPacket {
+            //SAFETY: ptr is non null, aligned for u8, readable for size bytes and not handed out
+            //again until the packet is released
+            bytes: unsafe { slice::from_raw_parts(ptr, size) },
+            session: self,
+        }))
+    }
+
+    /// Like [`Session::receive_blocking`], but returns a packet that borrows this session
+    pub fn receive_blocking_borrowed(&self) -> Result<BorrowedRecvPacket<'_>, Error> {
+        match self.receive_until(None, || self.try_receive_borrowed())? {
+            Some(packet) => Ok(packet),
+            None => unreachable!("An infinite wait cannot time out"),
+        }
+    }
+
+    /// Like [`Session::receive_timeout`], but returns a packet that borrows this session
+    pub fn receive_timeout_borrowed(&self, timeout: Duration) -> Result<Option<BorrowedRecvPacket<'_>>, Error> {
+        self.receive_until(Instant::now().checked_add(timeout), || self.try_receive_borrowed())
+    }
+
+    /// Like [`Session::receive_deadline`], but returns a packet that borrows this session
+    pub fn receive_deadline_borrowed(&self, deadline: Instant) -> Result<Option<BorrowedRecvPacket<'_>>, Error> {
+        self.receive_until(Some(deadline), || self.try_receive_borrowed())
+    }
+
+    /// Like [`Session::allocate_send_packet`], but returns a packet that borrows this session. It
+    /// must be sent with [`Session::send_borrowed_packet`]
+    pub fn allocate_send_packet_borrowed(&self, size: u16) -> Result<BorrowedSendPacket<'_>, Error> {
+        let ptr = self.allocate_raw(size)?;
+        Ok(BorrowedSendPacket {
+            //SAFETY: ptr is non null, aligned for u8 and writable for size bytes that belong to
+            //this packet until it is sent
+            bytes: unsafe { slice::from_raw_parts_mut(ptr, size as usize) },
+            session: self,
+        })
+    }
+
+    /// Sends a packet previously allocated with [`Session::allocate_send_packet_borrowed`] on this
+    /// session
+    pub fn send_borrowed_packet(&self, packet: BorrowedSendPacket<'_>) {
+        assert!(
+            std::ptr::eq(packet.session, self),
+            "Packet was not allocated by this session"
+        );
+
+        let packet = ManuallyDrop::new(packet);
+        unsafe { self.submit(packet.bytes.as_ptr(), packet.bytes.len()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FakeWintun, UnsentPacketPolicy, Wintun, MIN_RING_CAPACITY};
+    use std::sync::Arc;
+
+    fn session() -> (Arc<FakeWintun>, Arc<Session>) {
+        let fake = Arc::new(FakeWintun::new());
+        let wintun: Wintun = fake.clone();
+        let adapter = crate::Adapter::create(&wintun, "Test", "Test", None).unwrap();
+        (fake, Arc::new(adapter.start_session(MIN_RING_CAPACITY).unwrap()))
+    }
+
+    #[test]
+    fn received_packets_are_released_on_drop() {
+        let (fake, session) = session();
+        for i in 0..3 {
+            fake.inject_packet(&session, &[i]).unwrap();
+        }
+        let packet = session.try_receive_borrowed().unwrap().unwrap();
+        assert_eq!(packet.bytes(), &[0]);
+        assert_eq!(fake.unreleased_packets(&session).unwrap(), 1);
+        drop(packet);
+        assert_eq!(fake.unreleased_packets(&session).unwrap(), 0);
+
+        let packet = session.receive_blocking_borrowed().unwrap();
+        assert_eq!(packet.into_vec(), vec![1]);
+        let packet = session.receive_timeout_borrowed(Duration::ZERO).unwrap().unwrap();
+        assert_eq!(packet.into_owned().bytes(), &[2]);
+        assert_eq!(fake.unreleased_packets(&session).unwrap(), 0);
+        assert!(session.try_receive_borrowed().unwrap().is_none());
+        assert!(session.receive_deadline_borrowed(Instant::now()).unwrap().is_none());
+    }
+
+    #[test]
+    fn send_borrowed_packet() {
+        let (fake, session) = session();
+        let mut packet = session.allocate_send_packet_borrowed(3).unwrap();
+        packet.bytes_mut().copy_from_slice(&[1, 2, 3]);
+        assert_eq!(packet.bytes(), &[1, 2, 3]);
+        session.send_borrowed_packet(packet);
+        assert_eq!(fake.take_sent_packets(&session).unwrap(), vec![vec![1, 2, 3]]);
+        assert_eq!(fake.unsent_packets(&session).unwrap(), 0);
+        assert_eq!(session.unsent_packets_dropped(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Packet was not allocated by this session")]
+    fn send_borrowed_packet_of_another_session() {
+        let (a, b) = Session::pair().unwrap();
+        a.set_unsent_packet_policy(UnsentPacketPolicy::Count);
+        b.send_borrowed_packet(a.allocate_send_packet_borrowed(1).unwrap());
+    }
+
+    #[test]
+    fn unsent_packets_follow_the_policy() {
+        let (fake, session) = session();
+        session.set_unsent_packet_policy(UnsentPacketPolicy::Submit);
+        let mut packet = session.allocate_send_packet_borrowed(2).unwrap();
+        packet.bytes_mut().copy_from_slice(&[1, 2]);
+        drop(packet);
+        assert_eq!(fake.take_sent_packets(&session).unwrap(), vec![vec![0, 0]]);
+
+        session.set_unsent_packet_policy(UnsentPacketPolicy::Count);
+        drop(session.allocate_send_packet_borrowed(2).unwrap());
+        assert_eq!(fake.unsent_packets(&session).unwrap(), 1);
+        assert_eq!(session.unsent_packets_dropped(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Packet was never sent!")]
+    fn unsent_packets_panic_with_the_panic_policy() {
+        let (_fake, session) = session();
+        session.set_unsent_packet_policy(UnsentPacketPolicy::Panic);
+        drop(session.allocate_send_packet_borrowed(2).unwrap());
+    }
+}
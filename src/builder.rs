@@ -0,0 +1,486 @@
+//! Building IP packets directly in the send ring.
+
+use crate::{
+    checksum,
+    ip::{self, ICMP_HEADER_LEN, IPV4_HEADER_LEN, IPV6_HEADER_LEN, TCP_HEADER_LEN, UDP_HEADER_LEN},
+    BorrowedSendPacket, Error, SendPacket, Session, MAX_IP_PACKET_SIZE,
+};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+
+/// What follows the IP header
+#[derive(Copy, Clone, Debug)]
+enum Transport {
+    /// Only the payload, with this protocol number
+    Raw(u8),
+    Udp {
+        source_port: u16,
+        destination_port: u16,
+    },
+    Tcp {
+        source_port: u16,
+        destination_port: u16,
+    },
+    Icmp {
+        icmp_type: u8,
+        code: u8,
+        rest_of_header: [u8; 4],
+    },
+}
+
+/// Writes an IPv4 or IPv6 packet with a UDP, TCP or ICMP header, a payload and all checksums
+/// straight into a send packet, without building it in a separate buffer first
+///
+/// # Example
+/// ```
+/// use wintun::{ip::UdpView, PacketBuilder};
+///
+/// let (a, b) = wintun::Session::pair().unwrap();
+/// let packet = PacketBuilder::ipv4([10, 0, 0, 1].into(), [10, 0, 0, 2].into())
+///     .udp(12345, 4321)
+///     .payload(b"hey")
+///     .build(&a)
+///     .unwrap();
+/// a.send_packet(packet);
+///
+/// let received = b.receive_blocking().unwrap();
+/// let ip = wintun::ip::Ipv4View::new(received.bytes()).unwrap();
+/// assert_eq!(UdpView::new(ip.payload()).unwrap().payload(), b"hey");
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct PacketBuilder<'a> {
+    source: IpAddr,
+    destination: IpAddr,
+    ttl: u8,
+    identification: u16,
+    traffic_class: u8,
+    transport: Transport,
+    sequence_number: u32,
+    acknowledgment_number: u32,
+    tcp_flags: u8,
+    window: u16,
+    payload: &'a [u8],
+}
+
+impl<'a> PacketBuilder<'a> {
+    fn new(source: IpAddr, destination: IpAddr) -> Self {
+        Self {
+            source,
+            destination,
+            ttl: 64,
+            identification: 0,
+            traffic_class: 0,
+            transport: Transport::Raw(0),
+            sequence_number: 0,
+            acknowledgment_number: 0,
+            tcp_flags: 0,
+            window: 0xFFFF,
+            payload: &[],
+        }
+    }
+
+    /// Starts an IPv4 packet, with an empty payload and a TTL of 64
+    pub fn ipv4(source: Ipv4Addr, destination: Ipv4Addr) -> Self {
+        Self::new(source.into(), destination.into())
+    }
+
+    /// Starts an IPv6 packet, with an empty payload and a hop limit of 64
+    pub fn ipv6(source: Ipv6Addr, destination: Ipv6Addr) -> Self {
+        Self::new(source.into(), destination.into())
+    }
+
+    /// Sets the TTL of IPv4 packets and the hop limit of IPv6 packets
+    pub fn ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Sets the identification of IPv4 packets. Ignored for IPv6 packets
+    pub fn identification(mut self, identification: u16) -> Self {
+        self.identification = identification;
+        self
+    }
+
+    /// Sets the type of service byte of IPv4 packets and the traffic class of IPv6 packets
+    pub fn traffic_class(mut self, traffic_class: u8) -> Self {
+        self.traffic_class = traffic_class;
+        self
+    }
+
+    /// Puts the payload right after the IP header, marked as `protocol`. Packets without a
+    /// transport header are marked as protocol 0 until this is called
+    pub fn protocol(mut self, protocol: u8) -> Self {
+        self.transport = Transport::Raw(protocol);
+        self
+    }
+
+    /// Adds a UDP header
+    pub fn udp(mut self, source_port: u16, destination_port: u16) -> Self {
+        self.transport = Transport::Udp {
+            source_port,
+            destination_port,
+        };
+        self
+    }
+
+    /// Adds a TCP header without options. The remaining fields are set with
+    /// [`PacketBuilder::sequence_number`] and friends
+    pub fn tcp(mut self, source_port: u16, destination_port: u16) -> Self {
+        self.transport = Transport::Tcp {
+            source_port,
+            destination_port,
+        };
+        self
+    }
+
+    /// Sets the sequence number of the TCP header
+    pub fn sequence_number(mut self, sequence_number: u32) -> Self {
+        self.sequence_number = sequence_number;
+        self
+    }
+
+    /// Sets the acknowledgment number of the TCP header
+    pub fn acknowledgment_number(mut self, acknowledgment_number: u32) -> Self {
+        self.acknowledgment_number = acknowledgment_number;
+        self
+    }
+
+    /// Sets the flags of the TCP header, see [`ip::TCP_SYN`] and friends
+    pub fn tcp_flags(mut self, flags: u8) -> Self {
+        self.tcp_flags = flags;
+        self
+    }
+
+    /// Sets the window of the TCP header. Defaults to 65535
+    pub fn window(mut self, window: u16) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Adds an ICMP header, or an ICMPv6 header for IPv6 packets. `rest_of_header` holds the 4
+    /// bytes whose meaning depends on the type, such as the identifier and sequence number of echo
+    /// requests
+    pub fn icmp(mut self, icmp_type: u8, code: u8, rest_of_header: [u8; 4]) -> Self {
+        self.transport = Transport::Icmp {
+            icmp_type,
+            code,
+            rest_of_header,
+        };
+        self
+    }
+
+    /// Sets the bytes following the transport header
+    pub fn payload(mut self, payload: &'a [u8]) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Returns the size of the packet, in bytes
+    pub fn size(&self) -> usize {
+        self.ip_header_len() + self.transport_header_len() + self.payload.len()
+    }
+
+    /// Allocates a send packet of exactly [`PacketBuilder::size`] bytes on `session` and writes the
+    /// packet into it
+    pub fn build(&self, session: &Arc<Session>) -> Result<SendPacket, Error> {
+        let mut packet = session.allocate_send_packet(session.packet_size(self.size())?)?;
+        self.write(packet.bytes_mut());
+        Ok(packet)
+    }
+
+    /// Like [`PacketBuilder::build`], but returns a packet that borrows `session`
+    pub fn build_borrowed<'s>(&self, session: &'s Session) -> Result<BorrowedSendPacket<'s>, Error> {
+        let mut packet = session.allocate_send_packet_borrowed(session.packet_size(self.size())?)?;
+        self.write(packet.bytes_mut());
+        Ok(packet)
+    }
+
+    /// Writes the packet into `bytes`
+    ///
+    /// # Panics
+    /// If `bytes` is not exactly [`PacketBuilder::size`] bytes long, or longer than
+    /// [`MAX_IP_PACKET_SIZE`]
+    pub fn write(&self, bytes: &mut [u8]) {
+        assert_eq!(bytes.len(), self.size(), "Buffer does not match the packet length");
+        assert!(bytes.len() <= MAX_IP_PACKET_SIZE as usize, "Packet is too large");
+
+        let (header, segment) = bytes.split_at_mut(self.ip_header_len());
+        let transport_len = segment.len();
+        let (transport, payload) = segment.split_at_mut(self.transport_header_len());
+        payload.copy_from_slice(self.payload);
+
+        let protocol = self.protocol_number();
+        match (self.source, self.destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                header[0] = 0x45;
+                header[1] = self.traffic_class;
+                header[2..4].copy_from_slice(&(IPV4_HEADER_LEN as u16 + transport_len as u16).to_be_bytes());
+                header[4..6].copy_from_slice(&self.identification.to_be_bytes());
+                header[6..8].fill(0);
+                header[8] = self.ttl;
+                header[9] = protocol;
+                header[12..16].copy_from_slice(&source.octets());
+                header[16..20].copy_from_slice(&destination.octets());
+                let sum = checksum::ipv4_header(header);
+                header[10..12].copy_from_slice(&sum.to_be_bytes());
+            }
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                let first_word = (6 << 28) | (self.traffic_class as u32) << 20;
+                header[0..4].copy_from_slice(&first_word.to_be_bytes());
+                header[4..6].copy_from_slice(&(transport_len as u16).to_be_bytes());
+                header[6] = protocol;
+                header[7] = self.ttl;
+                header[8..24].copy_from_slice(&source.octets());
+                header[24..40].copy_from_slice(&destination.octets());
+            }
+            _ => unreachable!("The constructors only take addresses of the same family"),
+        }
+
+        match self.transport {
+            Transport::Raw(_) => return,
+            Transport::Udp {
+                source_port,
+                destination_port,
+            } => {
+                transport[0..2].copy_from_slice(&source_port.to_be_bytes());
+                transport[2..4].copy_from_slice(&destination_port.to_be_bytes());
+                transport[4..6].copy_from_slice(&(transport_len as u16).to_be_bytes());
+            }
+            Transport::Tcp {
+                source_port,
+                destination_port,
+            } => {
+                transport[0..2].copy_from_slice(&source_port.to_be_bytes());
+                transport[2..4].copy_from_slice(&destination_port.to_be_bytes());
+                transport[4..8].copy_from_slice(&self.sequence_number.to_be_bytes());
+                transport[8..12].copy_from_slice(&self.acknowledgment_number.to_be_bytes());
+                transport[12] = (TCP_HEADER_LEN as u8 / 4) << 4;
+                transport[13] = self.tcp_flags;
+                transport[14..16].copy_from_slice(&self.window.to_be_bytes());
+                transport[18..20].fill(0);
+            }
+            Transport::Icmp {
+                icmp_type,
+                code,
+                rest_of_header,
+            } => {
+                transport[0] = icmp_type;
+                transport[1] = code;
+                transport[4..8].copy_from_slice(&rest_of_header);
+            }
+        }
+
+        let sum = checksum::transport(self.source, self.destination, protocol, segment);
+        let offset = checksum::transport_checksum_offset(protocol).unwrap();
+        segment[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    fn ip_header_len(&self) -> usize {
+        match self.source {
+            IpAddr::V4(_) => IPV4_HEADER_LEN,
+            IpAddr::V6(_) => IPV6_HEADER_LEN,
+        }
+    }
+
+    fn transport_header_len(&self) -> usize {
+        match self.transport {
+            Transport::Raw(_) => 0,
+            Transport::Udp { .. } => UDP_HEADER_LEN,
+            Transport::Tcp { .. } => TCP_HEADER_LEN,
+            Transport::Icmp { .. } => ICMP_HEADER_LEN,
+        }
+    }
+
+    fn protocol_number(&self) -> u8 {
+        match (self.transport, self.source) {
+            (Transport::Raw(protocol), _) => protocol,
+            (Transport::Udp { .. }, _) => ip::PROTOCOL_UDP,
+            (Transport::Tcp { .. }, _) => ip::PROTOCOL_TCP,
+            (Transport::Icmp { .. }, IpAddr::V4(_)) => ip::PROTOCOL_ICMP,
+            (Transport::Icmp { .. }, IpAddr::V6(_)) => ip::PROTOCOL_ICMPV6,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checksum,
+        ip::{IcmpView, IpView, TcpView, UdpView},
+        FakeWintun, Wintun, MIN_RING_CAPACITY,
+    };
+
+    const V4: (Ipv4Addr, Ipv4Addr) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+    const V6: (Ipv6Addr, Ipv6Addr) = (
+        Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1),
+        Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2),
+    );
+
+    fn builders() -> [PacketBuilder<'static>; 2] {
+        [PacketBuilder::ipv4(V4.0, V4.1), PacketBuilder::ipv6(V6.0, V6.1)]
+    }
+
+    fn write(builder: &PacketBuilder) -> Vec<u8> {
+        let mut bytes = vec![0xAA; builder.size()];
+        builder.write(&mut bytes);
+        assert!(checksum::verify(&bytes).unwrap());
+        bytes
+    }
+
+    fn session() -> Arc<Session> {
+        let wintun: Wintun = Arc::new(FakeWintun::new());
+        let adapter = crate::Adapter::create(&wintun, "Test", "Test", None).unwrap();
+        Arc::new(adapter.start_session(MIN_RING_CAPACITY).unwrap())
+    }
+
+    #[test]
+    fn ipv4_udp_bytes() {
+        let builder = PacketBuilder::ipv4(V4.0, V4.1).udp(12345, 4321).payload(b"hey");
+        #[rustfmt::skip]
+        let expected = [
+            0x45, 0, 0, 31, 0, 0, 0, 0, 64, 17, 0x66, 0xCC, 10, 0, 0, 1, 10, 0, 0, 2,
+            0x30, 0x39, 0x10, 0xE1, 0, 11, 0xC9, 0x55,
+            b'h', b'e', b'y',
+        ];
+        assert_eq!(write(&builder), expected);
+    }
+
+    #[test]
+    fn udp() {
+        for builder in builders() {
+            let bytes = write(&builder.ttl(9).traffic_class(0xB8).udp(53, 5353).payload(b"query"));
+            let ip = IpView::new(&bytes).unwrap();
+            assert_eq!(ip.protocol(), ip::PROTOCOL_UDP);
+            assert_eq!(ip.bytes().len(), bytes.len());
+            match ip {
+                IpView::V4(ip) => assert_eq!((ip.ttl(), ip.dscp(), ip.ecn()), (9, 0x2E, 0)),
+                IpView::V6(ip) => assert_eq!((ip.hop_limit(), ip.traffic_class()), (9, 0xB8)),
+            }
+            let udp = UdpView::new(ip.payload()).unwrap();
+            assert_eq!((udp.source_port(), udp.destination_port()), (53, 5353));
+            assert_eq!(udp.length() as usize, UDP_HEADER_LEN + 5);
+            assert_eq!(udp.payload(), b"query");
+        }
+    }
+
+    #[test]
+    fn tcp() {
+        for builder in builders() {
+            let builder = builder
+                .tcp(443, 50000)
+                .sequence_number(1)
+                .acknowledgment_number(2)
+                .tcp_flags(ip::TCP_SYN | ip::TCP_ACK)
+                .window(1024)
+                .payload(b"data");
+            let bytes = write(&builder);
+            let ip = IpView::new(&bytes).unwrap();
+            assert_eq!(ip.protocol(), ip::PROTOCOL_TCP);
+            let tcp = TcpView::new(ip.payload()).unwrap();
+            assert_eq!((tcp.source_port(), tcp.destination_port()), (443, 50000));
+            assert_eq!((tcp.sequence_number(), tcp.acknowledgment_number()), (1, 2));
+            assert_eq!((tcp.flags(), tcp.window(), tcp.urgent_pointer()), (0x12, 1024, 0));
+            assert_eq!(tcp.header_len(), TCP_HEADER_LEN);
+            assert_eq!(tcp.payload(), b"data");
+        }
+    }
+
+    #[test]
+    fn icmp() {
+        for (builder, protocol) in builders().into_iter().zip([ip::PROTOCOL_ICMP, ip::PROTOCOL_ICMPV6]) {
+            let bytes = write(&builder.icmp(8, 0, [0, 7, 0, 1]).payload(b"ping"));
+            let ip = IpView::new(&bytes).unwrap();
+            assert_eq!(ip.protocol(), protocol);
+            let icmp = IcmpView::new(ip.payload()).unwrap();
+            assert_eq!((icmp.icmp_type(), icmp.code()), (8, 0));
+            assert_eq!((icmp.echo_identifier(), icmp.echo_sequence()), (7, 1));
+            assert_eq!(icmp.payload(), b"ping");
+        }
+        //ICMP over IPv4 has no pseudo header
+        let bytes = write(&PacketBuilder::ipv4(V4.0, V4.1).icmp(0, 0, [0; 4]));
+        assert_eq!(ip::read_u16(&bytes, IPV4_HEADER_LEN + 2), !0);
+    }
+
+    #[test]
+    fn ipv4_header_fields() {
+        let bytes = write(
+            &PacketBuilder::ipv4(V4.0, V4.1)
+                .identification(0x1234)
+                .protocol(99)
+                .payload(b"raw"),
+        );
+        let ip = ip::Ipv4View::new(&bytes).unwrap();
+        assert_eq!((ip.identification(), ip.protocol()), (0x1234, 99));
+        assert!(!ip.dont_fragment() && !ip.is_fragment());
+        assert_eq!(ip.payload(), b"raw");
+        assert_eq!(checksum::ipv4_header(ip.header()), ip.header_checksum());
+    }
+
+    #[test]
+    fn zero_udp_checksum_is_written_as_all_ones() {
+        for builder in builders() {
+            let builder = builder.udp(1, 2);
+            //Adding a payload word equal to the checksum makes the sum all ones
+            let sum = ip::read_u16(&write(&builder.payload(&[0, 0])), builder.ip_header_len() + 6);
+            let payload = sum.to_be_bytes();
+            let bytes = write(&builder.payload(&payload));
+            assert_eq!(ip::read_u16(&bytes, builder.ip_header_len() + 6), 0xFFFF);
+
+            //Other protocols keep a zero checksum
+            let builder = builder.tcp(1, 2);
+            let sum = ip::read_u16(&write(&builder.payload(&[0, 0])), builder.ip_header_len() + 16);
+            let payload = sum.to_be_bytes();
+            let bytes = write(&builder.payload(&payload));
+            assert_eq!(ip::read_u16(&bytes, builder.ip_header_len() + 16), 0);
+        }
+    }
+
+    #[test]
+    fn build() {
+        let session = session();
+        let builder = PacketBuilder::ipv6(V6.0, V6.1).udp(1, 2).payload(b"owned");
+        let packet = builder.build(&session).unwrap();
+        assert_eq!(packet.bytes(), write(&builder).as_slice());
+        session.send_packet(packet);
+
+        let packet = builder.build_borrowed(&session).unwrap();
+        assert_eq!(packet.bytes(), write(&builder).as_slice());
+        session.send_borrowed_packet(packet);
+    }
+
+    #[test]
+    fn build_size_errors() {
+        let session = session();
+        let payload = vec![0; MAX_IP_PACKET_SIZE as usize];
+        let largest = PacketBuilder::ipv4(V4.0, V4.1).payload(&payload[IPV4_HEADER_LEN..]);
+        let too_large = largest.udp(1, 2);
+        assert_eq!(too_large.size(), MAX_IP_PACKET_SIZE as usize + UDP_HEADER_LEN);
+        for result in [
+            too_large.build(&session).err(),
+            too_large.build_borrowed(&session).err(),
+        ] {
+            match result {
+                Some(Error::InvalidPacketSize { size, .. }) => assert_eq!(size, too_large.size()),
+                other => panic!("Expected an invalid packet size, got {:?}", other),
+            }
+        }
+        assert_eq!(session.stats().oversized_packets, 2);
+
+        //The largest packet fits, after which the ring is full
+        let packet = largest.build(&session).unwrap();
+        assert_eq!(packet.bytes().len(), MAX_IP_PACKET_SIZE as usize);
+        assert!(matches!(largest.build_borrowed(&session), Err(Error::RingFull { .. })));
+        session.send_packet(packet);
+    }
+
+    #[test]
+    #[should_panic(expected = "Buffer does not match the packet length")]
+    fn write_checks_the_length() {
+        PacketBuilder::ipv4(V4.0, V4.1)
+            .udp(1, 2)
+            .write(&mut [0; UDP_HEADER_LEN]);
+    }
+}
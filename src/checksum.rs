@@ -0,0 +1,395 @@
+//! The Internet checksum (RFC 1071) of IPv4, UDP, TCP, ICMP and ICMPv6 headers.
+//!
+//! The functions computing a checksum skip the checksum field of the header they are given, so
+//! they can be called on packets whose checksum is stale. [`update`] and [`verify`] work on whole
+//! IP packets, such as [`crate::SendPacket::bytes_mut`]. After changing a single field, [`adjust`]
+//! updates a checksum incrementally as described in RFC 1624, without reading the rest of the
+//! packet.
+//!
+//! # Example
+//! ```
+//! use wintun::{checksum, PacketBuilder};
+//!
+//! let mut packet = vec![0; 28];
+//! PacketBuilder::ipv4([10, 0, 0, 1].into(), [10, 0, 0, 2].into())
+//!     .udp(1234, 53)
+//!     .write(&mut packet);
+//!
+//! //Change the destination address, fixing up the IPv4 and UDP checksums incrementally
+//! let (old, new) = ([10, 0, 0, 2], [10, 0, 0, 3]);
+//! packet[16..20].copy_from_slice(&new);
+//! for offset in [10, 26] {
+//!     let sum = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+//!     let sum = checksum::adjust(sum, &old, &new);
+//!     packet[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+//! }
+//! assert!(checksum::verify(&packet).unwrap());
+//! ```
+
+use crate::{
+    ip::{self, IpView, PROTOCOL_ICMP, PROTOCOL_ICMPV6, PROTOCOL_TCP, PROTOCOL_UDP},
+    Error,
+};
+use std::net::{IpAddr, Ipv6Addr};
+
+/// Offset of the checksum field in the IPv4 header
+const IPV4_CHECKSUM_OFFSET: usize = 10;
+
+/// Adds `bytes` to the running sum `sum` as big endian 16 bit words, padding an odd trailing byte
+/// with zero
+pub(crate) fn add(sum: u64, bytes: &[u8]) -> u64 {
+    let mut chunks = bytes.chunks_exact(2);
+    let sum = chunks
+        .by_ref()
+        .fold(sum, |sum, word| sum + u16::from_be_bytes([word[0], word[1]]) as u64);
+    match chunks.remainder() {
+        [last] => sum + ((*last as u64) << 8),
+        _ => sum,
+    }
+}
+
+/// Folds the running sum `sum` into 16 bits and returns its complement, the value written into a
+/// checksum field
+pub(crate) fn finish(mut sum: u64) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Returns the running sum of the pseudo header that UDP, TCP and ICMPv6 checksums cover, for a
+/// payload of `len` bytes of `protocol` sent from `source` to `destination`
+fn pseudo_header(source: IpAddr, destination: IpAddr, protocol: u8, len: usize) -> u64 {
+    let sum = match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => add(add(0, &source.octets()), &destination.octets()),
+        (source, destination) => add(add(0, &to_ipv6(source)), &to_ipv6(destination)),
+    };
+    sum + protocol as u64 + (len as u64 >> 16) + (len as u64 & 0xFFFF)
+}
+
+fn to_ipv6(address: IpAddr) -> [u8; 16] {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped().octets(),
+        IpAddr::V6(address) => address.octets(),
+    }
+}
+
+/// Returns the checksum of `bytes` with the 16 bit field at `offset` counted as zero
+fn skipping(sum: u64, bytes: &[u8], offset: usize) -> u16 {
+    finish(add(add(sum, &bytes[..offset]), &bytes[offset + 2..]))
+}
+
+/// Returns the offset of the checksum field in the header of `protocol`, if the crate knows it
+pub(crate) fn transport_checksum_offset(protocol: u8) -> Option<usize> {
+    match protocol {
+        PROTOCOL_UDP => Some(6),
+        PROTOCOL_TCP => Some(16),
+        PROTOCOL_ICMP | PROTOCOL_ICMPV6 => Some(2),
+        _ => None,
+    }
+}
+
+/// Returns the checksum of the `protocol` header and payload `segment` sent from `source` to
+/// `destination`, with its checksum field counted as zero
+///
+/// # Panics
+/// If `protocol` is not UDP, TCP, ICMP or ICMPv6, or `segment` is shorter than the header's
+/// checksum field
+pub(crate) fn transport(source: IpAddr, destination: IpAddr, protocol: u8, segment: &[u8]) -> u16 {
+    let offset = transport_checksum_offset(protocol).expect("Protocol has no known checksum");
+    let sum = match protocol {
+        PROTOCOL_ICMP => 0,
+        _ => pseudo_header(source, destination, protocol, segment.len()),
+    };
+    match (protocol, skipping(sum, segment, offset)) {
+        //A zero UDP checksum means there is none, so use its equivalent instead
+        (PROTOCOL_UDP, 0) => 0xFFFF,
+        (_, sum) => sum,
+    }
+}
+
+/// Returns the checksum of the IPv4 header `header`, including options
+///
+/// # Panics
+/// If `header` is shorter than 20 bytes
+pub fn ipv4_header(header: &[u8]) -> u16 {
+    assert!(header.len() >= ip::IPV4_HEADER_LEN, "IPv4 header is truncated");
+    skipping(0, header, IPV4_CHECKSUM_OFFSET)
+}
+
+/// Returns the checksum of the UDP header and payload `datagram`, sent from `source` to
+/// `destination` over IPv4 or IPv6
+///
+/// # Panics
+/// If `datagram` is shorter than a UDP header
+pub fn udp(source: IpAddr, destination: IpAddr, datagram: &[u8]) -> u16 {
+    assert!(datagram.len() >= ip::UDP_HEADER_LEN, "UDP header is truncated");
+    transport(source, destination, PROTOCOL_UDP, datagram)
+}
+
+/// Returns the checksum of the TCP header and payload `segment`, sent from `source` to
+/// `destination` over IPv4 or IPv6
+///
+/// # Panics
+/// If `segment` is shorter than a TCP header
+pub fn tcp(source: IpAddr, destination: IpAddr, segment: &[u8]) -> u16 {
+    assert!(segment.len() >= ip::TCP_HEADER_LEN, "TCP header is truncated");
+    transport(source, destination, PROTOCOL_TCP, segment)
+}
+
+/// Returns the checksum of the ICMP `message`, which does not cover a pseudo header
+///
+/// # Panics
+/// If `message` is shorter than an ICMP header
+pub fn icmp(message: &[u8]) -> u16 {
+    assert!(message.len() >= ip::ICMP_HEADER_LEN, "ICMP header is truncated");
+    skipping(0, message, 2)
+}
+
+/// Returns the checksum of the ICMPv6 `message` sent from `source` to `destination`
+///
+/// # Panics
+/// If `message` is shorter than an ICMPv6 header
+pub fn icmpv6(source: Ipv6Addr, destination: Ipv6Addr, message: &[u8]) -> u16 {
+    assert!(message.len() >= ip::ICMP_HEADER_LEN, "ICMPv6 header is truncated");
+    transport(source.into(), destination.into(), PROTOCOL_ICMPV6, message)
+}
+
+/// Recomputes the IPv4 header checksum and the UDP, TCP, ICMP or ICMPv6 checksum of the IP packet
+/// `packet` in place
+///
+/// The transport checksum of fragments covers the whole reassembled packet, so it is left as is,
+/// along with the payload of other protocols
+pub fn update(packet: &mut [u8]) -> Result<(), Error> {
+    let (header_len, segment_len, source, destination, protocol, fragment) = {
+        let ip = IpView::new(packet)?;
+        let header_len = ip.bytes().len() - ip.payload().len();
+        let segment_len = ip.payload().len();
+        (
+            header_len,
+            segment_len,
+            ip.source(),
+            ip.destination(),
+            ip.protocol(),
+            ip.is_fragment(),
+        )
+    };
+    if source.is_ipv4() {
+        let sum = ipv4_header(&packet[..header_len]);
+        write(packet, IPV4_CHECKSUM_OFFSET, sum);
+    }
+
+    let segment = &mut packet[header_len..header_len + segment_len];
+    let Some(offset) = transport_offset(source, protocol, fragment) else {
+        return Ok(());
+    };
+    check_segment(protocol, segment)?;
+    let sum = transport(source, destination, protocol, segment);
+    write(segment, offset, sum);
+    Ok(())
+}
+
+/// Returns true if the IPv4 header checksum and the UDP, TCP, ICMP or ICMPv6 checksum of the IP
+/// packet `packet` are correct
+///
+/// Checksums that cannot be checked, such as the transport checksum of fragments, are assumed to be
+/// correct. So is a zero UDP checksum over IPv4, which means the sender did not compute one
+pub fn verify(packet: &[u8]) -> Result<bool, Error> {
+    //Summing a header along with its checksum gives all ones, whichever of the two
+    //representations of zero the checksum uses
+    let ip = IpView::new(packet)?;
+    if let IpView::V4(ipv4) = ip {
+        if finish(add(0, ipv4.header())) != 0 {
+            return Ok(false);
+        }
+    }
+
+    let (source, destination, protocol, segment) = (ip.source(), ip.destination(), ip.protocol(), ip.payload());
+    let Some(offset) = transport_offset(source, protocol, ip.is_fragment()) else {
+        return Ok(true);
+    };
+    check_segment(protocol, segment)?;
+    if protocol == PROTOCOL_UDP && source.is_ipv4() && ip::read_u16(segment, offset) == 0 {
+        return Ok(true);
+    }
+    let sum = match protocol {
+        PROTOCOL_ICMP => 0,
+        _ => pseudo_header(source, destination, protocol, segment.len()),
+    };
+    Ok(finish(add(sum, segment)) == 0)
+}
+
+/// Returns the offset of the transport checksum that [`update`] and [`verify`] handle for a
+/// packet from `source` carrying `protocol`
+fn transport_offset(source: IpAddr, protocol: u8, fragment: bool) -> Option<usize> {
+    match (protocol, source) {
+        _ if fragment => None,
+        (PROTOCOL_ICMP, IpAddr::V6(_)) | (PROTOCOL_ICMPV6, IpAddr::V4(_)) => None,
+        (protocol, _) => transport_checksum_offset(protocol),
+    }
+}
+
+/// Validates the header of `protocol` at the start of `segment`
+fn check_segment(protocol: u8, segment: &[u8]) -> Result<(), Error> {
+    match protocol {
+        PROTOCOL_UDP => ip::UdpView::new(segment).map(drop),
+        PROTOCOL_TCP => ip::TcpView::new(segment).map(drop),
+        _ => ip::IcmpView::new(segment).map(drop),
+    }
+}
+
+/// Updates `checksum` after the bytes `old` covered by it were replaced with `new` (RFC 1624)
+///
+/// `old` and `new` must have the same length, and start at an even offset from the start of the
+/// checksummed data, which holds for every address and port field. A zero UDP checksum over IPv4
+/// means there is none and must not be adjusted, and an adjusted UDP checksum of zero has to be
+/// written as 0xFFFF
+///
+/// # Panics
+/// If `old` and `new` have different lengths
+pub fn adjust(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    assert_eq!(old.len(), new.len(), "Replaced bytes must have the same length");
+    //HC' = ~(~HC + ~m + m'), where ~m is the one's complement negation of m
+    let mut chunks = old.chunks_exact(2);
+    let sum = chunks.by_ref().fold(!checksum as u64, |sum, word| {
+        sum + !u16::from_be_bytes([word[0], word[1]]) as u64
+    });
+    let sum = match chunks.remainder() {
+        [last] => sum + !((*last as u16) << 8) as u64,
+        _ => sum,
+    };
+    finish(add(sum, new))
+}
+
+/// Like [`adjust`] for a single 16 bit field, such as a port
+pub fn adjust_u16(checksum: u16, old: u16, new: u16) -> u16 {
+    adjust(checksum, &old.to_be_bytes(), &new.to_be_bytes())
+}
+
+fn write(bytes: &mut [u8], offset: usize, sum: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PacketBuilder;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn rfc1071_example() {
+        //Section 3 of RFC 1071: the words sum to 0xDDF2 once folded
+        let bytes = [0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7];
+        assert_eq!(add(0, &bytes), 0x2DDF0);
+        assert_eq!(finish(add(0, &bytes)), !0xDDF2);
+    }
+
+    #[test]
+    fn ipv4_header_vector() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xB8, 0x61, 0xC0, 0xA8, 0x00, 0x01, 0xC0, 0xA8,
+            0x00, 0xC7,
+        ];
+        assert_eq!(ipv4_header(&header), 0xB861);
+    }
+
+    #[test]
+    fn odd_length() {
+        //A trailing byte is padded with zero on the right
+        assert_eq!(add(0, &[0xAB]), 0xAB00);
+        assert_eq!(add(0, &[1, 2, 3]), add(0, &[1, 2, 3, 0]));
+
+        let old = [0x12, 0x34, 0x56, 0x78, 0x9A];
+        let new = [0x12, 0x34, 0x56, 0x78, 0xBC];
+        let sum = finish(add(0, &old));
+        assert_eq!(adjust(sum, &old[4..], &new[4..]), finish(add(0, &new)));
+        assert_eq!(adjust(sum, &old[2..], &new[2..]), finish(add(0, &new)));
+    }
+
+    #[test]
+    fn rfc1624_example() {
+        //Section 4 of RFC 1624, where eqn. 3 gives 0x0000 rather than the 0xFFFF of eqn. 2
+        assert_eq!(adjust_u16(0xDD2F, 0x5555, 0x3285), 0x0000);
+    }
+
+    #[test]
+    fn adjust_matches_recomputing() {
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xB8, 0x61, 0xC0, 0xA8, 0x00, 0x01, 0xC0, 0xA8,
+            0x00, 0xC7,
+        ];
+        let new = [10, 0, 0, 1];
+        let sum = adjust(0xB861, &header[12..16], &new);
+        header[12..16].copy_from_slice(&new);
+        assert_eq!(sum, ipv4_header(&header));
+    }
+
+    /// Returns the checksum of `segment` sent from `source` to `destination`, following the pseudo
+    /// header layout of RFC 8200 section 8.1
+    fn rfc8200(source: Ipv6Addr, destination: Ipv6Addr, next_header: u8, segment: &[u8]) -> u16 {
+        let mut pseudo = Vec::new();
+        pseudo.extend_from_slice(&source.octets());
+        pseudo.extend_from_slice(&destination.octets());
+        pseudo.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+        pseudo.extend_from_slice(&[0, 0, 0, next_header]);
+        pseudo.extend_from_slice(segment);
+        finish(add(0, &pseudo))
+    }
+
+    #[test]
+    fn ipv6_pseudo_header() {
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let destination: Ipv6Addr = "2001:db8::2".parse().unwrap();
+
+        let datagram = [0x30, 0x39, 0x00, 0x35, 0x00, 0x0B, 0x00, 0x00, b'h', b'e', b'y'];
+        let expected = rfc8200(source, destination, ip::PROTOCOL_UDP, &datagram);
+        assert_eq!(udp(source.into(), destination.into(), &datagram), expected);
+
+        let echo = [0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01];
+        let expected = rfc8200(source, destination, PROTOCOL_ICMPV6, &echo);
+        assert_eq!(icmpv6(source, destination, &echo), expected);
+    }
+
+    #[test]
+    fn zero_udp_checksum_is_sent_as_all_ones() {
+        let source = Ipv4Addr::new(10, 0, 0, 1).into();
+        let destination = Ipv4Addr::new(10, 0, 0, 2).into();
+        //Pick the last payload word so that everything sums to 0xFFFF, whose complement is zero
+        let mut datagram = [0x30, 0x39, 0x00, 0x35, 0x00, 0x0A, 0x00, 0x00, 0x00, 0x00];
+        let word = udp(source, destination, &datagram);
+        datagram[8..10].copy_from_slice(&word.to_be_bytes());
+        assert_eq!(udp(source, destination, &datagram), 0xFFFF);
+    }
+
+    #[test]
+    fn update_then_verify() {
+        let mut packet = vec![0; 31];
+        PacketBuilder::ipv4([10, 0, 0, 1].into(), [10, 0, 0, 2].into())
+            .udp(1234, 53)
+            .payload(b"hey")
+            .write(&mut packet);
+        assert!(verify(&packet).unwrap());
+
+        packet[30] ^= 1;
+        assert!(!verify(&packet).unwrap());
+        update(&mut packet).unwrap();
+        assert!(verify(&packet).unwrap());
+
+        //No checksum at all is fine over IPv4
+        packet[26..28].fill(0);
+        assert!(verify(&packet).unwrap());
+        packet[10] ^= 1;
+        assert!(!verify(&packet).unwrap());
+    }
+
+    #[test]
+    fn zero_udp_checksum_over_ipv6_is_rejected() {
+        let mut packet = vec![0; 48];
+        PacketBuilder::ipv6("fd00::1".parse().unwrap(), "fd00::2".parse().unwrap())
+            .udp(1234, 53)
+            .write(&mut packet);
+        assert!(verify(&packet).unwrap());
+        packet[46..48].fill(0);
+        assert!(!verify(&packet).unwrap());
+    }
+}
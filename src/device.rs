@@ -0,0 +1,132 @@
+//! A packet loop abstraction shared by every platform backend.
+//!
+//! [`Session`] implements [`TunDevice`] on Windows (and on any platform when driven by
+//! [`crate::FakeWintun`]), and [`crate::LinuxTun`] implements it on Linux, so the same code can
+//! receive [`RecvPacket`]s and send [`SendPacket`]s on both.
+
+use crate::{Error, RecvPacket, SendPacket, Session};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// A layer 3 virtual network device that exchanges raw IP packets with the system
+///
+/// # Example
+/// ```no_run
+/// use std::sync::Arc;
+/// use wintun::TunDevice;
+///
+/// //Echoes every packet back to the system until the device is shut down
+/// fn echo<D: TunDevice>(device: &Arc<D>) -> Result<(), wintun::Error> {
+///     loop {
+///         let received = match device.receive_blocking() {
+///             Ok(packet) => packet,
+///             Err(wintun::Error::ShuttingDown) => return Ok(()),
+///             Err(err) => return Err(err),
+///         };
+///         let mut reply = device.allocate_send_packet(received.bytes().len() as u16)?;
+///         reply.bytes_mut().copy_from_slice(received.bytes());
+///         device.send_packet(reply)?;
+///     }
+/// }
+/// ```
+pub trait TunDevice: Send + Sync {
+    /// Receives the next packet without blocking, returning Ok(None) if none is queued
+    fn try_receive(self: &Arc<Self>) -> Result<Option<RecvPacket>, Error>;
+
+    /// Blocks until a packet is available. Returns Err([`Error::ShuttingDown`]) once
+    /// [`TunDevice::shutdown`] is called
+    fn receive_blocking(self: &Arc<Self>) -> Result<RecvPacket, Error>;
+
+    /// Like [`TunDevice::receive_blocking`], but returns Ok(None) if no packet arrives before `deadline`
+    fn receive_deadline(self: &Arc<Self>, deadline: Instant) -> Result<Option<RecvPacket>, Error>;
+
+    /// Like [`TunDevice::receive_blocking`], but returns Ok(None) if no packet arrives within `timeout`
+    fn receive_timeout(self: &Arc<Self>, timeout: Duration) -> Result<Option<RecvPacket>, Error> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.receive_deadline(deadline),
+            None => self.receive_blocking().map(Some),
+        }
+    }
+
+    /// Allocates a packet of `size` bytes to be filled in and passed to [`TunDevice::send_packet`]
+    fn allocate_send_packet(self: &Arc<Self>, size: u16) -> Result<SendPacket, Error>;
+
+    /// Sends a packet previously allocated with [`TunDevice::allocate_send_packet`] on this device.
+    /// Packets allocated by another device are returned in Err([`Error::ForeignPacket`])
+    fn send_packet(&self, packet: SendPacket) -> Result<(), Error>;
+
+    /// Wakes up blocking readers, making them return Err([`Error::ShuttingDown`]). Shutdown is
+    /// permanent, afterwards receiving and allocating packets fail with [`Error::ShuttingDown`] too
+    fn shutdown(&self) -> Result<(), Error>;
+
+    /// Returns true once [`TunDevice::shutdown`] has been called
+    fn is_shut_down(&self) -> bool;
+
+    /// Returns the maximum transmission unit of the device in bytes
+    fn mtu(&self) -> Result<usize, Error>;
+}
+
+impl TunDevice for Session {
+    fn try_receive(self: &Arc<Self>) -> Result<Option<RecvPacket>, Error> {
+        Session::try_receive(self)
+    }
+
+    fn receive_blocking(self: &Arc<Self>) -> Result<RecvPacket, Error> {
+        Session::receive_blocking(self)
+    }
+
+    fn receive_deadline(self: &Arc<Self>, deadline: Instant) -> Result<Option<RecvPacket>, Error> {
+        Session::receive_deadline(self, deadline)
+    }
+
+    fn allocate_send_packet(self: &Arc<Self>, size: u16) -> Result<SendPacket, Error> {
+        Session::allocate_send_packet(self, size)
+    }
+
+    fn send_packet(&self, packet: SendPacket) -> Result<(), Error> {
+        if !packet.belongs_to(self) {
+            return Err(Error::ForeignPacket(packet));
+        }
+        Session::send_packet(self, packet);
+        Ok(())
+    }
+
+    fn shutdown(&self) -> Result<(), Error> {
+        Session::shutdown(self)
+    }
+
+    fn is_shut_down(&self) -> bool {
+        Session::is_shut_down(self)
+    }
+
+    #[cfg(windows)]
+    fn mtu(&self) -> Result<usize, Error> {
+        self.adapter.get_mtu()
+    }
+
+    #[cfg(not(windows))]
+    fn mtu(&self) -> Result<usize, Error> {
+        Err("Adapter MTU is only available on Windows".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn foreign_packets_are_handed_back() {
+        let (a, b) = Session::pair().unwrap();
+        let mut packet = a.allocate_send_packet(3).unwrap();
+        packet.bytes_mut().copy_from_slice(&[1, 2, 3]);
+        let packet = match TunDevice::send_packet(&*b, packet) {
+            Err(Error::ForeignPacket(packet)) => packet,
+            other => panic!("Expected the packet back, got {:?}", other),
+        };
+        TunDevice::send_packet(&*a, packet).unwrap();
+        assert_eq!(b.receive_blocking().unwrap().bytes(), &[1, 2, 3]);
+        assert_eq!(a.unsent_packets_dropped(), 0);
+    }
+}
@@ -1,3 +1,7 @@
+use windows_sys::Win32::Foundation::{
+    ERROR_ACCESS_DENIED, ERROR_BUFFER_OVERFLOW, ERROR_HANDLE_EOF, ERROR_INVALID_PARAMETER, WIN32_ERROR,
+};
+
 /// Error type used to convey that a value is outside of a range that it must fall inside
 #[derive(Debug)]
 pub struct OutOfRangeData<T> {
@@ -47,6 +51,108 @@ pub enum Error {
 
     #[error("Session shutting down")]
     ShuttingDown,
+
+    /// The ring buffer has no room for the packet. Retrying once the other side has caught up
+    /// may succeed
+    #[error("{operation} failed: ring buffer is full (Win32 error {code})")]
+    RingFull { operation: &'static str, code: u32 },
+
+    /// The adapter was removed or the session's rings were closed. The session has to be
+    /// restarted, possibly on a new adapter
+    #[error("{operation} failed: adapter is gone (Win32 error {code})")]
+    AdapterGone { operation: &'static str, code: u32 },
+
+    /// The packet is larger than [`crate::MAX_IP_PACKET_SIZE`]
+    #[error("{operation} failed: invalid packet size {size} (Win32 error {code})")]
+    InvalidPacketSize {
+        operation: &'static str,
+        size: usize,
+        code: u32,
+    },
+
+    /// The process lacks the privileges needed, usually because it is not running as Administrator
+    #[error("{operation} failed: access denied (Win32 error {code})")]
+    AccessDenied { operation: &'static str, code: u32 },
+
+    /// No adapter with the requested name exists
+    #[error("{operation} failed: adapter not found (Win32 error {code})")]
+    AdapterNotFound { operation: &'static str, code: u32 },
+
+    /// The wintun driver is not loaded, which is the case until the first adapter is created
+    #[error("{operation} failed: driver not loaded (Win32 error {code})")]
+    DriverNotLoaded { operation: &'static str, code: u32 },
+
+    /// Any other failure of a wintun function
+    #[error("{operation} failed with Win32 error {code}")]
+    Win32 { operation: &'static str, code: u32 },
+
+    /// The bytes given to one of the [`crate::ip`] views are not a well formed header of that kind
+    #[error("Malformed packet: {0}")]
+    MalformedPacket(&'static str),
+
+    /// The packet is well formed, but not of a kind the operation can handle, such as rewriting
+    /// the ports of a packet without ports
+    #[error("Unsupported packet: {0}")]
+    UnsupportedPacket(&'static str),
+
+    /// Every outer port of a [`crate::nat::NatTable`] is in use, or the one asked for is taken
+    #[error("NAT port unavailable")]
+    NatPortUnavailable,
+
+    /// A packet was passed to [`crate::TunDevice::send_packet`] of a device that did not allocate
+    /// it. The packet is handed back so it can be sent through its own device, because dropping
+    /// it unsent counts as an unsent packet of the session that allocated it
+    #[error("Packet was allocated by another device")]
+    ForeignPacket(crate::SendPacket),
+}
+
+impl Error {
+    /// Converts the error code returned by the wintun function `operation` into the matching variant
+    pub(crate) fn wintun(operation: &'static str, code: WIN32_ERROR) -> Self {
+        match code {
+            ERROR_BUFFER_OVERFLOW => Error::RingFull { operation, code },
+            ERROR_HANDLE_EOF => Error::AdapterGone { operation, code },
+            ERROR_ACCESS_DENIED => Error::AccessDenied { operation, code },
+            code => Error::Win32 { operation, code },
+        }
+    }
+
+    /// Like [`Error::wintun`] for functions that fail with ERROR_INVALID_PARAMETER when a packet
+    /// of `size` bytes is too large
+    pub(crate) fn wintun_packet(operation: &'static str, code: WIN32_ERROR, size: usize) -> Self {
+        match code {
+            ERROR_INVALID_PARAMETER => Error::InvalidPacketSize { operation, size, code },
+            code => Error::wintun(operation, code),
+        }
+    }
+
+    /// Returns the Win32 error code of errors reported by wintun
+    pub fn win32_code(&self) -> Option<u32> {
+        match self {
+            Error::RingFull { code, .. }
+            | Error::AdapterGone { code, .. }
+            | Error::InvalidPacketSize { code, .. }
+            | Error::AccessDenied { code, .. }
+            | Error::AdapterNotFound { code, .. }
+            | Error::DriverNotLoaded { code, .. }
+            | Error::Win32 { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the wintun function that failed for errors reported by wintun
+    pub fn operation(&self) -> Option<&'static str> {
+        match self {
+            Error::RingFull { operation, .. }
+            | Error::AdapterGone { operation, .. }
+            | Error::InvalidPacketSize { operation, .. }
+            | Error::AccessDenied { operation, .. }
+            | Error::AdapterNotFound { operation, .. }
+            | Error::DriverNotLoaded { operation, .. }
+            | Error::Win32 { operation, .. } => Some(operation),
+            _ => None,
+        }
+    }
 }
 
 impl From<String> for Error {
@@ -77,7 +183,12 @@ impl From<Error> for std::io::Error {
     fn from(value: Error) -> Self {
         match value {
             Error::Io(io) => io,
-            _ => std::io::Error::new(std::io::ErrorKind::Other, value),
+            //Keep the kind of the underlying code, e.g. PermissionDenied for AccessDenied
+            _ => match value.win32_code() {
+                #[cfg(windows)]
+                Some(code) => std::io::Error::new(std::io::Error::from_raw_os_error(code as i32).kind(), value),
+                _ => std::io::Error::other(value),
+            },
         }
     }
 }
@@ -0,0 +1,342 @@
+//! Thin wrappers around Win32 event objects.
+//!
+//! Sessions wait on the driver's read event and their own shutdown event. On Windows these are
+//! regular kernel events. Everywhere else sessions can only be backed by [`crate::FakeWintun`], so
+//! events are emulated in process with the same auto and manual reset semantics.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::Waker,
+    time::Instant,
+};
+use windows_sys::Win32::Foundation::HANDLE;
+
+/// Timeout value that makes [`wait_any`] wait forever
+pub(crate) const INFINITE: u32 = u32::MAX;
+
+/// Converts an optional deadline into a [`wait_any`] timeout, rounding up so that the wait does
+/// not end before the deadline. Deadlines too far away for a finite timeout are capped, so callers
+/// have to check whether the deadline really passed when the wait times out
+pub(crate) fn timeout_ms(deadline: Option<Instant>) -> u32 {
+    let Some(deadline) = deadline else {
+        return INFINITE;
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let ms = remaining.as_nanos().div_ceil(1_000_000);
+    ms.min(INFINITE as u128 - 1) as u32
+}
+
+/// State shared between a [`Wait`] and whoever signals it
+struct WaitContext {
+    fired: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl WaitContext {
+    fn fire(&self) {
+        self.fired.store(true, Ordering::SeqCst);
+        if let Some(waker) = self.waker.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::WaitContext;
+    use std::{ffi::c_void, sync::Arc};
+    use windows_sys::Win32::{
+        Foundation::{
+            CloseHandle, BOOLEAN, FALSE, HANDLE, INVALID_HANDLE_VALUE, WAIT_FAILED, WAIT_OBJECT_0, WAIT_TIMEOUT,
+        },
+        System::Threading::{
+            CreateEventW, RegisterWaitForSingleObject, SetEvent, UnregisterWaitEx, WaitForMultipleObjects,
+            WT_EXECUTEONLYONCE,
+        },
+    };
+
+    pub(crate) fn create(manual_reset: bool, initial_state: bool) -> std::io::Result<HANDLE> {
+        let handle = unsafe {
+            CreateEventW(
+                std::ptr::null(),
+                manual_reset as _,
+                initial_state as _,
+                std::ptr::null(),
+            )
+        };
+        match handle {
+            0 => Err(std::io::Error::last_os_error()),
+            handle => Ok(handle),
+        }
+    }
+
+    pub(crate) fn set(event: HANDLE) -> std::io::Result<()> {
+        match unsafe { SetEvent(event) } {
+            FALSE => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn close(event: HANDLE) -> std::io::Result<()> {
+        match unsafe { CloseHandle(event) } {
+            FALSE => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn wait_any(events: &[HANDLE], timeout_ms: u32) -> std::io::Result<Option<usize>> {
+        //SAFETY: We abide by the requirements of WaitForMultipleObjects, events is a pointer to
+        //valid, aligned memory
+        let result = unsafe { WaitForMultipleObjects(events.len() as u32, events.as_ptr(), FALSE, timeout_ms) };
+        match result {
+            WAIT_FAILED => Err(std::io::Error::last_os_error()),
+            WAIT_TIMEOUT => Ok(None),
+            r if (WAIT_OBJECT_0..WAIT_OBJECT_0 + events.len() as u32).contains(&r) => {
+                Ok(Some((r - WAIT_OBJECT_0) as usize))
+            }
+            //Only abandoned mutexes are left, which are never waited on
+            r => Err(std::io::Error::other(format!(
+                "WaitForMultipleObjects returned unexpected value {:#x}",
+                r
+            ))),
+        }
+    }
+
+    unsafe extern "system" fn callback(context: *mut c_void, _timed_out: BOOLEAN) {
+        (*(context as *const WaitContext)).fire();
+    }
+
+    /// A wait registered with the system thread pool
+    pub(crate) struct Registration(HANDLE);
+
+    pub(crate) fn register(event: HANDLE, context: &Arc<WaitContext>) -> std::io::Result<Registration> {
+        let mut wait = 0;
+        //SAFETY: The context outlives the registration because the Wait that owns both drops the
+        //registration first, which blocks until any running callback has returned
+        let result = unsafe {
+            RegisterWaitForSingleObject(
+                &mut wait,
+                event,
+                Some(callback),
+                Arc::as_ptr(context) as *const c_void,
+                super::INFINITE,
+                WT_EXECUTEONLYONCE,
+            )
+        };
+        match result {
+            FALSE => Err(std::io::Error::last_os_error()),
+            _ => Ok(Registration(wait)),
+        }
+    }
+
+    impl Drop for Registration {
+        fn drop(&mut self) {
+            //INVALID_HANDLE_VALUE makes UnregisterWaitEx wait for running callbacks to complete
+            if unsafe { UnregisterWaitEx(self.0, INVALID_HANDLE_VALUE) } == FALSE {
+                log::error!("Failed to unregister wait: {:?}", std::io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod sys {
+    use super::WaitContext;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Condvar, Mutex, MutexGuard,
+        },
+        time::{Duration, Instant},
+    };
+    use windows_sys::Win32::Foundation::HANDLE;
+
+    struct Event {
+        manual_reset: bool,
+        /// Only modified while holding `LOCK`
+        signaled: AtomicBool,
+    }
+
+    /// One lock and condition variable shared by every event, so that a thread can wait on any
+    /// number of events at once. The lock also guards the registered waits
+    static LOCK: Mutex<Vec<(HANDLE, Arc<WaitContext>)>> = Mutex::new(Vec::new());
+    static SIGNALED: Condvar = Condvar::new();
+
+    fn lock() -> MutexGuard<'static, Vec<(HANDLE, Arc<WaitContext>)>> {
+        LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// # Safety
+    /// `event` must have been returned by [`create`] and not closed yet
+    unsafe fn event<'a>(event: HANDLE) -> &'a Event {
+        &*(event as *const Event)
+    }
+
+    /// Consumes the signal of `event` if it is an auto reset event, returning whether it was signaled
+    fn try_acquire(event: &Event) -> bool {
+        let signaled = event.signaled.load(Ordering::Relaxed);
+        if signaled && !event.manual_reset {
+            event.signaled.store(false, Ordering::Relaxed);
+        }
+        signaled
+    }
+
+    pub(crate) fn create(manual_reset: bool, initial_state: bool) -> std::io::Result<HANDLE> {
+        let event = Box::new(Event {
+            manual_reset,
+            signaled: AtomicBool::new(initial_state),
+        });
+        Ok(Box::into_raw(event) as HANDLE)
+    }
+
+    pub(crate) fn set(handle: HANDLE) -> std::io::Result<()> {
+        let mut waits = lock();
+        let event = unsafe { event(handle) };
+        event.signaled.store(true, Ordering::Relaxed);
+        //Like the system thread pool, registered waits are satisfied as if they were a waiting thread
+        waits.retain(|(waiting_on, context)| {
+            if *waiting_on != handle || !try_acquire(event) {
+                return true;
+            }
+            context.fire();
+            false
+        });
+        SIGNALED.notify_all();
+        Ok(())
+    }
+
+    pub(crate) fn close(handle: HANDLE) -> std::io::Result<()> {
+        let mut waits = lock();
+        waits.retain(|(waiting_on, _)| *waiting_on != handle);
+        drop(unsafe { Box::from_raw(handle as *mut Event) });
+        Ok(())
+    }
+
+    pub(crate) fn wait_any(events: &[HANDLE], timeout_ms: u32) -> std::io::Result<Option<usize>> {
+        let deadline = match timeout_ms {
+            super::INFINITE => None,
+            ms => Some(Instant::now() + Duration::from_millis(ms as u64)),
+        };
+        let mut guard = lock();
+        loop {
+            for (i, handle) in events.iter().enumerate() {
+                if try_acquire(unsafe { event(*handle) }) {
+                    return Ok(Some(i));
+                }
+            }
+            guard = match deadline {
+                None => SIGNALED.wait(guard).unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    SIGNALED
+                        .wait_timeout(guard, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+            };
+        }
+    }
+
+    /// A wait in the list guarded by `LOCK`
+    pub(crate) struct Registration(Arc<WaitContext>);
+
+    pub(crate) fn register(handle: HANDLE, context: &Arc<WaitContext>) -> std::io::Result<Registration> {
+        let mut waits = lock();
+        if try_acquire(unsafe { event(handle) }) {
+            context.fire();
+        } else {
+            waits.push((handle, context.clone()));
+        }
+        Ok(Registration(context.clone()))
+    }
+
+    impl Drop for Registration {
+        fn drop(&mut self) {
+            lock().retain(|(_, context)| !Arc::ptr_eq(context, &self.0));
+        }
+    }
+}
+
+/// Creates a new unnamed event. Maps to CreateEventW
+pub(crate) fn create(manual_reset: bool, initial_state: bool) -> std::io::Result<HANDLE> {
+    sys::create(manual_reset, initial_state)
+}
+
+/// Signals `event`. Maps to SetEvent
+pub(crate) fn set(event: HANDLE) -> std::io::Result<()> {
+    sys::set(event)
+}
+
+/// Closes an event created with [`create`]. Maps to CloseHandle
+pub(crate) fn close(event: HANDLE) -> std::io::Result<()> {
+    sys::close(event)
+}
+
+/// Waits until any of `events` is signaled, returning its index, or until `timeout_ms`
+/// milliseconds have passed, returning `None`. Maps to WaitForMultipleObjects
+pub(crate) fn wait_any(events: &[HANDLE], timeout_ms: u32) -> std::io::Result<Option<usize>> {
+    sys::wait_any(events, timeout_ms)
+}
+
+/// A one shot asynchronous wait for an event. Maps to RegisterWaitForSingleObject
+///
+/// Once the event is signaled the wait fires: [`Wait::fired`] starts returning true and the most
+/// recently set waker is woken. Like a waiting thread, firing consumes the signal of an auto reset
+/// event. Dropping the wait cancels it
+pub(crate) struct Wait {
+    //Declared first so that it is dropped, and the callback can no longer run, before the context
+    _registration: sys::Registration,
+    context: Arc<WaitContext>,
+}
+
+impl Wait {
+    /// Returns true once the event has been signaled
+    pub(crate) fn fired(&self) -> bool {
+        self.context.fired.load(Ordering::SeqCst)
+    }
+
+    /// Replaces the waker that is woken when the wait fires
+    pub(crate) fn set_waker(&self, waker: &Waker) {
+        let mut current = self.context.waker.lock().unwrap_or_else(|e| e.into_inner());
+        match current.as_ref() {
+            Some(current) if current.will_wake(waker) => {}
+            _ => *current = Some(waker.clone()),
+        }
+    }
+}
+
+/// Starts waiting for `event` in the background, waking `waker` once it is signaled
+pub(crate) fn register_wait(event: HANDLE, waker: &Waker) -> std::io::Result<Wait> {
+    let context = Arc::new(WaitContext {
+        fired: AtomicBool::new(false),
+        waker: Mutex::new(Some(waker.clone())),
+    });
+    Ok(Wait {
+        _registration: sys::register(event, &context)?,
+        context,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn timeout_ms_rounds_up_and_caps() {
+        let now = Instant::now();
+        assert_eq!(timeout_ms(None), INFINITE);
+        assert_eq!(timeout_ms(Some(now)), 0);
+        let deadline = now + Duration::from_micros(100_500);
+        let ms = timeout_ms(Some(deadline));
+        assert!(ms <= 101 && Instant::now() + Duration::from_millis(ms as u64) >= deadline);
+        let far = now + Duration::from_secs(100 * 24 * 60 * 60);
+        assert_eq!(timeout_ms(Some(far)), INFINITE - 1);
+    }
+}
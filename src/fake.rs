@@ -0,0 +1,408 @@
+//! A pure rust stand in for wintun.dll
+//!
+//! [`FakeWintun`] implements [`WintunApi`] entirely in memory so that code built on [`Adapter`] and
+//! [`Session`] can be exercised without the wintun driver or administrator rights. Packets "sent by
+//! the system" are injected with [`FakeWintun::inject_packet`] and packets sent through the session
+//! are collected with [`FakeWintun::take_sent_packets`]. Each session is backed by [`SessionRings`],
+//! so ordering and backpressure match the real driver. Two sessions can also be wired back to back
+//! with [`FakeWintun::connect_sessions`] (see [`Session::pair`]), so that one receives what the
+//! other sends.
+
+use crate::{
+    api::{AdapterHandle, LoggerCallback, SessionHandle, WintunApi},
+    event,
+    ring::SessionRings,
+    Error, Session,
+};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+};
+use windows_sys::{
+    core::GUID,
+    Win32::{
+        Foundation::{ERROR_FILE_NOT_FOUND, ERROR_INVALID_HANDLE, ERROR_INVALID_PARAMETER, HANDLE, WIN32_ERROR},
+        NetworkManagement::Ndis::NET_LUID_LH,
+    },
+};
+
+/// The fallible wintun functions that [`FakeWintun::fail_next`] can make fail
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum FakeCall {
+    CreateAdapter,
+    OpenAdapter,
+    GetRunningDriverVersion,
+    DeleteDriver,
+    StartSession,
+    ReceivePacket,
+    AllocateSendPacket,
+}
+
+struct FakeAdapter {
+    name: String,
+    guid: u128,
+    luid: u64,
+}
+
+struct FakeSession {
+    /// Auto reset event signaled whenever a packet is injected, like the driver's read event
+    read_event: HANDLE,
+
+    /// The rings shared with the "driver"
+    rings: SessionRings,
+
+    /// Handle of the session that receives the packets sent through this one
+    peer: Option<usize>,
+}
+
+#[derive(Default)]
+struct State {
+    next_handle: usize,
+    adapters: HashMap<usize, FakeAdapter>,
+    sessions: HashMap<usize, FakeSession>,
+    /// How many more calls succeed before the call fails, and the error it fails with
+    failures: HashMap<FakeCall, (usize, WIN32_ERROR)>,
+    logger: LoggerCallback,
+    driver_version: u32,
+}
+
+impl State {
+    fn new_handle(&mut self) -> usize {
+        //Handles are never dereferenced, they only have to be unique and non null
+        self.next_handle += 1;
+        self.next_handle
+    }
+
+    fn check(&mut self, call: FakeCall) -> Result<(), WIN32_ERROR> {
+        match self.failures.get_mut(&call) {
+            Some((0, error)) => {
+                let error = *error;
+                self.failures.remove(&call);
+                Err(error)
+            }
+            Some((successes, _)) => {
+                *successes -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Moves the packets sent through `session` into the receive ring of its peer, if it has one.
+    /// Like the real driver, packets that do not fit in the peer's receive ring are dropped
+    fn forward(&mut self, session: usize) {
+        let Some(session) = self.sessions.get_mut(&session) else {
+            return;
+        };
+        let Some(peer) = session.peer else {
+            return;
+        };
+        let packets: Vec<_> = std::iter::from_fn(|| session.rings.read_packet()).collect();
+        let Some(peer) = self.sessions.get_mut(&peer).filter(|_| !packets.is_empty()) else {
+            return;
+        };
+        for packet in packets {
+            if let Err(e) = peer.rings.write_packet(&packet) {
+                log::warn!("Dropping looped back packet of {} bytes: {}", packet.len(), e);
+            }
+        }
+        if let Err(e) = event::set(peer.read_event) {
+            log::error!("Failed to signal read event: {:?}", e);
+        }
+    }
+}
+
+/// An in memory implementation of the wintun driver and dll
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+///
+/// let fake = Arc::new(wintun::FakeWintun::new());
+/// let wintun: wintun::Wintun = fake.clone();
+///
+/// let adapter = wintun::Adapter::create(&wintun, "Demo", "Example", None).unwrap();
+/// let session = Arc::new(adapter.start_session(wintun::MAX_RING_CAPACITY).unwrap());
+///
+/// fake.inject_packet(&session, &[0x45, 0, 0, 20]).unwrap();
+/// let packet = session.receive_blocking().unwrap();
+/// assert_eq!(packet.bytes(), &[0x45, 0, 0, 20]);
+///
+/// let mut packet = session.allocate_send_packet(2).unwrap();
+/// packet.bytes_mut().copy_from_slice(&[1, 2]);
+/// session.send_packet(packet);
+/// assert_eq!(fake.take_sent_packets(&session).unwrap(), vec![vec![1, 2]]);
+/// ```
+pub struct FakeWintun {
+    state: Mutex<State>,
+}
+
+impl Default for FakeWintun {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeWintun {
+    /// Creates a fake driver with no adapters that reports driver version 0.14
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                driver_version: 0x000e,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        //A panic while holding the lock cannot leave the state half updated, so ignore poisoning
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn with_session<T>(&self, session: &Session, f: impl FnOnce(&mut FakeSession) -> T) -> Result<T, Error> {
+        let mut state = self.state();
+        let session = state
+            .sessions
+            .get_mut(&(session.session.0 as usize))
+            .ok_or("Session was not started by this FakeWintun")?;
+        Ok(f(session))
+    }
+
+    /// Makes the next call to `call` fail with the Win32 error code `error`
+    pub fn fail_next(&self, call: FakeCall, error: WIN32_ERROR) {
+        self.fail_after(call, 0, error);
+    }
+
+    /// Lets the next `successes` calls to `call` through, then makes the one after fail with the
+    /// Win32 error code `error`
+    pub fn fail_after(&self, call: FakeCall, successes: usize, error: WIN32_ERROR) {
+        self.state().failures.insert(call, (successes, error));
+    }
+
+    /// Sets the version returned by WintunGetRunningDriverVersion
+    pub fn set_driver_version(&self, version: u32) {
+        self.state().driver_version = version;
+    }
+
+    /// Returns the logger most recently set through WintunSetLogger
+    pub fn logger(&self) -> LoggerCallback {
+        self.state().logger
+    }
+
+    /// Queues a packet as if the system had sent it to the adapter, waking any readers of `session`.
+    ///
+    /// Fails with [`Error::RingFull`] if the session's receive ring is full, which is when the
+    /// real driver would drop the packet
+    pub fn inject_packet(&self, session: &Session, packet: &[u8]) -> Result<(), Error> {
+        let read_event = self.with_session(session, |session| {
+            session.rings.write_packet(packet).map(|()| session.read_event)
+        })?;
+        let read_event = read_event.map_err(|e| Error::wintun_packet("FakeWintun::inject_packet", e, packet.len()))?;
+        Ok(event::set(read_event)?)
+    }
+
+    /// Removes and returns the contents of the packets sent through `session`, oldest first.
+    ///
+    /// Like the real driver, a sent packet is only returned once every packet allocated before it
+    /// has been sent too
+    pub fn take_sent_packets(&self, session: &Session) -> Result<Vec<Vec<u8>>, Error> {
+        self.with_session(session, |session| {
+            std::iter::from_fn(|| session.rings.read_packet()).collect()
+        })
+    }
+
+    /// Returns the number of received packets that have not been released yet
+    pub fn unreleased_packets(&self, session: &Session) -> Result<usize, Error> {
+        self.with_session(session, |session| session.rings.unreleased_packets())
+    }
+
+    /// Returns the number of allocated send packets that have not been sent yet
+    pub fn unsent_packets(&self, session: &Session) -> Result<usize, Error> {
+        self.with_session(session, |session| session.rings.unsent_packets())
+    }
+
+    /// Closes the rings of `session` as if its adapter was removed. All further receives and
+    /// allocations on the session fail with [`Error::AdapterGone`]
+    pub fn close_session_rings(&self, session: &Session) -> Result<(), Error> {
+        let read_event = self.with_session(session, |session| {
+            session.rings.close();
+            session.read_event
+        })?;
+        Ok(event::set(read_event)?)
+    }
+
+    /// Connects two sessions back to back, so that every packet sent through one is received by
+    /// the other. Packets sent through a connected session are no longer returned by
+    /// [`FakeWintun::take_sent_packets`]
+    pub fn connect_sessions(&self, a: &Session, b: &Session) -> Result<(), Error> {
+        let (a, b) = (a.session.0 as usize, b.session.0 as usize);
+        if a == b {
+            return Err("Cannot connect a session to itself".into());
+        }
+        let mut state = self.state();
+        if !state.sessions.contains_key(&a) || !state.sessions.contains_key(&b) {
+            return Err("Session was not started by this FakeWintun".into());
+        }
+        for (session, peer) in [(a, b), (b, a)] {
+            state.sessions.get_mut(&session).unwrap().peer = Some(peer);
+            //Deliver anything that was sent before the sessions were connected
+            state.forward(session);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of adapter handles that are currently open
+    pub fn open_adapters(&self) -> usize {
+        self.state().adapters.len()
+    }
+
+    /// Returns the number of sessions that are currently running
+    pub fn running_sessions(&self) -> usize {
+        self.state().sessions.len()
+    }
+}
+
+unsafe fn wide_to_string(s: *const u16) -> Result<String, WIN32_ERROR> {
+    if s.is_null() {
+        return Err(ERROR_INVALID_PARAMETER);
+    }
+    let mut len = 0;
+    while *s.add(len) != 0 {
+        len += 1;
+    }
+    String::from_utf16(std::slice::from_raw_parts(s, len)).map_err(|_| ERROR_INVALID_PARAMETER)
+}
+
+/// SAFETY: Packets live inside the session's rings, which only reuse their memory once the packet
+/// is released or sent, and are only freed when the session ends
+unsafe impl WintunApi for FakeWintun {
+    unsafe fn create_adapter(
+        &self,
+        name: *const u16,
+        _tunnel_type: *const u16,
+        requested_guid: *const GUID,
+    ) -> Result<AdapterHandle, WIN32_ERROR> {
+        let mut state = self.state();
+        state.check(FakeCall::CreateAdapter)?;
+        let name = wide_to_string(name)?;
+        let handle = state.new_handle();
+        let guid = match requested_guid.as_ref() {
+            Some(guid) => crate::util::win_guid_to_u128(guid),
+            None => handle as u128,
+        };
+        let adapter = FakeAdapter {
+            name,
+            guid,
+            luid: handle as u64,
+        };
+        state.adapters.insert(handle, adapter);
+        Ok(handle as AdapterHandle)
+    }
+
+    unsafe fn close_adapter(&self, adapter: AdapterHandle) {
+        self.state().adapters.remove(&(adapter as usize));
+    }
+
+    unsafe fn open_adapter(&self, name: *const u16) -> Result<AdapterHandle, WIN32_ERROR> {
+        let mut state = self.state();
+        state.check(FakeCall::OpenAdapter)?;
+        let name = wide_to_string(name)?;
+        let (guid, luid) = state
+            .adapters
+            .values()
+            .find(|adapter| adapter.name == name)
+            .map(|adapter| (adapter.guid, adapter.luid))
+            .ok_or(ERROR_FILE_NOT_FOUND)?;
+        let handle = state.new_handle();
+        state.adapters.insert(handle, FakeAdapter { name, guid, luid });
+        Ok(handle as AdapterHandle)
+    }
+
+    unsafe fn get_adapter_luid(&self, adapter: AdapterHandle) -> NET_LUID_LH {
+        let luid = self.state().adapters.get(&(adapter as usize)).map_or(0, |a| a.luid);
+        NET_LUID_LH { Value: luid }
+    }
+
+    fn get_running_driver_version(&self) -> Result<u32, WIN32_ERROR> {
+        let mut state = self.state();
+        state.check(FakeCall::GetRunningDriverVersion)?;
+        Ok(state.driver_version)
+    }
+
+    fn delete_driver(&self) -> Result<(), WIN32_ERROR> {
+        self.state().check(FakeCall::DeleteDriver)
+    }
+
+    fn set_logger(&self, callback: LoggerCallback) {
+        self.state().logger = callback;
+    }
+
+    unsafe fn start_session(&self, adapter: AdapterHandle, capacity: u32) -> Result<SessionHandle, WIN32_ERROR> {
+        let mut state = self.state();
+        state.check(FakeCall::StartSession)?;
+        if !state.adapters.contains_key(&(adapter as usize)) {
+            return Err(ERROR_INVALID_HANDLE);
+        }
+        let rings = SessionRings::new(capacity).map_err(|_| ERROR_INVALID_PARAMETER)?;
+        let read_event = event::create(false, false).map_err(|e| e.raw_os_error().unwrap_or(0) as WIN32_ERROR)?;
+        let handle = state.new_handle();
+        let session = FakeSession {
+            read_event,
+            rings,
+            peer: None,
+        };
+        state.sessions.insert(handle, session);
+        Ok(handle as SessionHandle)
+    }
+
+    unsafe fn end_session(&self, session: SessionHandle) {
+        let mut state = self.state();
+        if let Some(session) = state.sessions.remove(&(session as usize)) {
+            if let Some(peer) = session.peer.and_then(|peer| state.sessions.get_mut(&peer)) {
+                peer.peer = None;
+            }
+            let _ = event::close(session.read_event);
+        }
+    }
+
+    unsafe fn get_read_wait_event(&self, session: SessionHandle) -> HANDLE {
+        self.state()
+            .sessions
+            .get(&(session as usize))
+            .map_or(0, |s| s.read_event)
+    }
+
+    unsafe fn receive_packet(&self, session: SessionHandle) -> Result<(*mut u8, u32), WIN32_ERROR> {
+        let mut state = self.state();
+        state.check(FakeCall::ReceivePacket)?;
+        let session = state
+            .sessions
+            .get_mut(&(session as usize))
+            .ok_or(ERROR_INVALID_HANDLE)?;
+        session.rings.receive_packet()
+    }
+
+    unsafe fn release_receive_packet(&self, session: SessionHandle, packet: *const u8) {
+        if let Some(session) = self.state().sessions.get_mut(&(session as usize)) {
+            session.rings.release_receive_packet(packet);
+        }
+    }
+
+    unsafe fn allocate_send_packet(&self, session: SessionHandle, size: u32) -> Result<*mut u8, WIN32_ERROR> {
+        let mut state = self.state();
+        state.check(FakeCall::AllocateSendPacket)?;
+        let session = state
+            .sessions
+            .get_mut(&(session as usize))
+            .ok_or(ERROR_INVALID_HANDLE)?;
+        session.rings.allocate_send_packet(size)
+    }
+
+    unsafe fn send_packet(&self, session: SessionHandle, packet: *const u8) {
+        let mut state = self.state();
+        if let Some(fake) = state.sessions.get_mut(&(session as usize)) {
+            fake.rings.send_packet(packet);
+            state.forward(session as usize);
+        }
+    }
+}
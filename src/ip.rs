@@ -0,0 +1,796 @@
+//! Zero copy views over the headers of IP packets.
+//!
+//! Each view borrows the bytes of a packet, such as [`crate::RecvPacket::bytes`], and checks when
+//! it is created that the header it covers is complete and consistent. Accessors read fields
+//! straight out of the borrowed bytes.
+//!
+//! # Example
+//! ```
+//! use wintun::ip::{IpView, UdpView, PROTOCOL_UDP};
+//!
+//! let (a, b) = wintun::Session::pair().unwrap();
+//! a.send_batch(&[&[
+//!     0x45, 0, 0, 31, 0, 0, 0, 0, 64, PROTOCOL_UDP, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2, //IPv4
+//!     0x30, 0x39, 0x10, 0xE1, 0, 11, 0, 0, //UDP
+//!     b'h', b'e', b'y',
+//! ]])
+//! .unwrap();
+//!
+//! let packet = b.receive_blocking().unwrap();
+//! let ip = IpView::new(packet.bytes()).unwrap();
+//! assert_eq!(ip.source(), "10.0.0.1".parse::<std::net::IpAddr>().unwrap());
+//! assert_eq!(ip.protocol(), PROTOCOL_UDP);
+//!
+//! let udp = UdpView::new(ip.payload()).unwrap();
+//! assert_eq!((udp.source_port(), udp.destination_port()), (12345, 4321));
+//! assert_eq!(udp.payload(), b"hey");
+//! ```
+
+use crate::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// IP protocol number of ICMP
+pub const PROTOCOL_ICMP: u8 = 1;
+
+/// IP protocol number of TCP
+pub const PROTOCOL_TCP: u8 = 6;
+
+/// IP protocol number of UDP
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// IP protocol number of ICMPv6
+pub const PROTOCOL_ICMPV6: u8 = 58;
+
+/// IPv6 extension headers skipped by [`Ipv6View`]
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+pub(crate) const IPV6_FRAGMENT: u8 = 44;
+const IPV6_DESTINATION_OPTIONS: u8 = 60;
+
+/// TCP flag bit, as returned by [`TcpView::flags`]
+pub const TCP_FIN: u8 = 0x01;
+/// TCP flag bit, as returned by [`TcpView::flags`]
+pub const TCP_SYN: u8 = 0x02;
+/// TCP flag bit, as returned by [`TcpView::flags`]
+pub const TCP_RST: u8 = 0x04;
+/// TCP flag bit, as returned by [`TcpView::flags`]
+pub const TCP_PSH: u8 = 0x08;
+/// TCP flag bit, as returned by [`TcpView::flags`]
+pub const TCP_ACK: u8 = 0x10;
+/// TCP flag bit, as returned by [`TcpView::flags`]
+pub const TCP_URG: u8 = 0x20;
+
+/// Size of an IPv4 header without options
+pub const IPV4_HEADER_LEN: usize = 20;
+
+/// Size of the fixed IPv6 header
+pub const IPV6_HEADER_LEN: usize = 40;
+
+/// Size of a UDP header
+pub const UDP_HEADER_LEN: usize = 8;
+
+/// Size of a TCP header without options
+pub const TCP_HEADER_LEN: usize = 20;
+
+/// Size of an ICMP or ICMPv6 header, including the 4 bytes whose meaning depends on the type
+pub const ICMP_HEADER_LEN: usize = 8;
+
+pub(crate) fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// An IPv4 or IPv6 packet, depending on the version in its first byte
+#[derive(Copy, Clone, Debug)]
+pub enum IpView<'a> {
+    V4(Ipv4View<'a>),
+    V6(Ipv6View<'a>),
+}
+
+impl<'a> IpView<'a> {
+    /// Validates the IP header at the start of `bytes`
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        match bytes.first().map(|byte| byte >> 4) {
+            Some(4) => Ok(Self::V4(Ipv4View::new(bytes)?)),
+            Some(6) => Ok(Self::V6(Ipv6View::new(bytes)?)),
+            Some(_) => Err(Error::MalformedPacket("unknown IP version")),
+            None => Err(Error::MalformedPacket("packet is empty")),
+        }
+    }
+
+    pub fn source(&self) -> IpAddr {
+        match self {
+            Self::V4(ip) => ip.source().into(),
+            Self::V6(ip) => ip.source().into(),
+        }
+    }
+
+    pub fn destination(&self) -> IpAddr {
+        match self {
+            Self::V4(ip) => ip.destination().into(),
+            Self::V6(ip) => ip.destination().into(),
+        }
+    }
+
+    /// Returns the protocol of the payload, see [`Ipv4View::protocol`] and [`Ipv6View::protocol`]
+    pub fn protocol(&self) -> u8 {
+        match self {
+            Self::V4(ip) => ip.protocol(),
+            Self::V6(ip) => ip.protocol(),
+        }
+    }
+
+    /// Returns true if this packet is a fragment of a larger one
+    pub fn is_fragment(&self) -> bool {
+        match self {
+            Self::V4(ip) => ip.is_fragment(),
+            Self::V6(ip) => ip.is_fragment(),
+        }
+    }
+
+    /// Returns the bytes following the IP headers, see [`Ipv4View::payload`] and
+    /// [`Ipv6View::payload`]
+    pub fn payload(&self) -> &'a [u8] {
+        match self {
+            Self::V4(ip) => ip.payload(),
+            Self::V6(ip) => ip.payload(),
+        }
+    }
+
+    /// Returns the bytes of the packet, without any padding after it
+    pub fn bytes(&self) -> &'a [u8] {
+        match self {
+            Self::V4(ip) => ip.bytes(),
+            Self::V6(ip) => ip.bytes(),
+        }
+    }
+}
+
+/// An IPv4 header and its payload
+#[derive(Copy, Clone, Debug)]
+pub struct Ipv4View<'a> {
+    /// Cut to the total length of the packet
+    bytes: &'a [u8],
+}
+
+impl<'a> Ipv4View<'a> {
+    /// Validates the IPv4 header at the start of `bytes`. Bytes after the total length given in
+    /// the header are ignored
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        if bytes.len() < IPV4_HEADER_LEN {
+            return Err(Error::MalformedPacket("IPv4 header is truncated"));
+        }
+        if bytes[0] >> 4 != 4 {
+            return Err(Error::MalformedPacket("IP version is not 4"));
+        }
+        let header_len = (bytes[0] & 0x0F) as usize * 4;
+        if header_len < IPV4_HEADER_LEN {
+            return Err(Error::MalformedPacket("IPv4 header length is less than 20 bytes"));
+        }
+        let total_len = read_u16(bytes, 2) as usize;
+        if total_len < header_len {
+            return Err(Error::MalformedPacket(
+                "IPv4 total length is less than the header length",
+            ));
+        }
+        if total_len > bytes.len() {
+            return Err(Error::MalformedPacket("IPv4 packet is shorter than its total length"));
+        }
+        Ok(Self {
+            bytes: &bytes[..total_len],
+        })
+    }
+
+    /// Returns the length of the header including options, in bytes
+    pub fn header_len(&self) -> usize {
+        (self.bytes[0] & 0x0F) as usize * 4
+    }
+
+    pub fn dscp(&self) -> u8 {
+        self.bytes[1] >> 2
+    }
+
+    pub fn ecn(&self) -> u8 {
+        self.bytes[1] & 0x03
+    }
+
+    /// Returns the length of the header and payload, in bytes
+    pub fn total_len(&self) -> u16 {
+        read_u16(self.bytes, 2)
+    }
+
+    pub fn identification(&self) -> u16 {
+        read_u16(self.bytes, 4)
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.bytes[6] & 0x40 != 0
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.bytes[6] & 0x20 != 0
+    }
+
+    /// Returns the offset of this fragment's payload in the original payload, in units of 8 bytes
+    pub fn fragment_offset(&self) -> u16 {
+        read_u16(self.bytes, 6) & 0x1FFF
+    }
+
+    /// Returns true if this packet is a fragment of a larger one. Only the first fragment starts
+    /// with the header of the payload protocol
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    /// Returns the protocol of the payload, such as [`PROTOCOL_UDP`]
+    pub fn protocol(&self) -> u8 {
+        self.bytes[9]
+    }
+
+    pub fn header_checksum(&self) -> u16 {
+        read_u16(self.bytes, 10)
+    }
+
+    pub fn source(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.bytes[12], self.bytes[13], self.bytes[14], self.bytes[15])
+    }
+
+    pub fn destination(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.bytes[16], self.bytes[17], self.bytes[18], self.bytes[19])
+    }
+
+    pub fn options(&self) -> &'a [u8] {
+        &self.bytes[IPV4_HEADER_LEN..self.header_len()]
+    }
+
+    /// Returns the header including options
+    pub fn header(&self) -> &'a [u8] {
+        &self.bytes[..self.header_len()]
+    }
+
+    /// Returns the bytes following the header
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[self.header_len()..]
+    }
+
+    /// Returns the bytes of the packet, without any padding after it
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+/// An IPv6 header, its extension headers and the payload
+#[derive(Copy, Clone, Debug)]
+pub struct Ipv6View<'a> {
+    /// Cut to the payload length of the packet
+    bytes: &'a [u8],
+
+    /// Offset of the first header that is not a skipped extension header
+    payload_offset: usize,
+
+    /// Protocol of the header at payload_offset
+    protocol: u8,
+
+    /// Set if a fragment header says this is a fragment of a larger packet
+    fragment: bool,
+}
+
+impl<'a> Ipv6View<'a> {
+    /// Validates the IPv6 header at the start of `bytes`, along with the hop by hop, routing,
+    /// destination options and fragment extension headers following it. Bytes after the payload
+    /// length given in the header are ignored
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        if bytes.len() < IPV6_HEADER_LEN {
+            return Err(Error::MalformedPacket("IPv6 header is truncated"));
+        }
+        if bytes[0] >> 4 != 6 {
+            return Err(Error::MalformedPacket("IP version is not 6"));
+        }
+        let len = IPV6_HEADER_LEN + read_u16(bytes, 4) as usize;
+        if len > bytes.len() {
+            return Err(Error::MalformedPacket("IPv6 packet is shorter than its payload length"));
+        }
+        let bytes = &bytes[..len];
+
+        let (mut offset, mut protocol, mut fragment) = (IPV6_HEADER_LEN, bytes[6], false);
+        loop {
+            let header_len = match protocol {
+                IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION_OPTIONS => match bytes.get(offset + 1) {
+                    Some(&len) => (len as usize + 1) * 8,
+                    None => return Err(Error::MalformedPacket("IPv6 extension header is truncated")),
+                },
+                //Later fragments do not start with the header of the payload protocol, so stop
+                //at their fragment header
+                IPV6_FRAGMENT => match bytes.get(offset..offset + 8) {
+                    Some(header) => {
+                        //The low bit is the more fragments flag
+                        fragment = read_u16(header, 2) & 0xFFF9 != 0;
+                        if read_u16(header, 2) & 0xFFF8 != 0 {
+                            break;
+                        }
+                        8
+                    }
+                    None => return Err(Error::MalformedPacket("IPv6 fragment header is truncated")),
+                },
+                _ => break,
+            };
+            if offset + header_len > bytes.len() {
+                return Err(Error::MalformedPacket("IPv6 extension header is truncated"));
+            }
+            protocol = bytes[offset];
+            offset += header_len;
+        }
+        Ok(Self {
+            bytes,
+            payload_offset: offset,
+            protocol,
+            fragment,
+        })
+    }
+
+    pub fn traffic_class(&self) -> u8 {
+        ((read_u16(self.bytes, 0) >> 4) & 0xFF) as u8
+    }
+
+    pub fn flow_label(&self) -> u32 {
+        read_u32(self.bytes, 0) & 0x000F_FFFF
+    }
+
+    /// Returns the length of everything after the fixed header, in bytes
+    pub fn payload_len(&self) -> u16 {
+        read_u16(self.bytes, 4)
+    }
+
+    /// Returns the next header field of the fixed header, which may be an extension header
+    pub fn next_header(&self) -> u8 {
+        self.bytes[6]
+    }
+
+    pub fn hop_limit(&self) -> u8 {
+        self.bytes[7]
+    }
+
+    pub fn source(&self) -> Ipv6Addr {
+        Ipv6Addr::from(<[u8; 16]>::try_from(&self.bytes[8..24]).unwrap())
+    }
+
+    pub fn destination(&self) -> Ipv6Addr {
+        Ipv6Addr::from(<[u8; 16]>::try_from(&self.bytes[24..40]).unwrap())
+    }
+
+    /// Returns true if this packet is a fragment of a larger one. Only the first fragment starts
+    /// with the header of the payload protocol
+    pub fn is_fragment(&self) -> bool {
+        self.fragment
+    }
+
+    /// Returns the protocol of the payload, such as [`PROTOCOL_UDP`]. For fragments other than the
+    /// first this is the fragment extension header
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    /// Returns the fixed header and the extension headers that were skipped
+    pub fn header(&self) -> &'a [u8] {
+        &self.bytes[..self.payload_offset]
+    }
+
+    /// Returns the bytes following the fixed header and extension headers
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[self.payload_offset..]
+    }
+
+    /// Returns the bytes of the packet, without any padding after it
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+/// A UDP header and its payload
+#[derive(Copy, Clone, Debug)]
+pub struct UdpView<'a> {
+    /// Cut to the length given in the header
+    bytes: &'a [u8],
+}
+
+impl<'a> UdpView<'a> {
+    /// Validates the UDP header at the start of `bytes`, usually the payload of an IP view
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        if bytes.len() < UDP_HEADER_LEN {
+            return Err(Error::MalformedPacket("UDP header is truncated"));
+        }
+        let len = read_u16(bytes, 4) as usize;
+        if len < UDP_HEADER_LEN {
+            return Err(Error::MalformedPacket("UDP length is less than 8 bytes"));
+        }
+        if len > bytes.len() {
+            return Err(Error::MalformedPacket("UDP datagram is shorter than its length"));
+        }
+        Ok(Self { bytes: &bytes[..len] })
+    }
+
+    pub fn source_port(&self) -> u16 {
+        read_u16(self.bytes, 0)
+    }
+
+    pub fn destination_port(&self) -> u16 {
+        read_u16(self.bytes, 2)
+    }
+
+    /// Returns the length of the header and payload, in bytes
+    pub fn length(&self) -> u16 {
+        read_u16(self.bytes, 4)
+    }
+
+    pub fn checksum(&self) -> u16 {
+        read_u16(self.bytes, 6)
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[UDP_HEADER_LEN..]
+    }
+}
+
+/// A TCP header and its payload
+#[derive(Copy, Clone, Debug)]
+pub struct TcpView<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> TcpView<'a> {
+    /// Validates the TCP header at the start of `bytes`, usually the payload of an IP view
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        if bytes.len() < TCP_HEADER_LEN {
+            return Err(Error::MalformedPacket("TCP header is truncated"));
+        }
+        let header_len = (bytes[12] >> 4) as usize * 4;
+        if header_len < TCP_HEADER_LEN {
+            return Err(Error::MalformedPacket("TCP data offset is less than 20 bytes"));
+        }
+        if header_len > bytes.len() {
+            return Err(Error::MalformedPacket("TCP options are truncated"));
+        }
+        Ok(Self { bytes })
+    }
+
+    pub fn source_port(&self) -> u16 {
+        read_u16(self.bytes, 0)
+    }
+
+    pub fn destination_port(&self) -> u16 {
+        read_u16(self.bytes, 2)
+    }
+
+    pub fn sequence_number(&self) -> u32 {
+        read_u32(self.bytes, 4)
+    }
+
+    pub fn acknowledgment_number(&self) -> u32 {
+        read_u32(self.bytes, 8)
+    }
+
+    /// Returns the length of the header including options, in bytes
+    pub fn header_len(&self) -> usize {
+        (self.bytes[12] >> 4) as usize * 4
+    }
+
+    /// Returns the flag bits, see [`TCP_SYN`] and friends
+    pub fn flags(&self) -> u8 {
+        self.bytes[13]
+    }
+
+    pub fn window(&self) -> u16 {
+        read_u16(self.bytes, 14)
+    }
+
+    pub fn checksum(&self) -> u16 {
+        read_u16(self.bytes, 16)
+    }
+
+    pub fn urgent_pointer(&self) -> u16 {
+        read_u16(self.bytes, 18)
+    }
+
+    pub fn options(&self) -> &'a [u8] {
+        &self.bytes[TCP_HEADER_LEN..self.header_len()]
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[self.header_len()..]
+    }
+}
+
+/// An ICMP or ICMPv6 message
+#[derive(Copy, Clone, Debug)]
+pub struct IcmpView<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> IcmpView<'a> {
+    /// Validates the ICMP or ICMPv6 header at the start of `bytes`, usually the payload of an IP
+    /// view
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        if bytes.len() < ICMP_HEADER_LEN {
+            return Err(Error::MalformedPacket("ICMP header is truncated"));
+        }
+        Ok(Self { bytes })
+    }
+
+    pub fn icmp_type(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn code(&self) -> u8 {
+        self.bytes[1]
+    }
+
+    pub fn checksum(&self) -> u16 {
+        read_u16(self.bytes, 2)
+    }
+
+    /// Returns the 4 bytes after the checksum, whose meaning depends on the type
+    pub fn rest_of_header(&self) -> [u8; 4] {
+        self.bytes[4..8].try_into().unwrap()
+    }
+
+    /// Returns the identifier of an echo request or reply
+    pub fn echo_identifier(&self) -> u16 {
+        read_u16(self.bytes, 4)
+    }
+
+    /// Returns the sequence number of an echo request or reply
+    pub fn echo_sequence(&self) -> u16 {
+        read_u16(self.bytes, 6)
+    }
+
+    /// Returns the bytes following the header. For error messages this holds the start of the
+    /// packet that caused the error
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[ICMP_HEADER_LEN..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An IPv4 header without options for a packet carrying `payload`
+    fn ipv4_packet(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let total_len = (IPV4_HEADER_LEN + payload.len()) as u16;
+        let mut bytes = vec![0x45, 0, 0, 0, 0, 7, 0, 0, 64, protocol, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+        bytes[2..4].copy_from_slice(&total_len.to_be_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// An IPv6 header for a packet whose next header is `next_header`
+    fn ipv6_packet(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x60, 0, 0, 0];
+        bytes.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&[next_header, 64]);
+        bytes.extend_from_slice(&"fd00::1".parse::<Ipv6Addr>().unwrap().octets());
+        bytes.extend_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn malformed<T: std::fmt::Debug>(result: Result<T, Error>) -> &'static str {
+        match result {
+            Err(Error::MalformedPacket(reason)) => reason,
+            other => panic!("Expected a malformed packet, got {:?}", other),
+        }
+    }
+
+    const UDP: [u8; 10] = [0x30, 0x39, 0x10, 0xE1, 0, 10, 0, 0, b'h', b'i'];
+
+    #[test]
+    fn ipv4() {
+        let mut bytes = ipv4_packet(PROTOCOL_UDP, &UDP);
+        //Padding after the total length is ignored
+        bytes.extend_from_slice(&[0xFF; 3]);
+        let ip = Ipv4View::new(&bytes).unwrap();
+        assert_eq!(ip.header_len(), IPV4_HEADER_LEN);
+        assert_eq!(ip.total_len(), 30);
+        assert_eq!(ip.identification(), 7);
+        assert_eq!((ip.ttl(), ip.protocol()), (64, PROTOCOL_UDP));
+        assert_eq!(ip.source(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(ip.destination(), Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(ip.payload(), &UDP);
+        assert_eq!(ip.bytes().len(), 30);
+        assert!(!ip.is_fragment());
+
+        let udp = UdpView::new(IpView::new(&bytes).unwrap().payload()).unwrap();
+        assert_eq!((udp.source_port(), udp.destination_port()), (12345, 4321));
+        assert_eq!(udp.payload(), b"hi");
+    }
+
+    #[test]
+    fn ipv4_options() {
+        let mut bytes = ipv4_packet(PROTOCOL_UDP, &[[1, 1, 1, 0].as_slice(), &UDP].concat());
+        bytes[0] = 0x46;
+        let ip = Ipv4View::new(&bytes).unwrap();
+        assert_eq!(ip.header_len(), 24);
+        assert_eq!(ip.options(), &[1, 1, 1, 0]);
+        assert_eq!(ip.payload(), &UDP);
+    }
+
+    #[test]
+    fn malformed_ipv4() {
+        let bytes = ipv4_packet(PROTOCOL_UDP, &UDP);
+        assert_eq!(malformed(IpView::new(&[])), "packet is empty");
+        assert_eq!(malformed(IpView::new(&[0x55; 20])), "unknown IP version");
+        assert_eq!(malformed(Ipv4View::new(&bytes[..19])), "IPv4 header is truncated");
+        assert_eq!(
+            malformed(Ipv4View::new(&ipv6_packet(PROTOCOL_UDP, &UDP))),
+            "IP version is not 4"
+        );
+
+        let mut short_header = bytes.clone();
+        short_header[0] = 0x44;
+        assert_eq!(
+            malformed(Ipv4View::new(&short_header)),
+            "IPv4 header length is less than 20 bytes"
+        );
+
+        let mut long_header = bytes.clone();
+        long_header[0] = 0x4F;
+        long_header[3] = 24;
+        assert_eq!(
+            malformed(Ipv4View::new(&long_header)),
+            "IPv4 total length is less than the header length"
+        );
+
+        assert_eq!(
+            malformed(Ipv4View::new(&bytes[..bytes.len() - 1])),
+            "IPv4 packet is shorter than its total length"
+        );
+    }
+
+    #[test]
+    fn ipv4_fragments() {
+        let mut first = ipv4_packet(PROTOCOL_UDP, &UDP);
+        first[6] = 0x20;
+        let ip = IpView::new(&first).unwrap();
+        assert!(ip.is_fragment());
+        assert_eq!(ip.protocol(), PROTOCOL_UDP);
+        assert!(UdpView::new(ip.payload()).is_ok());
+
+        let mut later = ipv4_packet(PROTOCOL_UDP, b"later");
+        later[6..8].copy_from_slice(&3u16.to_be_bytes());
+        let ip = Ipv4View::new(&later).unwrap();
+        assert!(ip.is_fragment() && !ip.more_fragments());
+        assert_eq!(ip.fragment_offset(), 3);
+        assert_eq!(ip.payload(), b"later");
+
+        let mut unfragmented = ipv4_packet(PROTOCOL_UDP, &UDP);
+        unfragmented[6] = 0x40;
+        let ip = Ipv4View::new(&unfragmented).unwrap();
+        assert!(ip.dont_fragment() && !ip.is_fragment());
+    }
+
+    #[test]
+    fn ipv6() {
+        let mut bytes = ipv6_packet(PROTOCOL_UDP, &UDP);
+        bytes.push(0xFF);
+        let ip = Ipv6View::new(&bytes).unwrap();
+        assert_eq!(
+            (ip.payload_len(), ip.next_header(), ip.hop_limit()),
+            (10, PROTOCOL_UDP, 64)
+        );
+        assert_eq!(ip.source(), "fd00::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(ip.protocol(), PROTOCOL_UDP);
+        assert_eq!(ip.header().len(), IPV6_HEADER_LEN);
+        assert_eq!(ip.payload(), &UDP);
+        assert_eq!(ip.bytes().len(), IPV6_HEADER_LEN + UDP.len());
+        assert!(!ip.is_fragment());
+    }
+
+    #[test]
+    fn ipv6_extension_headers() {
+        let payload = [
+            &[IPV6_ROUTING, 0, 1, 4, 0, 0, 0, 0][..], //Hop by hop
+            &[IPV6_DESTINATION_OPTIONS, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], //Routing
+            &[PROTOCOL_UDP, 0, 1, 4, 0, 0, 0, 0],     //Destination options
+            &UDP,
+        ]
+        .concat();
+        let bytes = ipv6_packet(IPV6_HOP_BY_HOP, &payload);
+        let ip = Ipv6View::new(&bytes).unwrap();
+        assert_eq!(ip.next_header(), IPV6_HOP_BY_HOP);
+        assert_eq!(ip.protocol(), PROTOCOL_UDP);
+        assert_eq!(ip.header().len(), IPV6_HEADER_LEN + 32);
+        assert_eq!(ip.payload(), &UDP);
+        assert!(!ip.is_fragment());
+
+        //Anything that is not an extension header ends the chain
+        let bytes = ipv6_packet(PROTOCOL_TCP, &[IPV6_ROUTING, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(Ipv6View::new(&bytes).unwrap().payload().len(), 8);
+    }
+
+    #[test]
+    fn ipv6_fragments() {
+        //Offset 0 with more fragments set
+        let first = [&[PROTOCOL_UDP, 0, 0, 1, 0, 0, 0, 9][..], &UDP].concat();
+        let bytes = ipv6_packet(IPV6_FRAGMENT, &first);
+        let ip = IpView::new(&bytes).unwrap();
+        assert!(ip.is_fragment());
+        assert_eq!(ip.protocol(), PROTOCOL_UDP);
+        assert_eq!(ip.payload(), &UDP);
+
+        //Offset 1, the payload does not start with a UDP header
+        let later = [&[PROTOCOL_UDP, 0, 0, 8, 0, 0, 0, 9][..], b"later"].concat();
+        let bytes = ipv6_packet(IPV6_FRAGMENT, &later);
+        let ip = Ipv6View::new(&bytes).unwrap();
+        assert!(ip.is_fragment());
+        assert_eq!(ip.protocol(), IPV6_FRAGMENT);
+        assert_eq!(ip.payload(), later.as_slice());
+
+        //An atomic fragment is not part of a larger packet
+        let atomic = [&[PROTOCOL_UDP, 0, 0, 0, 0, 0, 0, 9][..], &UDP].concat();
+        let bytes = ipv6_packet(IPV6_FRAGMENT, &atomic);
+        let ip = Ipv6View::new(&bytes).unwrap();
+        assert!(!ip.is_fragment());
+        assert_eq!(ip.protocol(), PROTOCOL_UDP);
+    }
+
+    #[test]
+    fn malformed_ipv6() {
+        let bytes = ipv6_packet(PROTOCOL_UDP, &UDP);
+        assert_eq!(malformed(Ipv6View::new(&bytes[..39])), "IPv6 header is truncated");
+        assert_eq!(
+            malformed(Ipv6View::new(&ipv4_packet(PROTOCOL_UDP, &[0; 20]))),
+            "IP version is not 6"
+        );
+        assert_eq!(
+            malformed(Ipv6View::new(&bytes[..bytes.len() - 1])),
+            "IPv6 packet is shorter than its payload length"
+        );
+
+        let no_length = ipv6_packet(IPV6_HOP_BY_HOP, &[PROTOCOL_UDP]);
+        assert_eq!(
+            malformed(Ipv6View::new(&no_length)),
+            "IPv6 extension header is truncated"
+        );
+        let too_long = ipv6_packet(IPV6_DESTINATION_OPTIONS, &[PROTOCOL_UDP, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            malformed(Ipv6View::new(&too_long)),
+            "IPv6 extension header is truncated"
+        );
+        let fragment = ipv6_packet(IPV6_FRAGMENT, &[PROTOCOL_UDP, 0, 0, 1]);
+        assert_eq!(malformed(Ipv6View::new(&fragment)), "IPv6 fragment header is truncated");
+    }
+
+    #[test]
+    fn malformed_transport_headers() {
+        assert_eq!(malformed(UdpView::new(&UDP[..7])), "UDP header is truncated");
+        assert_eq!(
+            malformed(UdpView::new(&[0, 0, 0, 0, 0, 7, 0, 0])),
+            "UDP length is less than 8 bytes"
+        );
+        assert_eq!(
+            malformed(UdpView::new(&UDP[..9])),
+            "UDP datagram is shorter than its length"
+        );
+
+        let mut tcp = [0; 24];
+        tcp[12] = 0x50;
+        assert_eq!(TcpView::new(&tcp).unwrap().payload().len(), 4);
+        assert_eq!(malformed(TcpView::new(&tcp[..19])), "TCP header is truncated");
+        tcp[12] = 0x40;
+        assert_eq!(malformed(TcpView::new(&tcp)), "TCP data offset is less than 20 bytes");
+        tcp[12] = 0x70;
+        assert_eq!(malformed(TcpView::new(&tcp)), "TCP options are truncated");
+
+        assert_eq!(
+            malformed(IcmpView::new(&[8, 0, 0, 0, 0, 1, 0])),
+            "ICMP header is truncated"
+        );
+    }
+}
@@ -11,8 +11,13 @@
 //! Then either call [`Adapter::create`] or [`Adapter::open`] to obtain a wintun
 //! adapter. Start a session with [`Adapter::start_session`].
 //!
+//! Sessions implement [`TunDevice`], which is also implemented by `LinuxTun` on Linux, so the same
+//! packet loop can drive both platforms.
+//!
 //! # Example
 //! ```no_run
+//! # #[cfg(windows)]
+//! # fn main() {
 //! use std::sync::Arc;
 //!
 //! //Must be run as Administrator because we create network adapters
@@ -58,6 +63,9 @@
 //!
 //! //drop(adapter)
 //! //And the adapter closes its resources when dropped
+//! # }
+//! # #[cfg(not(windows))]
+//! # fn main() {}
 //! ```
 //!    
 //! See `examples/wireshark.rs` for a more complete example that writes received packets to a pcap
@@ -66,23 +74,49 @@
 //! # Features
 //!
 //! - `panic_on_unsent_packets`: Panics if a send packet is dropped without being sent. Useful for
-//! debugging packet issues because unsent packets that are dropped without being sent hold up
-//! wintun's internal ring buffer.
+//!   debugging packet issues because unsent packets that are dropped without being sent hold up
+//!   wintun's internal ring buffer. Sets the default [`UnsentPacketPolicy`], which can also be
+//!   changed at runtime with [`Session::set_unsent_packet_policy`].
+//! - `futures`: [`Session::into_stream`] and [`Session::into_sink`], adapting a session to the
+//!   `Stream` and `Sink` traits of the futures crate.
+//!
+//! # Async
 //!
-//! # TODO:
-//! - Add async support
-//! Requires hooking into a windows specific reactor and registering read interest on wintun's read
-//! handle. Asyncify other slow operations via tokio::spawn_blocking. As always, PR's are welcome!
+//! [`AsyncSession`] wraps a session with `async` receive and send functions. Pending receives are
+//! woken by the system thread pool rather than a runtime specific reactor, so they work with any
+//! executor. Other executors and event loops can build on [`Session::readiness`], which wakes a
+//! [`std::task::Waker`] once packets may be available.
 //!
 
 mod adapter;
+mod api;
+mod async_session;
+mod borrowed;
+mod builder;
+pub mod checksum;
+mod device;
 mod error;
+mod event;
+mod fake;
+pub mod ip;
+#[cfg(target_os = "linux")]
+mod linux;
 mod log;
+mod metrics;
+pub mod nat;
 mod packet;
+mod pool;
+mod readiness;
+mod ring;
 mod session;
+mod stats;
+#[cfg(feature = "futures")]
+mod stream;
+mod timer;
 mod util;
 
 //Generated by bingen
+#[cfg(windows)]
 #[allow(
     non_snake_case,
     dead_code,
@@ -93,13 +127,29 @@ mod util;
 )]
 mod wintun_raw;
 
+#[cfg(target_os = "linux")]
+pub use crate::linux::LinuxTun;
+#[cfg(feature = "futures")]
+pub use crate::stream::{PacketSink, PacketStream};
+#[cfg(windows)]
+pub use crate::util::{format_message, get_active_network_interface_gateways, run_command};
 pub use crate::{
     adapter::Adapter,
+    api::{AdapterHandle, LoggerCallback, LoggerLevel, SessionHandle, WintunApi, LOG_ERR, LOG_INFO, LOG_WARN},
+    async_session::AsyncSession,
+    borrowed::{BorrowedRecvPacket, BorrowedSendPacket},
+    builder::PacketBuilder,
+    device::TunDevice,
     error::{Error, OutOfRangeData, Result},
+    fake::{FakeCall, FakeWintun},
     log::{default_logger, reset_logger, set_logger},
-    packet::Packet,
+    metrics::render_prometheus_metrics,
+    packet::{OwnedPacket, RecvPacket, SendPacket, UnsentPacketPolicy},
+    pool::{BufferPool, PoolStats},
+    readiness::{Readiness, Ready},
+    ring::SessionRings,
     session::Session,
-    util::{format_message, get_active_network_interface_gateways, run_command},
+    stats::SessionStats,
 };
 pub use windows_sys::Win32::{Foundation::HANDLE, NetworkManagement::Ndis::NET_LUID_LH};
 
@@ -111,12 +161,18 @@ pub const MAX_RING_CAPACITY: u32 = 0x400_0000;
 /// The minimum size of wintun's internal ring buffer (in bytes)
 pub const MIN_RING_CAPACITY: u32 = 0x2_0000;
 
+/// The maximum size of a single packet (in bytes)
+pub const MAX_IP_PACKET_SIZE: u32 = 0xFFFF;
+
 /// Maximum pool name length including zero terminator
 pub const MAX_POOL: usize = 256;
 
-pub type Wintun = Arc<wintun_raw::wintun>;
+/// Shared handle to the wintun functions used by adapters and sessions. Usually the function
+/// table loaded from wintun.dll, or a [`FakeWintun`] when testing
+pub type Wintun = Arc<dyn WintunApi>;
 
 use std::sync::Arc;
+use windows_sys::Win32::Foundation::ERROR_FILE_NOT_FOUND;
 
 /// Attempts to load the Wintun library from the current directory using the default name "wintun.dll".
 ///
@@ -132,6 +188,7 @@ use std::sync::Arc;
 /// Hoverer one can never be too cautious when loading a dll file.
 ///
 /// For more information see [`libloading`]'s dynamic library safety guarantees: [`libloading`][`libloading::Library::new`]
+#[cfg(windows)]
 pub unsafe fn load() -> Result<Wintun, Error> {
     load_from_path("wintun")
 }
@@ -148,6 +205,7 @@ pub unsafe fn load() -> Result<Wintun, Error> {
 /// Hoverer one can never be too cautious when loading a dll file.
 ///
 /// For more information see [`libloading`]'s dynamic library safety guarantees: [`libloading`][`libloading::Library::new`]
+#[cfg(windows)]
 pub unsafe fn load_from_path<P>(path: P) -> Result<Wintun, Error>
 where
     P: AsRef<::std::ffi::OsStr>,
@@ -165,6 +223,7 @@ where
 /// is inherently unsafe.
 ///
 /// For more information see [`libloading`]'s dynamic library safety guarantees: [`libloading::Library::new`]
+#[cfg(windows)]
 pub unsafe fn load_from_library<L>(library: L) -> Result<Wintun, Error>
 where
     L: Into<libloading::Library>,
@@ -184,16 +243,22 @@ impl std::fmt::Display for Version {
     }
 }
 
-/// Returns the major and minor version of the wintun driver
+/// Returns the major and minor version of the wintun driver, or Err([`Error::DriverNotLoaded`])
+/// if it is not loaded
 pub fn get_running_driver_version(wintun: &Wintun) -> Result<Version> {
-    let version = unsafe { wintun.WintunGetRunningDriverVersion() };
-    if version == 0 {
-        Err(util::get_last_error()?.into())
-    } else {
-        let v = version.to_be_bytes();
-        Ok(Version {
-            major: u16::from_be_bytes([v[0], v[1]]),
-            minor: u16::from_be_bytes([v[2], v[3]]),
-        })
+    match wintun.get_running_driver_version() {
+        //Wintun reports ERROR_FILE_NOT_FOUND while the driver is not loaded
+        Err(code @ ERROR_FILE_NOT_FOUND) => Err(Error::DriverNotLoaded {
+            operation: "WintunGetRunningDriverVersion",
+            code,
+        }),
+        Err(e) => Err(Error::wintun("WintunGetRunningDriverVersion", e)),
+        Ok(version) => {
+            let v = version.to_be_bytes();
+            Ok(Version {
+                major: u16::from_be_bytes([v[0], v[1]]),
+                minor: u16::from_be_bytes([v[2], v[3]]),
+            })
+        }
     }
 }
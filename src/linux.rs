@@ -0,0 +1,299 @@
+//! A [`TunDevice`] backed by the Linux tun driver.
+//!
+//! Packets are exchanged with the kernel through a /dev/net/tun file descriptor opened with
+//! `IFF_TUN | IFF_NO_PI`, so every read and write is exactly one raw IP packet, the same as a wintun
+//! session.
+
+use crate::{Error, RecvPacket, SendPacket, TunDevice, MAX_IP_PACKET_SIZE};
+use std::{
+    ffi::CStr,
+    io,
+    mem::MaybeUninit,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// A tun interface created through /dev/net/tun
+///
+/// The interface is created down. Bring it up and assign addresses with `ip link` and `ip addr`,
+/// which like on Windows requires administrator rights (CAP_NET_ADMIN)
+pub struct LinuxTun {
+    /// The /dev/net/tun queue attached to the interface, in non blocking mode
+    fd: OwnedFd,
+
+    /// Eventfd that becomes readable once [`LinuxTun::shutdown`] is called
+    shutdown_event: OwnedFd,
+
+    /// Set by [`LinuxTun::shutdown`]
+    shut_down: AtomicBool,
+
+    /// The interface name assigned by the kernel
+    name: String,
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result),
+    }
+}
+
+fn new_ifreq(name: &str) -> Result<libc::ifreq, Error> {
+    //SAFETY: ifreq is plain old data, for which all zeros is a valid value
+    let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
+    if name.len() >= ifreq.ifr_name.len() || name.contains('\0') {
+        return Err(format!("Invalid interface name {:?}", name).into());
+    }
+    for (dst, src) in ifreq.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(ifreq)
+}
+
+impl LinuxTun {
+    /// Creates (or attaches to an existing persistent) tun interface named `name`.
+    /// If `name` is empty the kernel picks a name such as tun0, which is returned by [`LinuxTun::name`]
+    pub fn create(name: &str) -> Result<Self, Error> {
+        let mut ifreq = new_ifreq(name)?;
+        ifreq.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+
+        let path = c"/dev/net/tun";
+        //SAFETY: path is a valid null terminated string and the returned descriptor is owned by us
+        let fd = unsafe {
+            let fd = check(libc::open(
+                path.as_ptr(),
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            ))?;
+            OwnedFd::from_raw_fd(fd)
+        };
+        //SAFETY: TUNSETIFF reads and writes a single ifreq, which lives for the duration of the call
+        check(unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF as _, &mut ifreq as *mut libc::ifreq) })?;
+
+        //SAFETY: eventfd has no memory safety requirements and the returned descriptor is owned by us
+        let shutdown_event = unsafe {
+            let fd = check(libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK))?;
+            OwnedFd::from_raw_fd(fd)
+        };
+
+        //SAFETY: The kernel writes back the null terminated name of the interface
+        let name = unsafe { CStr::from_ptr(ifreq.ifr_name.as_ptr()) };
+        Ok(Self {
+            fd,
+            shutdown_event,
+            shut_down: AtomicBool::new(false),
+            name: name.to_str()?.to_owned(),
+        })
+    }
+
+    /// Returns the name of the interface
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the raw /dev/net/tun file descriptor, which is in non blocking mode
+    pub fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.fd.as_raw_fd()
+    }
+
+    /// Attempts to receive a packet without blocking, returning Ok(None) if none is queued.
+    /// Returns Err([`Error::ShuttingDown`]) after [`LinuxTun::shutdown`] is called
+    pub fn try_receive(&self) -> Result<Option<RecvPacket>, Error> {
+        if self.is_shut_down() {
+            return Err(Error::ShuttingDown);
+        }
+        //Read into uninitialized stack memory, so that only the received bytes are allocated
+        let mut buf = [MaybeUninit::<u8>::uninit(); MAX_IP_PACKET_SIZE as usize];
+        loop {
+            //SAFETY: buf is valid for writes of buf.len() bytes
+            let read = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if read >= 0 {
+                //SAFETY: read initialized the first `read` bytes of buf
+                let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr().cast::<u8>(), read as usize) };
+                return Ok(Some(RecvPacket::from_heap(bytes.into())));
+            }
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock => return Ok(None),
+                io::ErrorKind::Interrupted => continue,
+                _ => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Blocks until a packet is available, or until [`LinuxTun::shutdown`] is called in which case
+    /// Err([`Error::ShuttingDown`]) is returned
+    pub fn receive_blocking(&self) -> Result<RecvPacket, Error> {
+        match self.receive_until(None)? {
+            Some(packet) => Ok(packet),
+            None => unreachable!("An infinite poll cannot time out"),
+        }
+    }
+
+    /// Like [`LinuxTun::receive_blocking`], but gives up and returns Ok(None) if no packet arrives
+    /// within `timeout`
+    pub fn receive_timeout(&self, timeout: Duration) -> Result<Option<RecvPacket>, Error> {
+        self.receive_until(Instant::now().checked_add(timeout))
+    }
+
+    /// Like [`LinuxTun::receive_blocking`], but gives up and returns Ok(None) if no packet arrives
+    /// before `deadline`
+    pub fn receive_deadline(&self, deadline: Instant) -> Result<Option<RecvPacket>, Error> {
+        self.receive_until(Some(deadline))
+    }
+
+    fn receive_until(&self, deadline: Option<Instant>) -> Result<Option<RecvPacket>, Error> {
+        loop {
+            if let Some(packet) = self.try_receive()? {
+                return Ok(Some(packet));
+            }
+            //Round up so that we never wake before the deadline
+            let timeout = match deadline {
+                None => -1,
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    remaining.as_nanos().div_ceil(1_000_000).min(libc::c_int::MAX as u128) as libc::c_int
+                }
+            };
+            let mut fds = [
+                libc::pollfd {
+                    fd: self.fd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: self.shutdown_event.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            //SAFETY: fds is valid for reads and writes of fds.len() entries
+            match check(unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) }) {
+                //Polls are capped at about 24 days, so a later deadline may not have passed yet
+                Ok(0) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => return Ok(None),
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+            if fds[1].revents != 0 {
+                return Err(Error::ShuttingDown);
+            }
+        }
+    }
+
+    /// Allocates a zeroed packet of `size` bytes to be filled in and sent with [`LinuxTun::send_packet`].
+    /// Returns Err([`Error::ShuttingDown`]) after [`LinuxTun::shutdown`] is called
+    pub fn allocate_send_packet(&self, size: u16) -> Result<SendPacket, Error> {
+        if self.is_shut_down() {
+            return Err(Error::ShuttingDown);
+        }
+        let bytes = vec![0u8; size as usize].into_boxed_slice();
+        Ok(SendPacket::from_heap(bytes))
+    }
+
+    /// Writes a packet previously allocated with [`LinuxTun::allocate_send_packet`] to the interface.
+    /// Packets allocated by a wintun session are returned in Err([`Error::ForeignPacket`]), so that
+    /// they can still be sent through their session
+    pub fn send_packet(&self, packet: SendPacket) -> Result<(), Error> {
+        if !packet.is_heap() {
+            return Err(Error::ForeignPacket(packet));
+        }
+        let data = packet.into_data();
+        loop {
+            let bytes = &*data.bytes;
+            //SAFETY: bytes is valid for reads of bytes.len() bytes
+            let written = unsafe { libc::write(self.fd.as_raw_fd(), bytes.as_ptr().cast(), bytes.len()) };
+            if written >= 0 {
+                return match written as usize == bytes.len() {
+                    true => Ok(()),
+                    false => Err(format!("Short write of {} out of {} bytes", written, bytes.len()).into()),
+                };
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err.into());
+            }
+        }
+    }
+
+    /// Cancels any active and future calls to [`LinuxTun::receive_blocking`], making them return
+    /// Err([`Error::ShuttingDown`])
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.shut_down.store(true, Ordering::SeqCst);
+        let value = 1u64;
+        //SAFETY: value is valid for reads of 8 bytes, the size eventfd requires
+        let written = unsafe {
+            libc::write(
+                self.shutdown_event.as_raw_fd(),
+                &value as *const u64 as *const libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        match written {
+            -1 => Err(io::Error::last_os_error().into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns true once [`LinuxTun::shutdown`] has been called
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Returns the MTU of the interface. Maps to SIOCGIFMTU
+    pub fn mtu(&self) -> Result<usize, Error> {
+        let mut ifreq = new_ifreq(&self.name)?;
+        //SAFETY: socket has no memory safety requirements and the returned descriptor is owned by us
+        let socket = unsafe {
+            let fd = check(libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0))?;
+            OwnedFd::from_raw_fd(fd)
+        };
+        //SAFETY: SIOCGIFMTU reads and writes a single ifreq, which lives for the duration of the call
+        check(unsafe {
+            libc::ioctl(
+                socket.as_raw_fd(),
+                libc::SIOCGIFMTU as _,
+                &mut ifreq as *mut libc::ifreq,
+            )
+        })?;
+        //SAFETY: SIOCGIFMTU fills in the mtu member of the union
+        Ok(unsafe { ifreq.ifr_ifru.ifru_mtu } as usize)
+    }
+}
+
+impl TunDevice for LinuxTun {
+    fn try_receive(self: &Arc<Self>) -> Result<Option<RecvPacket>, Error> {
+        LinuxTun::try_receive(self)
+    }
+
+    fn receive_blocking(self: &Arc<Self>) -> Result<RecvPacket, Error> {
+        LinuxTun::receive_blocking(self)
+    }
+
+    fn receive_deadline(self: &Arc<Self>, deadline: Instant) -> Result<Option<RecvPacket>, Error> {
+        LinuxTun::receive_deadline(self, deadline)
+    }
+
+    fn allocate_send_packet(self: &Arc<Self>, size: u16) -> Result<SendPacket, Error> {
+        LinuxTun::allocate_send_packet(self, size)
+    }
+
+    fn send_packet(&self, packet: SendPacket) -> Result<(), Error> {
+        LinuxTun::send_packet(self, packet)
+    }
+
+    fn shutdown(&self) -> Result<(), Error> {
+        LinuxTun::shutdown(self)
+    }
+
+    fn is_shut_down(&self) -> bool {
+        LinuxTun::is_shut_down(self)
+    }
+
+    fn mtu(&self) -> Result<usize, Error> {
+        LinuxTun::mtu(self)
+    }
+}
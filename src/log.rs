@@ -1,9 +1,12 @@
-use crate::{util, wintun_raw, Wintun};
+use crate::{
+    api::{LoggerCallback, LoggerLevel, LOG_ERR, LOG_INFO, LOG_WARN},
+    util, Wintun,
+};
 use std::sync::atomic::{AtomicBool, Ordering};
 
 /// Sets the logger wintun will use when logging. Maps to the WintunSetLogger C function
-pub fn set_logger(wintun: &Wintun, f: wintun_raw::WINTUN_LOGGER_CALLBACK) {
-    unsafe { wintun.WintunSetLogger(f) };
+pub fn set_logger(wintun: &Wintun, f: LoggerCallback) {
+    wintun.set_logger(f);
 }
 
 pub fn reset_logger(wintun: &Wintun) {
@@ -16,17 +19,13 @@ static SET_LOGGER: AtomicBool = AtomicBool::new(false);
 ///
 /// # Safety
 /// `message` must be a valid pointer that points to an aligned null terminated UTF-16 string
-pub unsafe extern "stdcall" fn default_logger(
-    level: wintun_raw::WINTUN_LOGGER_LEVEL,
-    _timestamp: wintun_raw::DWORD64,
-    message: *const wintun_raw::WCHAR,
-) {
+pub unsafe extern "system" fn default_logger(level: LoggerLevel, _timestamp: u64, message: *const u16) {
     //Wintun will always give us a valid UTF16 null termineted string
     let utf8_msg = util::win_pwstr_to_string(message as *mut u16).unwrap_or_else(|e| e.to_string());
     match level {
-        wintun_raw::WINTUN_LOGGER_LEVEL_WINTUN_LOG_INFO => log::info!("WinTun: {}", utf8_msg),
-        wintun_raw::WINTUN_LOGGER_LEVEL_WINTUN_LOG_WARN => log::warn!("WinTun: {}", utf8_msg),
-        wintun_raw::WINTUN_LOGGER_LEVEL_WINTUN_LOG_ERR => log::error!("WinTun: {}", utf8_msg),
+        LOG_INFO => log::info!("WinTun: {}", utf8_msg),
+        LOG_WARN => log::warn!("WinTun: {}", utf8_msg),
+        LOG_ERR => log::error!("WinTun: {}", utf8_msg),
         _ => log::debug!("WinTun: {} (with invalid log level {})", utf8_msg, level),
     }
 }
@@ -0,0 +1,349 @@
+//! Prometheus text exposition of the counters of every live [`crate::Session`].
+//!
+//! Sessions register their counters with a process wide registry when they are started. When a
+//! session is dropped its counters are folded into the totals of its adapter, so that exported
+//! counters never go down while any handle to the adapter lives. [`render_prometheus_metrics`] turns the
+//! registry into text that can be served as is from an existing metrics endpoint.
+
+use crate::{stats::Counters, util, Adapter, SessionStats};
+use std::{
+    fmt::{self, Write},
+    sync::{Arc, Mutex, Weak},
+};
+
+/// The sessions started on one adapter, kept without holding up either them or the adapter
+struct Registered {
+    guid: u128,
+
+    /// Every handle to the adapter that has started a session. Handles are only merged when the
+    /// GUID is known, adapters opened on other platforms all have a GUID of 0
+    adapters: Vec<Weak<Adapter>>,
+
+    /// The counters of live sessions
+    sessions: Vec<Weak<Counters>>,
+
+    /// The value of each of [`SESSION_METRICS`] summed over the sessions that were dropped
+    retired: [u64; METRIC_COUNT],
+}
+
+static ADAPTERS: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+/// Reads one counter out of a [`SessionStats`]
+type Field = fn(&SessionStats) -> u64;
+
+/// Session counters that are exported, as metric name, help text and the field they read
+const SESSION_METRICS: &[(&str, &str, Field)] = &[
+    (
+        "wintun_session_received_packets_total",
+        "Packets taken out of the receive ring",
+        |s| s.packets_received,
+    ),
+    (
+        "wintun_session_received_bytes_total",
+        "Bytes of the packets taken out of the receive ring",
+        |s| s.bytes_received,
+    ),
+    (
+        "wintun_session_sent_packets_total",
+        "Packets submitted to the send ring",
+        |s| s.packets_sent,
+    ),
+    (
+        "wintun_session_sent_bytes_total",
+        "Bytes of the packets submitted to the send ring",
+        |s| s.bytes_sent,
+    ),
+    (
+        "wintun_session_allocation_failures_total",
+        "Send packet allocations that failed",
+        |s| s.allocation_failures,
+    ),
+    (
+        "wintun_session_ring_full_total",
+        "Send packet allocations that failed because the send ring was full",
+        |s| s.ring_full,
+    ),
+    (
+        "wintun_session_blocked_waits_total",
+        "Times a blocking receive waited for the read event, plus blocking allocations that waited for the send ring",
+        |s| s.blocked_waits,
+    ),
+    (
+        "wintun_session_shutdown_wakeups_total",
+        "Times a blocking receive or allocation was woken by a shutdown",
+        |s| s.shutdown_wakeups,
+    ),
+    (
+        "wintun_session_oversized_packets_total",
+        "Packets rejected for being too large",
+        |s| s.oversized_packets,
+    ),
+    (
+        "wintun_session_unsent_packets_dropped_total",
+        "Send packets dropped without being sent",
+        |s| s.unsent_packets_dropped,
+    ),
+];
+
+const METRIC_COUNT: usize = SESSION_METRICS.len();
+
+/// What gets exported about one adapter
+struct AdapterMetrics {
+    /// `adapter="...",guid="..."`
+    labels: String,
+    luid: u64,
+    mtu: Option<usize>,
+    /// The value of each of [`SESSION_METRICS`]
+    values: [u64; METRIC_COUNT],
+}
+
+/// Adds the counters of a newly started session to the registry
+pub(crate) fn register(counters: &Arc<Counters>, adapter: &Arc<Adapter>) {
+    let mut adapters = lock();
+    prune(&mut adapters);
+    let guid = adapter.get_guid();
+    let index = match adapters.iter().position(|registered| registered.matches(adapter)) {
+        Some(index) => index,
+        None => {
+            adapters.push(Registered {
+                guid,
+                adapters: Vec::new(),
+                sessions: Vec::new(),
+                retired: [0; METRIC_COUNT],
+            });
+            adapters.len() - 1
+        }
+    };
+    let registered = &mut adapters[index];
+    if !registered.holds(adapter) {
+        registered.adapters.push(Arc::downgrade(adapter));
+    }
+    registered.sessions.push(Arc::downgrade(counters));
+}
+
+/// Folds the counters of a session that is being dropped into the totals of its adapter
+pub(crate) fn retire(counters: &Arc<Counters>, adapter: &Adapter) {
+    let mut adapters = lock();
+    let Some(registered) = adapters.iter_mut().find(|registered| registered.holds(adapter)) else {
+        return;
+    };
+    let stats = counters.snapshot();
+    registered
+        .sessions
+        .retain(|session| session.as_ptr() != Arc::as_ptr(counters));
+    for (total, (_, _, field)) in registered.retired.iter_mut().zip(SESSION_METRICS) {
+        *total += field(&stats);
+    }
+}
+
+impl Registered {
+    /// Returns true if `adapter` is one of the handles of this entry
+    fn holds(&self, adapter: &Adapter) -> bool {
+        self.adapters.iter().any(|held| std::ptr::eq(held.as_ptr(), adapter))
+    }
+
+    /// Returns true if sessions of `adapter` belong in this entry
+    fn matches(&self, adapter: &Adapter) -> bool {
+        self.holds(adapter) || (self.guid != 0 && self.guid == adapter.get_guid())
+    }
+}
+
+/// Drops adapters that are gone along with all of their sessions
+fn prune(adapters: &mut Vec<Registered>) {
+    for registered in adapters.iter_mut() {
+        registered.sessions.retain(|session| session.strong_count() > 0);
+        registered.adapters.retain(|adapter| adapter.strong_count() > 0);
+    }
+    adapters.retain(|registered| !registered.adapters.is_empty() || !registered.sessions.is_empty());
+}
+
+/// Renders the counters of the sessions of every live adapter, along with the attributes of the
+/// adapters, in the Prometheus text exposition format (`text/plain; version=0.0.4`)
+///
+/// Samples are labeled with the adapter's friendly name and GUID. Counters of several sessions on
+/// the same adapter are summed, including sessions that were dropped, so they only go up until
+/// every handle to the adapter is dropped. The MTU is only exported on Windows
+///
+/// # Example
+/// ```
+/// let (a, _b) = wintun::Session::pair().unwrap();
+/// a.send_batch(&[&[0x45, 0, 0, 4]]).unwrap();
+///
+/// let metrics = wintun::render_prometheus_metrics();
+/// assert!(metrics.lines().any(|line| {
+///     line.starts_with("wintun_session_sent_packets_total{adapter=\"Pair A\"") && line.ends_with(" 1")
+/// }));
+/// ```
+pub fn render_prometheus_metrics() -> String {
+    let mut out = String::new();
+    write_metrics(&mut out, &collect()).expect("Writing to a String cannot fail");
+    out
+}
+
+/// Snapshots the registered sessions, grouped by adapter
+fn collect() -> Vec<AdapterMetrics> {
+    //Looking up names and MTUs can take a while, so do it outside of the lock
+    let live: Vec<_> = {
+        let mut adapters = lock();
+        prune(&mut adapters);
+        adapters
+            .iter()
+            .filter_map(|registered| {
+                let mut values = registered.retired;
+                for counters in registered.sessions.iter().filter_map(Weak::upgrade) {
+                    let stats = counters.snapshot();
+                    for (value, (_, _, field)) in values.iter_mut().zip(SESSION_METRICS) {
+                        *value += field(&stats);
+                    }
+                }
+                Some((registered.adapters.iter().find_map(Weak::upgrade)?, values))
+            })
+            .collect()
+    };
+
+    live.into_iter()
+        .map(|(adapter, values)| {
+            let guid = adapter.get_guid();
+            AdapterMetrics {
+                labels: format!(
+                    "adapter=\"{}\",guid=\"{}\"",
+                    escape_label(&adapter.display_name()),
+                    util::guid_to_string(guid)
+                ),
+                //SAFETY: Every bit pattern of the union is a valid u64
+                luid: unsafe { adapter.get_luid().Value },
+                mtu: mtu(&adapter),
+                values,
+            }
+        })
+        .collect()
+}
+
+fn write_metrics(out: &mut String, adapters: &[AdapterMetrics]) -> fmt::Result {
+    writeln!(
+        out,
+        "# HELP wintun_adapter_info Wintun adapter that has started a session"
+    )?;
+    writeln!(out, "# TYPE wintun_adapter_info gauge")?;
+    for adapter in adapters {
+        writeln!(
+            out,
+            "wintun_adapter_info{{{},luid=\"{}\"}} 1",
+            adapter.labels, adapter.luid
+        )?;
+    }
+
+    if adapters.iter().any(|adapter| adapter.mtu.is_some()) {
+        writeln!(out, "# HELP wintun_adapter_mtu_bytes MTU of the adapter")?;
+        writeln!(out, "# TYPE wintun_adapter_mtu_bytes gauge")?;
+        for adapter in adapters {
+            if let Some(mtu) = adapter.mtu {
+                writeln!(out, "wintun_adapter_mtu_bytes{{{}}} {}", adapter.labels, mtu)?;
+            }
+        }
+    }
+
+    for (index, (name, help, _)) in SESSION_METRICS.iter().enumerate() {
+        writeln!(out, "# HELP {} {}", name, help)?;
+        writeln!(out, "# TYPE {} counter", name)?;
+        for adapter in adapters {
+            writeln!(out, "{}{{{}}} {}", name, adapter.labels, adapter.values[index])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn mtu(adapter: &Adapter) -> Option<usize> {
+    adapter.get_mtu().ok()
+}
+
+#[cfg(not(windows))]
+fn mtu(_adapter: &Adapter) -> Option<usize> {
+    None
+}
+
+/// Escapes `value` for use inside a quoted label value
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Vec<Registered>> {
+    //Every update leaves the registry consistent, so ignore poisoning
+    ADAPTERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Adapter, FakeWintun, Wintun};
+    use std::sync::Arc;
+
+    fn sent_packets(adapter: &str) -> Option<u64> {
+        let prefix = format!("wintun_session_sent_packets_total{{adapter=\"{}\"", adapter);
+        let metrics = super::render_prometheus_metrics();
+        let line = metrics.lines().find(|line| line.starts_with(&prefix))?;
+        Some(line.rsplit(' ').next()?.parse().unwrap())
+    }
+
+    #[test]
+    fn counters_survive_dropped_sessions() {
+        let wintun: Wintun = Arc::new(FakeWintun::new());
+        let adapter = Adapter::create(&wintun, "Metrics retire", "Test", None).unwrap();
+
+        for sent in 1..=3 {
+            let session = adapter.start_session(crate::MIN_RING_CAPACITY).unwrap();
+            session.send_batch(&[&[0x45, 0, 0, 4]]).unwrap();
+            assert_eq!(sent_packets("Metrics retire"), Some(sent));
+            drop(session);
+            assert_eq!(sent_packets("Metrics retire"), Some(sent));
+        }
+
+        drop(adapter);
+        assert_eq!(sent_packets("Metrics retire"), None);
+    }
+
+    #[test]
+    fn handles_with_the_same_guid_share_counters() {
+        let wintun: Wintun = Arc::new(FakeWintun::new());
+        let guid = Some(0x5f0b_6c1e_8a1d_4e55_9d0a_7c4e_3b2a_1f10);
+        let first = Adapter::create(&wintun, "Metrics shared", "Test", guid).unwrap();
+        let second = Adapter::create(&wintun, "Metrics shared", "Test", guid).unwrap();
+
+        for adapter in [&first, &second] {
+            let session = adapter.start_session(crate::MIN_RING_CAPACITY).unwrap();
+            session.send_batch(&[&[0x45, 0, 0, 4]]).unwrap();
+        }
+        assert_eq!(sent_packets("Metrics shared"), Some(2));
+
+        //Dropping the handle that registered last keeps the totals
+        drop(second);
+        assert_eq!(sent_packets("Metrics shared"), Some(2));
+        drop(first);
+        assert_eq!(sent_packets("Metrics shared"), None);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn adapters_without_a_guid_are_kept_apart() {
+        let wintun: Wintun = Arc::new(FakeWintun::new());
+        let _created = [
+            Adapter::create(&wintun, "Metrics open A", "Test", None).unwrap(),
+            Adapter::create(&wintun, "Metrics open B", "Test", None).unwrap(),
+        ];
+        let a = Adapter::open(&wintun, "Metrics open A").unwrap();
+        let b = Adapter::open(&wintun, "Metrics open B").unwrap();
+        assert_eq!((a.get_guid(), b.get_guid()), (0, 0));
+
+        let session = a.start_session(crate::MIN_RING_CAPACITY).unwrap();
+        session.send_batch(&[&[0x45, 0, 0, 4]]).unwrap();
+        let session = b.start_session(crate::MIN_RING_CAPACITY).unwrap();
+        session.send_batch(&[&[0x45, 0, 0, 4], &[0x45, 0, 0, 4]]).unwrap();
+        assert_eq!(sent_packets("Metrics open A"), Some(1));
+        assert_eq!(sent_packets("Metrics open B"), Some(2));
+
+        drop(session);
+        drop(b);
+        assert_eq!(sent_packets("Metrics open A"), Some(1));
+        assert_eq!(sent_packets("Metrics open B"), None);
+    }
+}
@@ -0,0 +1,1347 @@
+//! Rewriting the addresses and ports of IP packets, for network address translation.
+//!
+//! Every rewrite updates the IPv4 header checksum and the UDP, TCP, ICMP or ICMPv6 checksum
+//! incrementally, so checksums that were correct stay correct without reading the payload. ICMP
+//! error messages quote the start of the packet that caused them, which travelled in the opposite
+//! direction, and the quoted addresses, ports and checksums are translated along with the message.
+//!
+//! Received packets are read only, so rewrite the bytes of a [`crate::SendPacket`] or of an
+//! [`crate::OwnedPacket`] copied with [`crate::RecvPacket::into_owned`].
+//!
+//! [`NatTable`] builds on these rewrites to share one address between the hosts of a network,
+//! tracking which inner host each outer port belongs to.
+//!
+//! # Example
+//! ```
+//! use wintun::{checksum, ip::UdpView, nat, PacketBuilder};
+//!
+//! let mut packet = vec![0; 31];
+//! PacketBuilder::ipv4([192, 168, 1, 20].into(), [1, 1, 1, 1].into())
+//!     .udp(5000, 53)
+//!     .payload(b"hey")
+//!     .write(&mut packet);
+//!
+//! nat::rewrite_src(&mut packet, [10, 8, 0, 2].into()).unwrap();
+//! nat::rewrite_ports(&mut packet, Some(40000), None).unwrap();
+//!
+//! let ip = wintun::ip::Ipv4View::new(&packet).unwrap();
+//! assert_eq!(ip.source(), std::net::Ipv4Addr::new(10, 8, 0, 2));
+//! assert_eq!(UdpView::new(ip.payload()).unwrap().source_port(), 40000);
+//! assert!(checksum::verify(&packet).unwrap());
+//! ```
+
+use crate::{
+    checksum,
+    ip::{self, IpView, PROTOCOL_ICMP, PROTOCOL_ICMPV6, PROTOCOL_TCP, PROTOCOL_UDP},
+    BufferPool, Error, OwnedPacket, RecvPacket,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// A checksum field of a packet
+#[derive(Copy, Clone)]
+struct Sum {
+    offset: usize,
+
+    /// UDP checksums of zero mean there is none, and must be sent as 0xFFFF when they come out as
+    /// zero
+    udp: bool,
+}
+
+impl Sum {
+    fn plain(offset: usize) -> Self {
+        Self { offset, udp: false }
+    }
+
+    /// Adjusts this checksum for `old` being replaced with `new`, returning its old and new bytes
+    fn adjust(self, packet: &mut [u8], old: &[u8], new: &[u8]) -> ([u8; 2], [u8; 2]) {
+        let before = [packet[self.offset], packet[self.offset + 1]];
+        let current = u16::from_be_bytes(before);
+        if self.udp && current == 0 {
+            return (before, before);
+        }
+        let updated = match checksum::adjust(current, old, new) {
+            0 if self.udp => 0xFFFF,
+            updated => updated,
+        };
+        packet[self.offset..self.offset + 2].copy_from_slice(&updated.to_be_bytes());
+        (before, updated.to_be_bytes())
+    }
+}
+
+/// Where the parts of an IP packet are
+struct Layout {
+    v4: bool,
+
+    /// Offset of the transport header
+    transport: usize,
+
+    /// Length of the packet without padding
+    end: usize,
+
+    protocol: u8,
+
+    /// Unset for fragments other than the first, which do not start with the transport header
+    has_transport: bool,
+}
+
+impl Layout {
+    fn new(packet: &[u8]) -> Result<Self, Error> {
+        let ip = IpView::new(packet)?;
+        let layout = Self {
+            v4: matches!(ip, IpView::V4(_)),
+            transport: ip.bytes().len() - ip.payload().len(),
+            end: ip.bytes().len(),
+            protocol: ip.protocol(),
+            //Later IPv6 fragments stop at the fragment header
+            has_transport: match ip {
+                IpView::V4(ipv4) => ipv4.fragment_offset() == 0,
+                IpView::V6(ipv6) => ipv6.protocol() != ip::IPV6_FRAGMENT,
+            },
+        };
+        if layout.has_transport {
+            match (layout.protocol, layout.v4) {
+                //The UDP length covers the whole datagram, which a first fragment only starts
+                (PROTOCOL_UDP, _) if ip.is_fragment() && ip.payload().len() < ip::UDP_HEADER_LEN => {
+                    return Err(Error::MalformedPacket("UDP header is truncated"));
+                }
+                (PROTOCOL_UDP, _) if ip.is_fragment() => {}
+                (PROTOCOL_UDP, _) => ip::UdpView::new(ip.payload()).map(drop)?,
+                (PROTOCOL_TCP, _) => ip::TcpView::new(ip.payload()).map(drop)?,
+                (PROTOCOL_ICMP, true) | (PROTOCOL_ICMPV6, false) => ip::IcmpView::new(ip.payload()).map(drop)?,
+                _ => {}
+            }
+        }
+        Ok(layout)
+    }
+
+    /// Returns true if the payload is an ICMP or ICMPv6 message of the packet's IP version
+    fn is_icmp(&self) -> bool {
+        self.has_transport
+            && matches!(
+                (self.protocol, self.v4),
+                (PROTOCOL_ICMP, true) | (PROTOCOL_ICMPV6, false)
+            )
+    }
+}
+
+/// Where the parts of the packet quoted by an ICMP error message are. The quote may be cut off
+/// anywhere after the IP header
+struct Quote {
+    start: usize,
+    v4: bool,
+    transport: usize,
+    end: usize,
+    protocol: u8,
+}
+
+impl Quote {
+    /// Returns the quoted packet if `packet` is an ICMP error message quoting a packet of the same
+    /// IP version
+    fn new(packet: &[u8], layout: &Layout) -> Option<Self> {
+        if !layout.is_icmp() {
+            return None;
+        }
+        let is_error = match layout.v4 {
+            true => matches!(packet[layout.transport], 3 | 4 | 5 | 11 | 12),
+            false => packet[layout.transport] < 128,
+        };
+        if !is_error {
+            return None;
+        }
+        let start = layout.transport + ip::ICMP_HEADER_LEN;
+        let quoted = packet.get(start..layout.end)?;
+        let (header_len, protocol) = match (layout.v4, quoted.first()? >> 4) {
+            (true, 4) if quoted.len() >= ip::IPV4_HEADER_LEN => ((quoted[0] & 0x0F) as usize * 4, quoted[9]),
+            (false, 6) if quoted.len() >= ip::IPV6_HEADER_LEN => (ip::IPV6_HEADER_LEN, quoted[6]),
+            _ => return None,
+        };
+        if header_len < ip::IPV4_HEADER_LEN {
+            return None;
+        }
+        Some(Self {
+            start,
+            v4: layout.v4,
+            transport: start + header_len,
+            end: layout.end,
+            protocol,
+        })
+    }
+
+    /// Returns the checksum of the quoted transport header if it was quoted
+    fn transport_sum(&self) -> Option<Sum> {
+        let sum = transport_sum(self.protocol, self.v4, self.transport)?;
+        (sum.offset + 2 <= self.end).then_some(sum)
+    }
+}
+
+/// Returns the checksum of the transport header of `protocol` at `transport`, if it has one
+fn transport_sum(protocol: u8, v4: bool, transport: usize) -> Option<Sum> {
+    match (protocol, v4) {
+        (PROTOCOL_UDP, _) => Some(Sum {
+            offset: transport + 6,
+            udp: true,
+        }),
+        (PROTOCOL_TCP, _) => Some(Sum::plain(transport + 16)),
+        (PROTOCOL_ICMP, true) | (PROTOCOL_ICMPV6, false) => Some(Sum::plain(transport + 2)),
+        _ => None,
+    }
+}
+
+/// Returns true if the checksum of `protocol` covers the addresses of the IP header
+fn covers_addresses(protocol: u8) -> bool {
+    protocol != PROTOCOL_ICMP
+}
+
+/// Returns the offset of the source or destination address in an IP header
+fn address_offset(v4: bool, source: bool) -> usize {
+    match (v4, source) {
+        (true, true) => 12,
+        (true, false) => 16,
+        (false, true) => 8,
+        (false, false) => 24,
+    }
+}
+
+/// Replaces the bytes at `offset` with `new`, adjusting the checksums `sums` that cover them.
+/// `outer` is the checksum of an ICMP error message covering both the bytes and `sums`
+fn replace(packet: &mut [u8], offset: usize, new: &[u8], sums: &[Option<Sum>], outer: Option<Sum>) {
+    let mut old = [0; 16];
+    let old = &mut old[..new.len()];
+    old.copy_from_slice(&packet[offset..offset + new.len()]);
+    for sum in sums.iter().flatten() {
+        let (before, after) = sum.adjust(packet, old, new);
+        if let Some(outer) = outer {
+            outer.adjust(packet, &before, &after);
+        }
+    }
+    if let Some(outer) = outer {
+        outer.adjust(packet, old, new);
+    }
+    packet[offset..offset + new.len()].copy_from_slice(new);
+}
+
+/// Rewrites the source address of the IP packet `packet` to `address`, which must be of the same
+/// IP version
+///
+/// The destination of the packet quoted by an ICMP error message is rewritten too if it matched
+/// the old source
+pub fn rewrite_src(packet: &mut [u8], address: IpAddr) -> Result<(), Error> {
+    rewrite_address(packet, address, true)
+}
+
+/// Rewrites the destination address of the IP packet `packet` to `address`, which must be of the
+/// same IP version
+///
+/// The source of the packet quoted by an ICMP error message is rewritten too if it matched the old
+/// destination
+pub fn rewrite_dst(packet: &mut [u8], address: IpAddr) -> Result<(), Error> {
+    rewrite_address(packet, address, false)
+}
+
+fn rewrite_address(packet: &mut [u8], address: IpAddr, source: bool) -> Result<(), Error> {
+    let layout = Layout::new(packet)?;
+    let mut buffer = [0; 16];
+    let octets = match address {
+        IpAddr::V4(address) if layout.v4 => {
+            buffer[..4].copy_from_slice(&address.octets());
+            &buffer[..4]
+        }
+        IpAddr::V6(address) if !layout.v4 => {
+            buffer.copy_from_slice(&address.octets());
+            &buffer[..]
+        }
+        _ => return Err(Error::UnsupportedPacket("address is not of the packet's IP version")),
+    };
+    let offset = address_offset(layout.v4, source);
+    let mut old = [0; 16];
+    let old = &mut old[..octets.len()];
+    old.copy_from_slice(&packet[offset..offset + octets.len()]);
+
+    let header_sum = layout.v4.then(|| Sum::plain(10));
+    let transport_sum = transport_sum(layout.protocol, layout.v4, layout.transport)
+        .filter(|_| layout.has_transport && covers_addresses(layout.protocol));
+    replace(packet, offset, octets, &[header_sum, transport_sum], None);
+
+    if let Some(quote) = Quote::new(packet, &layout) {
+        let inner = quote.start + address_offset(quote.v4, !source);
+        if packet.get(inner..inner + old.len()) == Some(old) {
+            let header_sum = quote.v4.then(|| Sum::plain(quote.start + 10));
+            let transport_sum = quote.transport_sum().filter(|_| covers_addresses(quote.protocol));
+            let outer = Sum::plain(layout.transport + 2);
+            replace(packet, inner, octets, &[header_sum, transport_sum], Some(outer));
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the ports of the UDP or TCP packet `packet`. Ports that are `None` are left as is
+///
+/// For ICMP and ICMPv6 echo messages the identifier counts as the source port of requests and the
+/// destination port of replies. ICMP error messages have the ports of the packet they quote, seen
+/// from the direction of the error: `source` rewrites the quoted destination port and
+/// `destination` the quoted source port
+pub fn rewrite_ports(packet: &mut [u8], source: Option<u16>, destination: Option<u16>) -> Result<(), Error> {
+    let layout = Layout::new(packet)?;
+    if !layout.has_transport {
+        return Err(Error::UnsupportedPacket(
+            "fragment does not start with the transport header",
+        ));
+    }
+    let transport = layout.transport;
+    let sum = transport_sum(layout.protocol, layout.v4, transport);
+
+    if let PROTOCOL_UDP | PROTOCOL_TCP = layout.protocol {
+        rewrite_port(packet, layout.end, transport, source, sum, None);
+        rewrite_port(packet, layout.end, transport + 2, destination, sum, None);
+        return Ok(());
+    }
+    if !layout.is_icmp() {
+        return Err(Error::UnsupportedPacket("packet has no ports"));
+    }
+    if let Some(identifier) = echo_identifier(packet[transport], source, destination) {
+        rewrite_port(packet, layout.end, transport + 4, identifier, sum, None);
+        return Ok(());
+    }
+    let Some(quote) = Quote::new(packet, &layout) else {
+        return Err(Error::UnsupportedPacket("ICMP message has no ports"));
+    };
+
+    //The quoted packet travelled the other way
+    let (source, destination) = (destination, source);
+    let inner_sum = quote.transport_sum();
+    match quote.protocol {
+        PROTOCOL_UDP | PROTOCOL_TCP => {
+            rewrite_port(packet, quote.end, quote.transport, source, inner_sum, sum);
+            rewrite_port(packet, quote.end, quote.transport + 2, destination, inner_sum, sum);
+        }
+        //The checksum being quoted means the type was too
+        PROTOCOL_ICMP | PROTOCOL_ICMPV6 if inner_sum.is_some() => {
+            if let Some(identifier) = echo_identifier(packet[quote.transport], source, destination) {
+                rewrite_port(packet, quote.end, quote.transport + 4, identifier, inner_sum, sum);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Returns which of the ports replaces the identifier of an ICMP or ICMPv6 message of type
+/// `icmp_type`, if it is an echo message
+fn echo_identifier(icmp_type: u8, source: Option<u16>, destination: Option<u16>) -> Option<Option<u16>> {
+    match icmp_type {
+        ICMP_ECHO_REQUEST | ICMPV6_ECHO_REQUEST => Some(source),
+        ICMP_ECHO_REPLY | ICMPV6_ECHO_REPLY => Some(destination),
+        _ => None,
+    }
+}
+
+/// Rewrites the port at `offset` to `port` if it is set and was not cut off by `end`
+fn rewrite_port(packet: &mut [u8], end: usize, offset: usize, port: Option<u16>, sum: Option<Sum>, outer: Option<Sum>) {
+    if let Some(port) = port.filter(|_| offset + 2 <= end) {
+        replace(packet, offset, &port.to_be_bytes(), &[sum], outer);
+    }
+}
+
+/// Idle time after which the mappings of a [`NatTable`] expire, per protocol
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NatTimeouts {
+    /// TCP connections that have not seen a FIN or RST. Defaults to 2 hours and 4 minutes, as
+    /// RFC 5382 asks
+    pub tcp: Duration,
+
+    /// TCP connections that have seen a FIN or RST. Defaults to 4 minutes
+    pub tcp_closing: Duration,
+
+    /// Defaults to 5 minutes, RFC 4787 asks for at least 2
+    pub udp: Duration,
+
+    /// ICMP and ICMPv6 echo identifiers. Defaults to 60 seconds
+    pub icmp: Duration,
+}
+
+impl Default for NatTimeouts {
+    fn default() -> Self {
+        Self {
+            tcp: Duration::from_secs(124 * 60),
+            tcp_closing: Duration::from_secs(4 * 60),
+            udp: Duration::from_secs(5 * 60),
+            icmp: Duration::from_secs(60),
+        }
+    }
+}
+
+impl NatTimeouts {
+    fn get(&self, protocol: u8, closing: bool) -> Duration {
+        match protocol {
+            PROTOCOL_TCP if closing => self.tcp_closing,
+            PROTOCOL_TCP => self.tcp,
+            PROTOCOL_UDP => self.udp,
+            _ => self.icmp,
+        }
+    }
+}
+
+/// Where a [`NatTable`] sends packets arriving at one outer port
+struct Mapping {
+    inner: IpAddr,
+    inner_port: u16,
+
+    /// `None` for mappings added with [`NatTable::forward`], which never expire
+    expires: Option<Instant>,
+
+    /// Set once a TCP connection saw a FIN or RST
+    closing: bool,
+}
+
+impl Mapping {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Pushes the expiry of a dynamic mapping back after it carried a packet with `tcp_flags`
+    fn refresh(&mut self, protocol: u8, tcp_flags: u8, timeouts: &NatTimeouts, now: Instant) {
+        if self.expires.is_some() {
+            self.closing |= tcp_flags & (ip::TCP_FIN | ip::TCP_RST) != 0;
+            self.expires = Some(now + timeouts.get(protocol, self.closing));
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Keyed by protocol and outer port
+    mappings: HashMap<(u8, u16), Mapping>,
+
+    /// The outer port of every inner protocol, address and port with a mapping
+    outer_ports: HashMap<(u8, IpAddr, u16), u16>,
+
+    /// Offset into the port range where the search for a free port starts
+    next_port: u32,
+}
+
+impl State {
+    /// Returns the mapping of the outer `port`, dropping it if it expired
+    fn get(&mut self, protocol: u8, port: u16, now: Instant) -> Option<&mut Mapping> {
+        if self.mappings.get(&(protocol, port))?.is_expired(now) {
+            self.remove(protocol, port);
+            return None;
+        }
+        self.mappings.get_mut(&(protocol, port))
+    }
+
+    /// Returns the live outer port of `inner`:`inner_port`
+    fn outer_port(&mut self, protocol: u8, inner: IpAddr, inner_port: u16, now: Instant) -> Option<u16> {
+        let port = *self.outer_ports.get(&(protocol, inner, inner_port))?;
+        self.get(protocol, port, now).map(|_| port)
+    }
+
+    fn insert(&mut self, protocol: u8, port: u16, mapping: Mapping) {
+        self.outer_ports
+            .insert((protocol, mapping.inner, mapping.inner_port), port);
+        self.mappings.insert((protocol, port), mapping);
+    }
+
+    fn remove(&mut self, protocol: u8, port: u16) {
+        if let Some(mapping) = self.mappings.remove(&(protocol, port)) {
+            self.outer_ports.remove(&(protocol, mapping.inner, mapping.inner_port));
+        }
+    }
+
+    /// Returns the outer port of `inner`:`inner_port`, allocating one out of `ports` if it has
+    /// none. The inner port is kept when it is free, and new mappings expire after `timeout`
+    fn map(
+        &mut self,
+        protocol: u8,
+        inner: IpAddr,
+        inner_port: u16,
+        ports: &RangeInclusive<u16>,
+        timeout: Duration,
+        now: Instant,
+    ) -> Result<u16, Error> {
+        if let Some(port) = self.outer_port(protocol, inner, inner_port, now) {
+            return Ok(port);
+        }
+        let (start, count) = (*ports.start(), (ports.end() - ports.start()) as u32 + 1);
+        let preferred = ports.contains(&inner_port).then_some(inner_port);
+        let next_port = self.next_port;
+        let scan = (0..count).map(|i| start + ((next_port + i) % count) as u16);
+        for port in preferred.into_iter().chain(scan) {
+            if self.get(protocol, port, now).is_none() {
+                let mapping = Mapping {
+                    inner,
+                    inner_port,
+                    expires: Some(now + timeout),
+                    closing: false,
+                };
+                self.insert(protocol, port, mapping);
+                self.next_port = (port - start) as u32 + 1;
+                return Ok(port);
+            }
+        }
+        Err(Error::NatPortUnavailable)
+    }
+}
+
+/// A connection tracking NAT table that shares one outer address between the hosts behind it
+///
+/// Packets leaving the inner network, such as those forwarded from a LAN into a [`crate::Session`],
+/// go through [`NatTable::outbound`]. It rewrites their source to the outer address and a port
+/// allocated for the inner address and port, so replies can be told apart. Packets received from
+/// the session go through [`NatTable::inbound`], which translates replies back to the inner host.
+/// ICMP and ICMPv6 echo identifiers are mapped like ports, and ICMP errors are translated along
+/// with the packet they quote. [`NatTable::forward`] adds static mappings for servers on the
+/// inner side.
+///
+/// Mappings are independent of the remote endpoint: once an inner port is mapped, packets from any
+/// remote host reach it through its outer port until the mapping has been idle for the timeout of
+/// its protocol.
+///
+/// # Example
+/// ```
+/// use wintun::{ip::{Ipv4View, UdpView}, nat::NatTable, PacketBuilder};
+///
+/// let (tunnel, remote) = wintun::Session::pair().unwrap();
+/// let nat = NatTable::new([10, 8, 0, 2].into(), 40000..=40999);
+///
+/// //A packet from a LAN host leaves through the tunnel
+/// let lan = PacketBuilder::ipv4([192, 168, 1, 20].into(), [1, 1, 1, 1].into()).udp(5000, 53);
+/// let mut packet = lan.build(&tunnel).unwrap();
+/// assert!(nat.outbound(packet.bytes_mut()).unwrap());
+/// tunnel.send_packet(packet);
+///
+/// let received = remote.receive_blocking().unwrap();
+/// let ip = Ipv4View::new(received.bytes()).unwrap();
+/// assert_eq!(ip.source(), std::net::Ipv4Addr::new(10, 8, 0, 2));
+/// let outer_port = UdpView::new(ip.payload()).unwrap().source_port();
+///
+/// //The reply comes back to the LAN host
+/// let reply = PacketBuilder::ipv4([1, 1, 1, 1].into(), [10, 8, 0, 2].into()).udp(53, outer_port);
+/// remote.send_packet(reply.build(&remote).unwrap());
+/// let reply = nat.inbound_packet(&tunnel.receive_blocking().unwrap()).unwrap().unwrap();
+/// let ip = Ipv4View::new(reply.bytes()).unwrap();
+/// assert_eq!(ip.destination(), std::net::Ipv4Addr::new(192, 168, 1, 20));
+/// assert_eq!(UdpView::new(ip.payload()).unwrap().destination_port(), 5000);
+/// ```
+pub struct NatTable {
+    address: IpAddr,
+    ports: RangeInclusive<u16>,
+    timeouts: NatTimeouts,
+    state: Mutex<State>,
+}
+
+impl NatTable {
+    /// Creates a table translating to the outer address `address`, allocating outer ports out of
+    /// `ports`. Each of UDP, TCP and ICMP echo identifiers has its own set of ports
+    ///
+    /// # Panics
+    /// If `ports` is empty
+    pub fn new(address: IpAddr, ports: RangeInclusive<u16>) -> Self {
+        assert!(!ports.is_empty(), "NAT port range is empty");
+        Self {
+            address,
+            ports,
+            timeouts: NatTimeouts::default(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Replaces the default timeouts
+    pub fn with_timeouts(mut self, timeouts: NatTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Returns the outer address packets are translated to
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    /// Sends packets of `protocol` arriving at the outer `port` to `inner`, and packets from
+    /// `inner` out through `port`. The port does not need to be in the table's range, and the
+    /// mapping never expires
+    ///
+    /// Fails with [`Error::NatPortUnavailable`] if `port` or `inner` is already mapped, unless the
+    /// mapping has expired or is the same one
+    pub fn forward(&self, protocol: u8, port: u16, inner: SocketAddr) -> Result<(), Error> {
+        self.forward_at(protocol, port, inner, Instant::now())
+    }
+
+    fn forward_at(&self, protocol: u8, port: u16, inner: SocketAddr, now: Instant) -> Result<(), Error> {
+        if !matches!(protocol, PROTOCOL_UDP | PROTOCOL_TCP) {
+            return Err(Error::UnsupportedPacket("only UDP and TCP ports can be forwarded"));
+        }
+        if inner.is_ipv4() != self.address.is_ipv4() {
+            return Err(Error::UnsupportedPacket("address is not of the table's IP version"));
+        }
+        let mut state = self.lock();
+        let existing = state.outer_port(protocol, inner.ip(), inner.port(), now);
+        if existing.is_some_and(|existing| existing != port)
+            || existing.is_none() && state.get(protocol, port, now).is_some()
+        {
+            return Err(Error::NatPortUnavailable);
+        }
+        let mapping = Mapping {
+            inner: inner.ip(),
+            inner_port: inner.port(),
+            expires: None,
+            closing: false,
+        };
+        state.insert(protocol, port, mapping);
+        Ok(())
+    }
+
+    /// Translates the IP packet `packet`, which leaves the inner network, in place. Its source
+    /// becomes the outer address and port, allocating a port if the inner address and port have
+    /// none yet
+    ///
+    /// Returns false, leaving the packet as is, for packets that cannot be translated: other IP
+    /// versions, protocols without ports, fragments after the first, echo replies and ICMP errors
+    /// about packets that did not come in through the table. Fails with
+    /// [`Error::NatPortUnavailable`] when every port of the range is in use
+    pub fn outbound(&self, packet: &mut [u8]) -> Result<bool, Error> {
+        self.outbound_at(packet, Instant::now())
+    }
+
+    fn outbound_at(&self, packet: &mut [u8], now: Instant) -> Result<bool, Error> {
+        let layout = Layout::new(packet)?;
+        if layout.v4 != self.address.is_ipv4() || !layout.has_transport {
+            return Ok(false);
+        }
+        let mut state = self.lock();
+
+        let outer_port = match Quote::new(packet, &layout) {
+            //The quoted packet came in through the table, so its destination is on the inner side
+            Some(quote) => {
+                let (_, Some(port)) = ports(packet, quote.v4, quote.protocol, quote.transport, quote.end) else {
+                    return Ok(false);
+                };
+                let inner = read_address(packet, quote.start + address_offset(quote.v4, false), quote.v4);
+                match state.outer_port(quote.protocol, inner, port, now) {
+                    Some(outer_port) => outer_port,
+                    None => return Ok(false),
+                }
+            }
+            None => {
+                let (Some(port), _) = ports(packet, layout.v4, layout.protocol, layout.transport, layout.end) else {
+                    return Ok(false);
+                };
+                let inner = read_address(packet, address_offset(layout.v4, true), layout.v4);
+                let outer_port = state.map(
+                    layout.protocol,
+                    inner,
+                    port,
+                    &self.ports,
+                    self.timeouts.get(layout.protocol, false),
+                    now,
+                )?;
+                let mapping = state
+                    .get(layout.protocol, outer_port, now)
+                    .expect("Mapping was just made live");
+                mapping.refresh(layout.protocol, tcp_flags(packet, &layout), &self.timeouts, now);
+                outer_port
+            }
+        };
+        drop(state);
+
+        rewrite_src(packet, self.address)?;
+        rewrite_ports(packet, Some(outer_port), None)?;
+        Ok(true)
+    }
+
+    /// Translates the IP packet `packet`, which arrived at the outer address, in place. Its
+    /// destination becomes the inner address and port of the mapping of its destination port
+    ///
+    /// Returns false, leaving the packet as is, for packets without a live mapping, such as those
+    /// sent to another address or carrying echo requests, which the outer host answers itself
+    pub fn inbound(&self, packet: &mut [u8]) -> Result<bool, Error> {
+        self.inbound_at(packet, Instant::now())
+    }
+
+    fn inbound_at(&self, packet: &mut [u8], now: Instant) -> Result<bool, Error> {
+        let layout = Layout::new(packet)?;
+        if layout.v4 != self.address.is_ipv4() || !layout.has_transport {
+            return Ok(false);
+        }
+        if read_address(packet, address_offset(layout.v4, false), layout.v4) != self.address {
+            return Ok(false);
+        }
+        let mut state = self.lock();
+
+        let (inner, inner_port) = match Quote::new(packet, &layout) {
+            //The quoted packet went out through the table, so its source is on the outer side
+            Some(quote) => {
+                let (Some(port), _) = ports(packet, quote.v4, quote.protocol, quote.transport, quote.end) else {
+                    return Ok(false);
+                };
+                if read_address(packet, quote.start + address_offset(quote.v4, true), quote.v4) != self.address {
+                    return Ok(false);
+                }
+                match state.get(quote.protocol, port, now) {
+                    Some(mapping) => (mapping.inner, mapping.inner_port),
+                    None => return Ok(false),
+                }
+            }
+            None => {
+                let (_, Some(port)) = ports(packet, layout.v4, layout.protocol, layout.transport, layout.end) else {
+                    return Ok(false);
+                };
+                let Some(mapping) = state.get(layout.protocol, port, now) else {
+                    return Ok(false);
+                };
+                mapping.refresh(layout.protocol, tcp_flags(packet, &layout), &self.timeouts, now);
+                (mapping.inner, mapping.inner_port)
+            }
+        };
+        drop(state);
+
+        rewrite_dst(packet, inner)?;
+        rewrite_ports(packet, None, Some(inner_port))?;
+        Ok(true)
+    }
+
+    /// Copies a packet received from a session and translates it with [`NatTable::inbound`],
+    /// returning `None` if it was not translated
+    pub fn inbound_packet(&self, packet: &RecvPacket) -> Result<Option<OwnedPacket>, Error> {
+        let mut owned = BufferPool::global().copy(packet.bytes());
+        Ok(self.inbound(owned.bytes_mut())?.then_some(owned))
+    }
+
+    /// Drops every expired mapping. Expired mappings are otherwise dropped as packets run into them
+    pub fn expire(&self) {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&self, now: Instant) {
+        let mut state = self.lock();
+        let expired: Vec<_> = state
+            .mappings
+            .iter()
+            .filter(|(_, mapping)| mapping.is_expired(now))
+            .map(|(key, _)| *key)
+            .collect();
+        for (protocol, port) in expired {
+            state.remove(protocol, port);
+        }
+    }
+
+    /// Returns the number of mappings that have not expired, including forwarded ports
+    pub fn len(&self) -> usize {
+        self.len_at(Instant::now())
+    }
+
+    fn len_at(&self, now: Instant) -> usize {
+        let state = self.lock();
+        state
+            .mappings
+            .values()
+            .filter(|mapping| !mapping.is_expired(now))
+            .count()
+    }
+
+    /// Returns true if the table has no mappings that have not expired
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        //Every update leaves the maps consistent, so ignore poisoning
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Returns the ports of the UDP, TCP or ICMP echo header of `protocol` at `transport`, which may be
+/// cut off by `end`. Echo identifiers count as the source port of requests and the destination
+/// port of replies
+fn ports(packet: &[u8], v4: bool, protocol: u8, transport: usize, end: usize) -> (Option<u16>, Option<u16>) {
+    let port = |offset: usize| (offset + 2 <= end).then(|| ip::read_u16(packet, offset));
+    match (protocol, v4) {
+        (PROTOCOL_UDP | PROTOCOL_TCP, _) => (port(transport), port(transport + 2)),
+        (PROTOCOL_ICMP, true) | (PROTOCOL_ICMPV6, false) if transport < end => match packet[transport] {
+            ICMP_ECHO_REQUEST | ICMPV6_ECHO_REQUEST => (port(transport + 4), None),
+            ICMP_ECHO_REPLY | ICMPV6_ECHO_REPLY => (None, port(transport + 4)),
+            _ => (None, None),
+        },
+        _ => (None, None),
+    }
+}
+
+/// Returns the TCP flags of the packet, or zero for other protocols
+fn tcp_flags(packet: &[u8], layout: &Layout) -> u8 {
+    match layout.protocol {
+        PROTOCOL_TCP => packet[layout.transport + 13],
+        _ => 0,
+    }
+}
+
+fn read_address(packet: &[u8], offset: usize, v4: bool) -> IpAddr {
+    match v4 {
+        true => <[u8; 4]>::try_from(&packet[offset..offset + 4]).unwrap().into(),
+        false => <[u8; 16]>::try_from(&packet[offset..offset + 16]).unwrap().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ip::IcmpView, PacketBuilder};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const V4_INNER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 20);
+    const V4_OUTER: Ipv4Addr = Ipv4Addr::new(10, 8, 0, 2);
+    const V4_REMOTE: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+    const V6_INNER: Ipv6Addr = Ipv6Addr::new(0xfd01, 0, 0, 0, 0, 0, 0, 0x20);
+    const V6_OUTER: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+    const V6_REMOTE: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+    fn build(builder: PacketBuilder) -> Vec<u8> {
+        let mut packet = vec![0; builder.size()];
+        builder.write(&mut packet);
+        packet
+    }
+
+    fn verify(packet: &[u8]) {
+        assert!(checksum::verify(packet).unwrap(), "bad checksum in {:02x?}", packet);
+    }
+
+    /// Returns the source and destination port of the UDP or TCP header at `transport`
+    fn ports_at(packet: &[u8], transport: usize) -> (u16, u16) {
+        (ip::read_u16(packet, transport), ip::read_u16(packet, transport + 2))
+    }
+
+    /// Rewrites the source of a packet leaving `inner` to `outer`:40000 and checks every checksum
+    fn snat(packet: &mut [u8], outer: IpAddr) {
+        rewrite_src(packet, outer).unwrap();
+        verify(packet);
+        rewrite_ports(packet, Some(40000), None).unwrap();
+        verify(packet);
+        assert_eq!(IpView::new(packet).unwrap().source(), outer);
+    }
+
+    #[test]
+    fn udp() {
+        let payload = [7; 33];
+        let mut packet = build(PacketBuilder::ipv4(V4_INNER, V4_REMOTE).udp(5000, 53).payload(&payload));
+        snat(&mut packet, V4_OUTER.into());
+        assert_eq!(ports_at(&packet, 20), (40000, 53));
+
+        let mut packet = build(PacketBuilder::ipv6(V6_INNER, V6_REMOTE).udp(5000, 53).payload(&payload));
+        snat(&mut packet, V6_OUTER.into());
+        assert_eq!(ports_at(&packet, 40), (40000, 53));
+
+        rewrite_dst(&mut packet, V6_INNER.into()).unwrap();
+        rewrite_ports(&mut packet, None, Some(4242)).unwrap();
+        verify(&packet);
+        assert_eq!(IpView::new(&packet).unwrap().destination(), IpAddr::from(V6_INNER));
+        assert_eq!(ports_at(&packet, 40), (40000, 4242));
+    }
+
+    #[test]
+    fn tcp() {
+        fn builder(packet: PacketBuilder) -> PacketBuilder {
+            packet.tcp(5000, 443).sequence_number(77).tcp_flags(ip::TCP_SYN)
+        }
+        let mut packet = build(builder(PacketBuilder::ipv4(V4_INNER, V4_REMOTE)));
+        snat(&mut packet, V4_OUTER.into());
+        assert_eq!(ports_at(&packet, 20), (40000, 443));
+
+        let mut packet = build(builder(PacketBuilder::ipv6(V6_INNER, V6_REMOTE)).payload(b"odd"));
+        snat(&mut packet, V6_OUTER.into());
+        assert_eq!(ports_at(&packet, 40), (40000, 443));
+    }
+
+    #[test]
+    fn icmp_echo() {
+        let identifier = |packet: &[u8]| {
+            IcmpView::new(IpView::new(packet).unwrap().payload())
+                .unwrap()
+                .echo_identifier()
+        };
+
+        let mut request = build(
+            PacketBuilder::ipv4(V4_INNER, V4_REMOTE)
+                .icmp(8, 0, [0, 7, 0, 1])
+                .payload(b"ping"),
+        );
+        snat(&mut request, V4_OUTER.into());
+        assert_eq!(identifier(&request), 40000);
+
+        //The identifier of replies is their destination port
+        let mut reply = build(PacketBuilder::ipv4(V4_REMOTE, V4_OUTER).icmp(0, 0, [0x9c, 0x40, 0, 1]));
+        rewrite_dst(&mut reply, V4_INNER.into()).unwrap();
+        rewrite_ports(&mut reply, Some(1), None).unwrap();
+        assert_eq!(identifier(&reply), 40000);
+        rewrite_ports(&mut reply, None, Some(7)).unwrap();
+        verify(&reply);
+        assert_eq!(identifier(&reply), 7);
+
+        let mut request = build(PacketBuilder::ipv6(V6_INNER, V6_REMOTE).icmp(128, 0, [0, 7, 0, 1]));
+        snat(&mut request, V6_OUTER.into());
+        assert_eq!(identifier(&request), 40000);
+    }
+
+    #[test]
+    fn zero_udp_checksum_over_ipv4() {
+        let mut packet = build(PacketBuilder::ipv4(V4_INNER, V4_REMOTE).udp(5000, 53).payload(b"abc"));
+        packet[26..28].fill(0);
+        snat(&mut packet, V4_OUTER.into());
+        assert_eq!(ip::read_u16(&packet, 26), 0);
+    }
+
+    #[test]
+    fn udp_checksum_of_zero_is_sent_as_all_ones() {
+        let mut packet = build(PacketBuilder::ipv4(V4_INNER, V4_REMOTE).udp(5000, 53));
+        //Pick the new source port so that the adjusted checksum comes out as zero:
+        //~(~HC + ~m + m') = 0 when m' = ~(~HC + ~m)
+        let sum = ip::read_u16(&packet, 26);
+        let port = checksum::finish(!sum as u64 + !5000u16 as u64);
+        assert_eq!(checksum::adjust_u16(sum, 5000, port), 0);
+        rewrite_ports(&mut packet, Some(port), None).unwrap();
+        verify(&packet);
+        assert_eq!(ip::read_u16(&packet, 26), 0xFFFF);
+    }
+
+    /// Builds an ICMP error from `from` to `to` quoting the first `quoted` bytes of `original`
+    fn icmp_error(from: IpAddr, to: IpAddr, original: &[u8], quoted: usize) -> Vec<u8> {
+        let quote = &original[..quoted.min(original.len())];
+        match (from, to) {
+            (IpAddr::V4(from), IpAddr::V4(to)) => {
+                build(PacketBuilder::ipv4(from, to).icmp(3, 3, [0; 4]).payload(quote))
+            }
+            (IpAddr::V6(from), IpAddr::V6(to)) => {
+                build(PacketBuilder::ipv6(from, to).icmp(1, 4, [0; 4]).payload(quote))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Translates an ICMP error about `original`, which left `inner` and was rewritten to `outer`,
+    /// back towards `inner`, quoting `quoted` bytes
+    fn icmp_error_back(inner: IpAddr, outer: IpAddr, remote: IpAddr, mut original: Vec<u8>, quoted: usize) {
+        let header_len = if inner.is_ipv4() { 20 } else { 40 };
+        let (inner_port, remote_port) = ports_at(&original, header_len);
+        rewrite_src(&mut original, outer).unwrap();
+        rewrite_ports(&mut original, Some(40000), None).unwrap();
+
+        let mut error = icmp_error(remote, outer, &original, quoted);
+        verify(&error);
+        rewrite_dst(&mut error, inner).unwrap();
+        verify(&error);
+        rewrite_ports(&mut error, None, Some(inner_port)).unwrap();
+        verify(&error);
+
+        let quote = header_len + ip::ICMP_HEADER_LEN;
+        let quoted_source = quote + address_offset(inner.is_ipv4(), true);
+        assert_eq!(read_address(&error, quoted_source, inner.is_ipv4()), inner);
+        assert_eq!(ports_at(&error, quote + header_len), (inner_port, remote_port));
+        if quoted >= original.len() {
+            //The whole packet was quoted, so its checksums can be checked too
+            verify(&error[quote..]);
+        }
+    }
+
+    #[test]
+    fn icmp_errors_quoting_udp_and_tcp() {
+        let v4 = (V4_INNER.into(), V4_OUTER.into(), V4_REMOTE.into());
+        let v6 = (V6_INNER.into(), V6_OUTER.into(), V6_REMOTE.into());
+        for (inner, outer, remote) in [v4, v6] {
+            let builder = || match (inner, remote) {
+                (IpAddr::V4(inner), IpAddr::V4(remote)) => PacketBuilder::ipv4(inner, remote),
+                (IpAddr::V6(inner), IpAddr::V6(remote)) => PacketBuilder::ipv6(inner, remote),
+                _ => unreachable!(),
+            };
+            let udp = build(builder().udp(5000, 53).payload(b"query"));
+            let tcp = build(builder().tcp(5000, 443).tcp_flags(ip::TCP_SYN).payload(b"hello"));
+            for original in [udp, tcp] {
+                let header_len = if inner.is_ipv4() { 20 } else { 40 };
+                //Whole packet, then cut off after the ports, the classic 8 bytes and halfway
+                //through the TCP checksum
+                for quoted in [original.len(), header_len + 4, header_len + 8, header_len + 17] {
+                    icmp_error_back(inner, outer, remote, original.clone(), quoted);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quoted_addresses_that_do_not_match_are_left_alone() {
+        let original = build(PacketBuilder::ipv4(V4_OUTER, V4_REMOTE).udp(40000, 53));
+        let mut error = icmp_error(V4_REMOTE.into(), V4_OUTER.into(), &original, original.len());
+        let other = Ipv4Addr::new(10, 8, 0, 99);
+        error[28 + 12..28 + 16].copy_from_slice(&other.octets());
+        checksum::update(&mut error).unwrap();
+        rewrite_dst(&mut error, V4_INNER.into()).unwrap();
+        verify(&error);
+        assert_eq!(read_address(&error, 28 + 12, true), IpAddr::from(other));
+    }
+
+    /// Splits the IPv4 packet `packet` into a first fragment carrying `first` bytes of its payload
+    /// and a second fragment carrying the rest
+    fn fragment_v4(packet: &[u8], first: usize) -> (Vec<u8>, Vec<u8>) {
+        let (header, payload) = packet.split_at(20);
+        let fragment = |offset: usize, bytes: &[u8], more: bool| {
+            let mut fragment = [header, bytes].concat();
+            let len = fragment.len() as u16;
+            fragment[2..4].copy_from_slice(&len.to_be_bytes());
+            let flags = (offset / 8) as u16 | if more { 0x2000 } else { 0 };
+            fragment[6..8].copy_from_slice(&flags.to_be_bytes());
+            checksum::update(&mut fragment).unwrap();
+            fragment
+        };
+        (
+            fragment(0, &payload[..first], true),
+            fragment(first, &payload[first..], false),
+        )
+    }
+
+    fn reassemble_v4(first: &[u8], second: &[u8]) -> Vec<u8> {
+        let mut packet = [first, &second[20..]].concat();
+        let len = packet.len() as u16;
+        packet[2..4].copy_from_slice(&len.to_be_bytes());
+        packet[6..8].fill(0);
+        let sum = checksum::ipv4_header(&packet[..20]);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        packet
+    }
+
+    /// Like [`fragment_v4`] for IPv6, inserting a fragment header
+    fn fragment_v6(packet: &[u8], first: usize) -> (Vec<u8>, Vec<u8>) {
+        let (header, payload) = packet.split_at(40);
+        let fragment = |offset: usize, bytes: &[u8], more: bool| {
+            let mut fragment = header.to_vec();
+            fragment[4..6].copy_from_slice(&(8 + bytes.len() as u16).to_be_bytes());
+            fragment[6] = ip::IPV6_FRAGMENT;
+            let offset = (offset as u16) | more as u16;
+            fragment.extend_from_slice(&[header[6], 0]);
+            fragment.extend_from_slice(&offset.to_be_bytes());
+            fragment.extend_from_slice(&[0, 0, 0x12, 0x34]);
+            fragment.extend_from_slice(bytes);
+            fragment
+        };
+        (
+            fragment(0, &payload[..first], true),
+            fragment(first, &payload[first..], false),
+        )
+    }
+
+    fn reassemble_v6(first: &[u8], second: &[u8]) -> Vec<u8> {
+        let mut packet = [&first[..40], &first[48..], &second[48..]].concat();
+        packet[6] = first[40];
+        let len = packet.len() as u16 - 40;
+        packet[4..6].copy_from_slice(&len.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn fragments() {
+        let payload: Vec<u8> = (0..992).map(|i| i as u8).collect();
+        let v4 = build(PacketBuilder::ipv4(V4_INNER, V4_REMOTE).udp(5000, 53).payload(&payload));
+        let v6 = build(PacketBuilder::ipv6(V6_INNER, V6_REMOTE).udp(5000, 53).payload(&payload));
+        type Split = fn(&[u8], usize) -> (Vec<u8>, Vec<u8>);
+        type Join = fn(&[u8], &[u8]) -> Vec<u8>;
+        let cases: [(Vec<u8>, IpAddr, Split, Join); 2] = [
+            (v4, V4_OUTER.into(), fragment_v4, reassemble_v4),
+            (v6, V6_OUTER.into(), fragment_v6, reassemble_v6),
+        ];
+        for (packet, outer, split, join) in cases {
+            let (mut first, mut second) = split(&packet, 488);
+            assert!(IpView::new(&first).unwrap().is_fragment());
+
+            rewrite_src(&mut first, outer).unwrap();
+            rewrite_ports(&mut first, Some(40000), None).unwrap();
+            verify(&first);
+            rewrite_src(&mut second, outer).unwrap();
+            verify(&second);
+            assert!(matches!(
+                rewrite_ports(&mut second, Some(40000), None),
+                Err(Error::UnsupportedPacket(_))
+            ));
+
+            //The transport checksum covers the whole datagram, so check it once reassembled
+            let packet = join(&first, &second);
+            verify(&packet);
+            let ip = IpView::new(&packet).unwrap();
+            assert_eq!(ip.source(), outer);
+            assert_eq!(ports_at(ip.payload(), 0), (40000, 53));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let mut packet = build(PacketBuilder::ipv4(V4_INNER, V4_REMOTE).udp(5000, 53));
+        assert!(matches!(
+            rewrite_src(&mut packet, V6_OUTER.into()),
+            Err(Error::UnsupportedPacket(_))
+        ));
+        let mut packet = build(PacketBuilder::ipv4(V4_INNER, V4_REMOTE).protocol(47).payload(b"gre"));
+        assert!(matches!(
+            rewrite_ports(&mut packet, Some(1), None),
+            Err(Error::UnsupportedPacket(_))
+        ));
+        let mut packet = build(PacketBuilder::ipv4(V4_INNER, V4_REMOTE).icmp(13, 0, [0; 4]));
+        assert!(matches!(
+            rewrite_ports(&mut packet, Some(1), None),
+            Err(Error::UnsupportedPacket(_))
+        ));
+        assert!(matches!(
+            rewrite_src(&mut [0x45, 0, 0], V4_OUTER.into()),
+            Err(Error::MalformedPacket(_))
+        ));
+    }
+
+    fn table() -> NatTable {
+        NatTable::new(V4_OUTER.into(), 40000..=40999)
+    }
+
+    fn udp_out(port: u16) -> Vec<u8> {
+        build(PacketBuilder::ipv4(V4_INNER, V4_REMOTE).udp(port, 53).payload(b"query"))
+    }
+
+    fn udp_in(port: u16) -> Vec<u8> {
+        build(
+            PacketBuilder::ipv4(V4_REMOTE, V4_OUTER)
+                .udp(53, port)
+                .payload(b"answer"),
+        )
+    }
+
+    /// Sends `packet` out through `nat` at `now` and returns its outer source port
+    fn outbound(nat: &NatTable, mut packet: Vec<u8>, now: Instant) -> u16 {
+        assert!(nat.outbound_at(&mut packet, now).unwrap());
+        verify(&packet);
+        assert_eq!(IpView::new(&packet).unwrap().source(), nat.address());
+        ports_at(&packet, 20).0
+    }
+
+    /// Returns true if a reply to the outer `port` is translated by `nat` at `now`
+    fn replied(nat: &NatTable, port: u16, now: Instant) -> bool {
+        let mut packet = udp_in(port);
+        let translated = nat.inbound_at(&mut packet, now).unwrap();
+        if !translated {
+            assert_eq!(packet, udp_in(port));
+        }
+        translated
+    }
+
+    #[test]
+    fn replies_reach_the_inner_host() {
+        let nat = table();
+        let now = Instant::now();
+        let port = outbound(&nat, udp_out(5000), now);
+        assert!(nat.ports.contains(&port));
+
+        let mut reply = udp_in(port);
+        assert!(nat.inbound_at(&mut reply, now).unwrap());
+        verify(&reply);
+        assert_eq!(IpView::new(&reply).unwrap().destination(), IpAddr::from(V4_INNER));
+        assert_eq!(ports_at(&reply, 20), (53, 5000));
+
+        //The same inner port keeps its outer port, other ports get their own
+        assert_eq!(outbound(&nat, udp_out(5000), now), port);
+        assert_ne!(outbound(&nat, udp_out(5001), now), port);
+        assert_eq!(nat.len_at(now), 2);
+
+        //Unmapped ports and other addresses are left alone
+        assert!(!replied(&nat, port.wrapping_add(500), now));
+        let mut other = build(PacketBuilder::ipv4(V4_REMOTE, Ipv4Addr::new(10, 8, 0, 99)).udp(53, port));
+        let copy = other.clone();
+        assert!(!nat.inbound_at(&mut other, now).unwrap());
+        assert_eq!(other, copy);
+    }
+
+    #[test]
+    fn inner_port_is_kept_when_in_range_and_free() {
+        let nat = table();
+        assert_eq!(outbound(&nat, udp_out(40123), Instant::now()), 40123);
+    }
+
+    #[test]
+    fn icmp_errors_are_translated_back() {
+        let nat = table();
+        let now = Instant::now();
+        let mut original = udp_out(5000);
+        assert!(nat.outbound_at(&mut original, now).unwrap());
+
+        let mut error = icmp_error(V4_REMOTE.into(), V4_OUTER.into(), &original, original.len());
+        assert!(nat.inbound_at(&mut error, now).unwrap());
+        verify(&error);
+        assert_eq!(IpView::new(&error).unwrap().destination(), IpAddr::from(V4_INNER));
+        assert_eq!(read_address(&error, 28 + 12, true), IpAddr::from(V4_INNER));
+        assert_eq!(ports_at(&error, 48), (5000, 53));
+    }
+
+    #[test]
+    fn udp_and_icmp_expire_after_their_timeouts() {
+        let nat = table();
+        let start = Instant::now();
+        let port = outbound(&nat, udp_out(5000), start);
+        let echo = build(PacketBuilder::ipv4(V4_INNER, V4_REMOTE).icmp(8, 0, [0, 7, 0, 1]));
+        outbound(&nat, echo, start);
+        assert_eq!(nat.len_at(start), 2);
+
+        let timeouts = NatTimeouts::default();
+        assert_eq!(nat.len_at(start + timeouts.icmp - Duration::from_millis(1)), 2);
+        assert_eq!(nat.len_at(start + timeouts.icmp), 1);
+        assert_eq!(nat.len_at(start + timeouts.udp - Duration::from_millis(1)), 1);
+        assert!(!replied(&nat, port, start + timeouts.udp));
+        assert_eq!(nat.len_at(start + timeouts.udp), 0);
+    }
+
+    #[test]
+    fn traffic_keeps_mappings_alive() {
+        let nat = table();
+        let start = Instant::now();
+        let udp = NatTimeouts::default().udp;
+        let port = outbound(&nat, udp_out(5000), start);
+        //Replies refresh the mapping as well as outbound packets
+        assert!(replied(&nat, port, start + udp / 2));
+        assert_eq!(outbound(&nat, udp_out(5000), start + udp), port);
+        assert!(replied(&nat, port, start + udp * 2 - Duration::from_millis(1)));
+        assert!(!replied(&nat, port, start + udp * 3));
+    }
+
+    #[test]
+    fn expire_drops_expired_mappings() {
+        let nat = table();
+        let start = Instant::now();
+        outbound(&nat, udp_out(5000), start);
+        nat.expire_at(start);
+        assert_eq!(nat.state.lock().unwrap().mappings.len(), 1);
+        nat.expire_at(start + NatTimeouts::default().udp);
+        let state = nat.state.lock().unwrap();
+        assert!(state.mappings.is_empty());
+        assert!(state.outer_ports.is_empty());
+    }
+
+    #[test]
+    fn tcp_mappings_close_after_fin_or_rst() {
+        let timeouts = NatTimeouts::default();
+        let tcp = |flags: u8| build(PacketBuilder::ipv4(V4_INNER, V4_REMOTE).tcp(5000, 443).tcp_flags(flags));
+        for closing in [ip::TCP_FIN | ip::TCP_ACK, ip::TCP_RST] {
+            let nat = table();
+            let start = Instant::now();
+            outbound(&nat, tcp(ip::TCP_SYN), start);
+            //Established connections outlive the UDP and closing timeouts
+            let later = start + timeouts.tcp - Duration::from_secs(1);
+            assert_eq!(nat.len_at(later), 1);
+            outbound(&nat, tcp(ip::TCP_ACK), later);
+
+            outbound(&nat, tcp(closing), later);
+            assert_eq!(nat.len_at(later + timeouts.tcp_closing - Duration::from_millis(1)), 1);
+            assert_eq!(nat.len_at(later + timeouts.tcp_closing), 0);
+        }
+    }
+
+    #[test]
+    fn custom_timeouts() {
+        let timeouts = NatTimeouts {
+            udp: Duration::from_secs(5),
+            ..NatTimeouts::default()
+        };
+        let nat = table().with_timeouts(timeouts);
+        let start = Instant::now();
+        outbound(&nat, udp_out(5000), start);
+        assert_eq!(nat.len_at(start + Duration::from_secs(4)), 1);
+        assert_eq!(nat.len_at(start + Duration::from_secs(5)), 0);
+    }
+
+    #[test]
+    fn exhausted_ports() {
+        let nat = NatTable::new(V4_OUTER.into(), 40000..=40001);
+        let start = Instant::now();
+        outbound(&nat, udp_out(5000), start);
+        outbound(&nat, udp_out(5001), start);
+        let mut packet = udp_out(5002);
+        assert!(matches!(
+            nat.outbound_at(&mut packet, start),
+            Err(Error::NatPortUnavailable)
+        ));
+        assert_eq!(packet, udp_out(5002));
+
+        //Each protocol has its own ports
+        let tcp = build(
+            PacketBuilder::ipv4(V4_INNER, V4_REMOTE)
+                .tcp(5002, 443)
+                .tcp_flags(ip::TCP_SYN),
+        );
+        outbound(&nat, tcp, start);
+
+        //Ports are reused once their mappings expire
+        let later = start + NatTimeouts::default().udp;
+        assert!(nat.ports.contains(&outbound(&nat, udp_out(5002), later)));
+    }
+
+    #[test]
+    fn forwarded_ports() {
+        let nat = table();
+        let start = Instant::now();
+        let server = SocketAddr::new(V4_INNER.into(), 8080);
+        nat.forward_at(PROTOCOL_UDP, 53, server, start).unwrap();
+
+        let mut request = build(PacketBuilder::ipv4(V4_REMOTE, V4_OUTER).udp(5353, 53));
+        assert!(nat.inbound_at(&mut request, start).unwrap());
+        verify(&request);
+        assert_eq!(ports_at(&request, 20), (5353, 8080));
+
+        //Replies from the server leave through the forwarded port, and it never expires
+        let later = start + Duration::from_secs(365 * 24 * 60 * 60);
+        let reply = build(PacketBuilder::ipv4(V4_INNER, V4_REMOTE).udp(8080, 5353));
+        assert_eq!(outbound(&nat, reply, later), 53);
+        nat.expire_at(later);
+        assert_eq!(nat.len_at(later), 1);
+
+        //Forwarding again is fine, but not to another port or from another host
+        nat.forward_at(PROTOCOL_UDP, 53, server, later).unwrap();
+        assert!(matches!(
+            nat.forward_at(PROTOCOL_UDP, 54, server, later),
+            Err(Error::NatPortUnavailable)
+        ));
+        let other = SocketAddr::new(Ipv4Addr::new(192, 168, 1, 21).into(), 8080);
+        assert!(matches!(
+            nat.forward_at(PROTOCOL_UDP, 53, other, later),
+            Err(Error::NatPortUnavailable)
+        ));
+        assert!(matches!(
+            nat.forward_at(PROTOCOL_ICMP, 53, other, later),
+            Err(Error::UnsupportedPacket(_))
+        ));
+        assert!(matches!(
+            nat.forward_at(PROTOCOL_UDP, 53, SocketAddr::new(V6_INNER.into(), 53), later),
+            Err(Error::UnsupportedPacket(_))
+        ));
+    }
+
+    #[test]
+    fn forward_conflicting_with_a_live_mapping() {
+        let nat = table();
+        let start = Instant::now();
+        let port = outbound(&nat, udp_out(5000), start);
+        let server = SocketAddr::new(Ipv4Addr::new(192, 168, 1, 21).into(), 8080);
+        assert!(matches!(
+            nat.forward_at(PROTOCOL_UDP, port, server, start),
+            Err(Error::NatPortUnavailable)
+        ));
+        //Nor can the inner port of the live mapping be forwarded elsewhere
+        let inner = SocketAddr::new(V4_INNER.into(), 5000);
+        assert!(matches!(
+            nat.forward_at(PROTOCOL_UDP, 41000, inner, start),
+            Err(Error::NatPortUnavailable)
+        ));
+
+        let later = start + NatTimeouts::default().udp;
+        nat.forward_at(PROTOCOL_UDP, port, server, later).unwrap();
+        let mut reply = udp_in(port);
+        assert!(nat.inbound_at(&mut reply, later).unwrap());
+        assert_eq!(IpView::new(&reply).unwrap().destination(), server.ip());
+    }
+}
@@ -1,16 +1,22 @@
-use crate::session;
-use std::sync::Arc;
+use crate::{session, BufferPool};
+use std::{
+    mem::ManuallyDrop,
+    sync::{atomic::Ordering, Arc},
+};
 
-pub(crate) enum Kind {
-    SendPacketPending, //Send packet type, but not sent yet
-    SendPacketSent,    //Send packet type - sent
-    ReceivePacket,
-}
+/// Where the memory behind a packet's bytes comes from
+pub(crate) enum Owner {
+    /// A region of the ring buffer of this session
+    Session(Arc<session::Session>),
 
-/// Represents a wintun packet
-pub struct Packet {
-    pub(crate) kind: Kind,
+    /// A `Box<[u8]>` that was leaked when the packet was created, and is freed when it is dropped.
+    /// Used by devices that copy packets in and out of the kernel, such as [`crate::LinuxTun`]
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    Heap,
+}
 
+/// The bytes of a [`SendPacket`] or [`RecvPacket`] along with what keeps them alive
+pub(crate) struct PacketData {
     /// This lifetime is not actually 'static, however before you get your pitchforks let me explain...
     /// The bytes in this slice live for as long at the session that allocated them, or until
     /// WintunReleaseReceivePacket, or WintunSendPacket is called on them (whichever happens first).
@@ -36,52 +42,306 @@ pub struct Packet {
     /// therefore mut is okay here.
     pub(crate) bytes: &'static mut [u8],
 
-    /// Share ownership of session to prevent the session from being dropped before packets that
-    /// belong to it
-    pub(crate) session: Arc<session::Session>,
+    /// Owns the memory behind bytes. For ring buffer packets this shares ownership of the session
+    /// to prevent the session from being dropped before packets that belong to it
+    pub(crate) owner: Owner,
+}
+
+impl PacketData {
+    /// Returns the session whose ring buffer holds the bytes, if any
+    fn session(&self) -> Option<&session::Session> {
+        match &self.owner {
+            Owner::Session(session) => Some(session),
+            Owner::Heap => None,
+        }
+    }
+}
+
+impl Drop for PacketData {
+    fn drop(&mut self) {
+        //Ring buffer regions are handed back by SendPacket and RecvPacket, which know what they are
+        if let Owner::Heap = self.owner {
+            //SAFETY: bytes was leaked from a box in from_heap and never handed out beyond the
+            //lifetime of this packet
+            drop(unsafe { Box::from_raw(self.bytes as *mut [u8]) });
+        }
+    }
+}
+
+/// A packet allocated to be filled in and sent through a [`crate::TunDevice`]
+///
+/// Created with [`crate::Session::allocate_send_packet`] and consumed by
+/// [`crate::Session::send_packet`]
+pub struct SendPacket {
+    data: PacketData,
 }
 
-impl Packet {
+impl SendPacket {
+    pub(crate) fn new(bytes: &'static mut [u8], owner: Owner) -> Self {
+        Self {
+            data: PacketData { bytes, owner },
+        }
+    }
+
+    /// Creates a packet that owns `bytes` on the heap
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn from_heap(bytes: Box<[u8]>) -> Self {
+        Self::new(Box::leak(bytes), Owner::Heap)
+    }
+
+    /// Takes the bytes out of this packet once it has been handed to the device, so that dropping
+    /// them does not count as an unsent packet
+    pub(crate) fn into_data(self) -> PacketData {
+        let this = ManuallyDrop::new(self);
+        //SAFETY: this is never used or dropped again, so data is moved out exactly once
+        unsafe { std::ptr::read(&this.data) }
+    }
+
+    /// Returns true if this packet owns its bytes on the heap
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn is_heap(&self) -> bool {
+        matches!(self.data.owner, Owner::Heap)
+    }
+
+    /// Returns true if this packet's bytes live in the ring buffer of `session`
+    pub(crate) fn belongs_to(&self, session: &session::Session) -> bool {
+        self.data.session().is_some_and(|owner| std::ptr::eq(owner, session))
+    }
+
     /// Returns the bytes this packet holds as &mut.
     /// The lifetime of the bytes is tied to the lifetime of this packet.
     pub fn bytes_mut(&mut self) -> &mut [u8] {
-        self.bytes
+        self.data.bytes
+    }
+
+    /// Returns an immutable reference to the bytes this packet holds.
+    /// The lifetime of the bytes is tied to the lifetime of this packet.
+    pub fn bytes(&self) -> &[u8] {
+        self.data.bytes
+    }
+}
+
+impl Drop for SendPacket {
+    fn drop(&mut self) {
+        if let Owner::Session(session) = &self.data.owner {
+            drop_unsent(session, self.data.bytes);
+        }
+    }
+}
+
+/// A read only packet received from a [`crate::TunDevice`]
+///
+/// The packet's region of the receive ring is handed back to wintun once it is dropped. Use
+/// [`RecvPacket::into_vec`] to keep the contents around for longer or to modify them
+///
+/// Received packets cannot be sent back as they are, they have to be copied into a [`SendPacket`]:
+/// ```compile_fail
+/// let (a, b) = wintun::Session::pair().unwrap();
+/// let received = b.receive_blocking().unwrap();
+/// b.send_packet(received);
+/// ```
+pub struct RecvPacket {
+    data: PacketData,
+}
+
+impl RecvPacket {
+    pub(crate) fn new(bytes: &'static mut [u8], owner: Owner) -> Self {
+        Self {
+            data: PacketData { bytes, owner },
+        }
+    }
+
+    /// Creates a packet that owns `bytes` on the heap
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn from_heap(bytes: Box<[u8]>) -> Self {
+        Self::new(Box::leak(bytes), Owner::Heap)
     }
 
     /// Returns an immutable reference to the bytes this packet holds.
     /// The lifetime of the bytes is tied to the lifetime of this packet.
     pub fn bytes(&self) -> &[u8] {
-        self.bytes
+        self.data.bytes
+    }
+
+    /// Copies the contents of this packet into a new vector and releases the packet
+    pub fn into_vec(self) -> Vec<u8> {
+        self.bytes().to_vec()
+    }
+
+    /// Copies the contents of this packet into an [`OwnedPacket`] from [`BufferPool::global`] and
+    /// releases the packet before returning
+    pub fn into_owned(self) -> OwnedPacket {
+        BufferPool::global().copy(self.bytes())
+    }
+}
+
+impl Drop for RecvPacket {
+    fn drop(&mut self) {
+        if let Some(session) = self.data.session() {
+            //SAFETY: We share ownership of the session therefore it hasn't been dropped yet, and
+            //bytes is a region of its receive ring that is not accessed again
+            unsafe { release_receive(session, self.data.bytes) };
+        }
+    }
+}
+
+/// A copy of a packet that lives on the heap, independently of any session
+///
+/// Keeping a [`RecvPacket`] around holds up its session, because wintun releases received packets
+/// in order. Long lived packets, such as packets queued for another thread, should be copied with
+/// [`RecvPacket::into_owned`] instead. Owned packets are sent with [`crate::Session::send_owned`]
+///
+/// # Example
+/// ```
+/// let (a, b) = wintun::Session::pair().unwrap();
+/// a.send_owned(&wintun::OwnedPacket::from(vec![0x45, 0, 0, 4])).unwrap();
+///
+/// //into_owned releases the received packet before returning
+/// let mut owned = b.receive_blocking().unwrap().into_owned();
+/// owned.bytes_mut()[1] = 0x10;
+/// b.send_owned(&owned).unwrap();
+/// assert_eq!(a.receive_blocking().unwrap().bytes(), &[0x45, 0x10, 0, 4]);
+/// ```
+#[derive(Default)]
+pub struct OwnedPacket {
+    bytes: Vec<u8>,
+
+    /// Where the buffer goes once the packet is dropped
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl OwnedPacket {
+    pub(crate) fn from_pool(bytes: Vec<u8>, pool: Arc<BufferPool>) -> Self {
+        Self {
+            bytes,
+            pool: Some(pool),
+        }
+    }
+
+    /// Returns the bytes this packet holds as &mut
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+
+    /// Returns an immutable reference to the bytes this packet holds
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the buffer that holds this packet's bytes, taking it out of its pool
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.pool = None;
+        std::mem::take(&mut self.bytes)
     }
 }
 
-impl Drop for Packet {
+impl Drop for OwnedPacket {
     fn drop(&mut self) {
-        match self.kind {
-            Kind::ReceivePacket => {
-                unsafe {
-                    //SAFETY:
-                    //
-                    //  1. We share ownership of the session therefore it hasn't been dropped yet
-                    //  2. Bytes is valid because each packet holds exclusive access to a region of the
-                    //     ring buffer that the wintun session owns. We return that region of
-                    //     memory back to wintun here
-                    self.session
-                        .wintun
-                        .WintunReleaseReceivePacket(self.session.session.0, self.bytes.as_ptr())
-                };
-            }
-            Kind::SendPacketPending => {
-                //If someone allocates a packet with session.allocate_send_packet() and then it is
-                //dropped without being sent, this will hold up the send queue because wintun expects
-                //that every allocated packet is sent
-
-                #[cfg(feature = "panic_on_unsent_packets")]
-                panic!("Packet was never sent!");
-            }
-            Kind::SendPacketSent => {
-                //Nop
-            }
+        if let Some(pool) = self.pool.take() {
+            pool.put(std::mem::take(&mut self.bytes));
+        }
+    }
+}
+
+impl Clone for OwnedPacket {
+    fn clone(&self) -> Self {
+        match &self.pool {
+            Some(pool) => pool.copy(&self.bytes),
+            None => Self::from(self.bytes.clone()),
+        }
+    }
+}
+
+impl PartialEq for OwnedPacket {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for OwnedPacket {}
+
+impl std::fmt::Debug for SendPacket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendPacket").field("bytes", &self.data.bytes).finish()
+    }
+}
+
+impl std::fmt::Debug for OwnedPacket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OwnedPacket").field("bytes", &self.bytes).finish()
+    }
+}
+
+/// Creates a packet that is not part of any pool
+impl From<Vec<u8>> for OwnedPacket {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self { bytes, pool: None }
+    }
+}
+
+/// Copies `bytes` into a packet that is not part of any pool
+impl From<&[u8]> for OwnedPacket {
+    fn from(bytes: &[u8]) -> Self {
+        Self::from(bytes.to_vec())
+    }
+}
+
+/// Hands the receive ring region `bytes` of a dropped packet back to `session`
+///
+/// # Safety
+/// `bytes` must be a region of the receive ring of `session` that was handed out by
+/// WintunReceivePacket, and must not be accessed afterwards
+pub(crate) unsafe fn release_receive(session: &session::Session, bytes: &[u8]) {
+    //SAFETY: Bytes is valid because each packet holds exclusive access to a region of the ring
+    //buffer that the wintun session owns. We return that region of memory back to wintun here
+    session.wintun.release_receive_packet(session.session.0, bytes.as_ptr());
+}
+
+/// Called when a send packet allocated by `session` is dropped without being sent
+pub(crate) fn drop_unsent(session: &session::Session, bytes: &mut [u8]) {
+    //If someone allocates a packet with session.allocate_send_packet() and then it is dropped
+    //without being sent, this will hold up the send queue because wintun expects that every
+    //allocated packet is sent
+    session.counters.unsent_packets_dropped.fetch_add(1, Ordering::Relaxed);
+    match session.unsent_packet_policy() {
+        UnsentPacketPolicy::Count => {}
+        UnsentPacketPolicy::Submit => {
+            log::error!(
+                "Send packet of {} bytes was dropped without being sent, submitting it zeroed",
+                bytes.len()
+            );
+            bytes.fill(0);
+            //SAFETY: bytes was allocated by this session and is handed back to it exactly once
+            unsafe { session.wintun.send_packet(session.session.0, bytes.as_ptr()) };
+        }
+        UnsentPacketPolicy::Panic => panic!("Packet was never sent!"),
+    }
+}
+
+/// What a [`crate::Session`] does with a send packet that is dropped without being sent, set with
+/// [`crate::Session::set_unsent_packet_policy`]
+///
+/// Wintun hands packets to the system in the order they were allocated, so a packet that is never
+/// sent holds up every packet allocated after it. Every policy counts the packet in
+/// [`crate::Session::unsent_packets_dropped`]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UnsentPacketPolicy {
+    /// Only count the packet, leaving the send queue stalled. The default
+    Count,
+
+    /// Log an error and send the packet with its bytes zeroed so that the send queue advances. The
+    /// system discards it because it is not a valid IP packet
+    Submit,
+
+    /// Panic. The default with the `panic_on_unsent_packets` feature
+    Panic,
+}
+
+impl Default for UnsentPacketPolicy {
+    fn default() -> Self {
+        match cfg!(feature = "panic_on_unsent_packets") {
+            true => UnsentPacketPolicy::Panic,
+            false => UnsentPacketPolicy::Count,
         }
     }
 }
@@ -0,0 +1,264 @@
+//! Reusable buffers for [`OwnedPacket`]s.
+//!
+//! Copying every packet out of the ring buffer into a fresh `Vec` churns the allocator on busy
+//! tunnels. A [`BufferPool`] keeps the buffers of dropped owned packets around, sorted into size
+//! classes, and hands them out again for the next copies.
+
+use crate::{OwnedPacket, MAX_IP_PACKET_SIZE};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, OnceLock,
+};
+
+/// Size of the smallest size class. Each following class is twice as large
+const MIN_CLASS_SIZE: usize = 128;
+
+/// Number of size classes, enough for the largest class to fit [`MAX_IP_PACKET_SIZE`] bytes
+const CLASS_COUNT: usize = (MAX_IP_PACKET_SIZE as usize + 1).ilog2() as usize - MIN_CLASS_SIZE.ilog2() as usize + 1;
+
+/// Number of free buffers per size class kept by [`BufferPool::global`]
+const GLOBAL_BUFFERS_PER_CLASS: usize = 64;
+
+/// Counters describing how well a [`BufferPool`] is doing, returned by [`BufferPool::stats`]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct PoolStats {
+    /// Buffers that were reused from the pool
+    pub hits: u64,
+
+    /// Buffers that had to be allocated because the pool had none of the right size
+    pub misses: u64,
+
+    /// Buffers that were freed instead of returned because their size class was full
+    pub discarded: u64,
+}
+
+/// A bounded pool of packet buffers, in power of two size classes from 128 bytes up to
+/// [`MAX_IP_PACKET_SIZE`]
+///
+/// Buffers are drawn with [`BufferPool::copy`] and [`BufferPool::alloc`], and go back to the pool
+/// when the returned [`OwnedPacket`] is dropped. Each size class keeps at most the number of free
+/// buffers given to [`BufferPool::new`], the rest are freed
+///
+/// # Example
+/// ```
+/// let pool = wintun::BufferPool::new(16);
+/// drop(pool.copy(&[0x45, 0, 0, 4]));
+///
+/// let packet = pool.copy(&[0x45, 0, 0, 5]);
+/// assert_eq!(packet.bytes(), &[0x45, 0, 0, 5]);
+/// assert_eq!(pool.stats().hits, 1);
+/// assert_eq!(pool.stats().misses, 1);
+/// ```
+pub struct BufferPool {
+    buffers_per_class: usize,
+    classes: [Mutex<Vec<Vec<u8>>>; CLASS_COUNT],
+    hits: AtomicU64,
+    misses: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl BufferPool {
+    /// Creates a pool that keeps up to `buffers_per_class` free buffers in each size class
+    pub fn new(buffers_per_class: usize) -> Arc<Self> {
+        Arc::new(Self {
+            buffers_per_class,
+            classes: std::array::from_fn(|_| Mutex::new(Vec::new())),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the process wide pool used by [`crate::RecvPacket::into_owned`]
+    pub fn global() -> &'static Arc<Self> {
+        static GLOBAL: OnceLock<Arc<BufferPool>> = OnceLock::new();
+        GLOBAL.get_or_init(|| Self::new(GLOBAL_BUFFERS_PER_CLASS))
+    }
+
+    /// Returns an owned packet holding a copy of `bytes` in a buffer from this pool
+    pub fn copy(self: &Arc<Self>, bytes: &[u8]) -> OwnedPacket {
+        let mut buffer = self.take(bytes.len());
+        buffer.extend_from_slice(bytes);
+        OwnedPacket::from_pool(buffer, self.clone())
+    }
+
+    /// Returns an owned packet of `len` zeroed bytes in a buffer from this pool
+    pub fn alloc(self: &Arc<Self>, len: usize) -> OwnedPacket {
+        let mut buffer = self.take(len);
+        buffer.resize(len, 0);
+        OwnedPacket::from_pool(buffer, self.clone())
+    }
+
+    /// Returns the hit, miss and discard counters of this pool
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns an empty buffer with a capacity of at least `len` bytes
+    fn take(&self, len: usize) -> Vec<u8> {
+        //Sizes larger than the largest class are not pooled
+        let Some(class) = (0..CLASS_COUNT).find(|&class| class_size(class) >= len) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Vec::with_capacity(len);
+        };
+        match lock(&self.classes[class]).pop() {
+            Some(buffer) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(class_size(class))
+            }
+        }
+    }
+
+    /// Keeps `buffer` for reuse if its size class has room
+    pub(crate) fn put(&self, mut buffer: Vec<u8>) {
+        //Like in take, sizes larger than the largest class are not pooled
+        if buffer.capacity() > class_size(CLASS_COUNT - 1) {
+            return;
+        }
+        //Buffers go in the largest class they can serve
+        let Some(class) = (0..CLASS_COUNT)
+            .rev()
+            .find(|&class| class_size(class) <= buffer.capacity())
+        else {
+            return;
+        };
+        let mut free = lock(&self.classes[class]);
+        if free.len() < self.buffers_per_class {
+            buffer.clear();
+            free.push(buffer);
+        } else {
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn class_size(class: usize) -> usize {
+    MIN_CLASS_SIZE << class
+}
+
+fn lock(class: &Mutex<Vec<Vec<u8>>>) -> std::sync::MutexGuard<'_, Vec<Vec<u8>>> {
+    //A list of free buffers cannot be left in an invalid state, so ignore poisoning
+    class.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_classes() {
+        let pool = BufferPool::new(4);
+        drop(pool.copy(&[1; 100]));
+        //100 and 128 bytes share the smallest class, 129 bytes need the next one
+        drop(pool.alloc(128));
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 1,
+                misses: 1,
+                discarded: 0
+            }
+        );
+        drop(pool.alloc(129));
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 1,
+                misses: 2,
+                discarded: 0
+            }
+        );
+        let packet = pool.copy(&[2; 256]);
+        assert_eq!(packet.bytes(), &[2; 256]);
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 2,
+                misses: 2,
+                discarded: 0
+            }
+        );
+
+        //Reused buffers are cleared
+        let packet = pool.alloc(10);
+        assert_eq!(packet.bytes(), &[0; 10]);
+        let packet = pool.alloc(MAX_IP_PACKET_SIZE as usize);
+        assert_eq!(packet.bytes().len(), MAX_IP_PACKET_SIZE as usize);
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 3,
+                misses: 3,
+                discarded: 0
+            }
+        );
+    }
+
+    #[test]
+    fn put_is_bounded() {
+        let pool = BufferPool::new(2);
+        let packets: Vec<_> = (0..3).map(|_| pool.alloc(64)).collect();
+        drop(packets);
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 0,
+                misses: 3,
+                discarded: 1
+            }
+        );
+
+        //Other classes have their own bound
+        drop(pool.alloc(1000));
+        assert_eq!(pool.stats().discarded, 1);
+
+        let packets: Vec<_> = (0..3).map(|_| pool.alloc(64)).collect();
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 2,
+                misses: 5,
+                discarded: 1
+            }
+        );
+        drop(packets);
+        assert_eq!(pool.stats().discarded, 2);
+    }
+
+    #[test]
+    fn oversize_buffers_are_not_pooled() {
+        let pool = BufferPool::new(4);
+        let len = class_size(CLASS_COUNT - 1) + 1;
+        drop(pool.alloc(len));
+        drop(pool.alloc(len));
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 0,
+                misses: 2,
+                discarded: 0
+            }
+        );
+
+        pool.put(Vec::with_capacity(len));
+        pool.put(Vec::with_capacity(MIN_CLASS_SIZE - 1));
+        drop(pool.alloc(MAX_IP_PACKET_SIZE as usize));
+        drop(pool.alloc(1));
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 0,
+                misses: 4,
+                discarded: 0
+            }
+        );
+    }
+}
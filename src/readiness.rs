@@ -0,0 +1,262 @@
+//! Runtime agnostic readiness notifications for a [`Session`].
+//!
+//! [`Readiness`] registers a [`Waker`] with the system thread pool, which wakes it once the
+//! session's read event is signaled or shutdown is requested. Executors that are not Tokio, or
+//! hand written event loops, can be integrated on top of it without a blocking thread per session.
+
+use crate::{
+    event::{self, Wait},
+    Error, RecvPacket, Session,
+};
+use std::{
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+/// Why [`Readiness::poll_ready`] completed
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Ready {
+    /// The read event was signaled, so packets may be available from [`Session::try_receive`]
+    Readable,
+
+    /// [`Session::shutdown`] was called
+    ShuttingDown,
+}
+
+/// Notifies a waker when a session may have packets to receive, created with [`Session::readiness`]
+///
+/// Like waiting on the read event directly, readiness is edge triggered: only wait once
+/// [`Session::try_receive`] has returned Ok(None), otherwise packets that are already queued may
+/// never signal the event.
+///
+/// # Example
+/// ```
+/// use std::{sync::Arc, task::{Context, Poll, Wake}};
+///
+/// struct Flag(std::sync::atomic::AtomicBool);
+/// impl Wake for Flag {
+///     fn wake(self: Arc<Self>) {
+///         self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+///     }
+/// }
+///
+/// let (a, b) = wintun::Session::pair().unwrap();
+/// let mut readiness = b.readiness();
+/// let flag = Arc::new(Flag(Default::default()));
+/// let waker = flag.clone().into();
+///
+/// //Nothing queued yet, so the waker is registered
+/// assert!(readiness.poll_ready(&mut Context::from_waker(&waker)).is_pending());
+///
+/// let packet = a.allocate_send_packet(1).unwrap();
+/// a.send_packet(packet);
+/// assert!(flag.0.load(std::sync::atomic::Ordering::SeqCst));
+/// assert!(matches!(readiness.poll_recv(&mut Context::from_waker(&waker)), Poll::Ready(Ok(_))));
+/// ```
+pub struct Readiness {
+    session: Arc<Session>,
+    read: Option<Wait>,
+    shutdown: Option<Wait>,
+}
+
+impl Readiness {
+    /// Returns the session whose events are waited on
+    pub fn session(&self) -> &Arc<Session> {
+        &self.session
+    }
+
+    /// Returns Poll::Ready once the read event was signaled since the last time this returned
+    /// [`Ready::Readable`], or once shutdown is requested. Otherwise the waker of `cx` is woken
+    /// when either happens. Only the waker passed to the most recent call is woken
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<Ready, Error>> {
+        if self.session.is_shut_down() {
+            return Poll::Ready(Ok(Ready::ShuttingDown));
+        }
+        let shutdown = match &mut self.shutdown {
+            Some(shutdown) => shutdown,
+            None => self
+                .shutdown
+                .insert(event::register_wait(self.session.shutdown_event, cx.waker())?),
+        };
+        let read = match &mut self.read {
+            Some(read) => read,
+            None => self
+                .read
+                .insert(event::register_wait(self.session.get_read_wait_event()?, cx.waker())?),
+        };
+        for _ in 0..2 {
+            if shutdown.fired() {
+                return Poll::Ready(Ok(Ready::ShuttingDown));
+            }
+            if read.fired() {
+                //Waits only fire once, so the next call registers a new one
+                self.read = None;
+                return Poll::Ready(Ok(Ready::Readable));
+            }
+            //Set the waker and check again in case a wait fired before it had the new waker
+            shutdown.set_waker(cx.waker());
+            read.set_waker(cx.waker());
+        }
+        Poll::Pending
+    }
+
+    /// Registers `waker` to be woken once the session is ready. Returns Some if it already is, in
+    /// which case `waker` is not woken. Convenience for event loops that do not use [`Context`]
+    pub fn register(&mut self, waker: &Waker) -> Result<Option<Ready>, Error> {
+        match self.poll_ready(&mut Context::from_waker(waker)) {
+            Poll::Ready(ready) => ready.map(Some),
+            Poll::Pending => Ok(None),
+        }
+    }
+
+    /// Receives the next packet, waking the waker of `cx` once one may be available if the
+    /// receive queue is empty. Returns Err([`Error::ShuttingDown`]) once shutdown is requested
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<RecvPacket, Error>> {
+        loop {
+            if let Some(packet) = self.session.try_receive()? {
+                return Poll::Ready(Ok(packet));
+            }
+            match self.poll_ready(cx)? {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ready::Readable) => continue,
+                Poll::Ready(Ready::ShuttingDown) => return Poll::Ready(Err(Error::ShuttingDown)),
+            }
+        }
+    }
+}
+
+impl Session {
+    /// Returns a [`Readiness`] for integrating this session with an executor or event loop
+    pub fn readiness(self: &Arc<Self>) -> Readiness {
+        Readiness {
+            session: self.clone(),
+            read: None,
+            shutdown: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FakeWintun, Wintun, MIN_RING_CAPACITY};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        task::Wake,
+        time::{Duration, Instant},
+    };
+
+    /// Counts how often it was woken
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Counter {
+        fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+
+        /// Waits for the thread pool to wake us `count` times in total
+        fn wait_for(&self, count: usize) {
+            let start = Instant::now();
+            while self.count() < count {
+                assert!(start.elapsed() < Duration::from_secs(10), "Waker was not woken");
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    fn session() -> (Arc<FakeWintun>, Arc<Session>) {
+        let fake = Arc::new(FakeWintun::new());
+        let wintun: Wintun = fake.clone();
+        let adapter = crate::Adapter::create(&wintun, "Test", "Test", None).unwrap();
+        (fake, Arc::new(adapter.start_session(MIN_RING_CAPACITY).unwrap()))
+    }
+
+    fn waker() -> (Arc<Counter>, Waker) {
+        let counter = Arc::new(Counter::default());
+        (counter.clone(), counter.into())
+    }
+
+    #[test]
+    fn wakes_on_read_event() {
+        let (fake, session) = session();
+        let mut readiness = session.readiness();
+        let (counter, waker) = waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(readiness.poll_ready(&mut cx).is_pending());
+        assert_eq!(counter.count(), 0);
+
+        fake.inject_packet(&session, &[1]).unwrap();
+        counter.wait_for(1);
+        assert!(matches!(
+            readiness.poll_ready(&mut cx),
+            Poll::Ready(Ok(Ready::Readable))
+        ));
+        assert_eq!(readiness.register(&waker).unwrap(), None);
+
+        //A new wait is registered for the next packet
+        fake.inject_packet(&session, &[2]).unwrap();
+        counter.wait_for(2);
+        for expected in [1, 2] {
+            match readiness.poll_recv(&mut cx) {
+                Poll::Ready(Ok(packet)) => assert_eq!(packet.bytes(), &[expected]),
+                _ => panic!("Expected packet {}", expected),
+            }
+        }
+        assert!(readiness.poll_recv(&mut cx).is_pending());
+    }
+
+    #[test]
+    fn wakes_on_shutdown() {
+        let (_fake, session) = session();
+        let mut readiness = session.readiness();
+        let (counter, waker) = waker();
+        assert_eq!(readiness.register(&waker).unwrap(), None);
+
+        session.shutdown().unwrap();
+        counter.wait_for(1);
+        assert_eq!(readiness.register(&waker).unwrap(), Some(Ready::ShuttingDown));
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            readiness.poll_recv(&mut cx),
+            Poll::Ready(Err(Error::ShuttingDown))
+        ));
+        //New readiness reports shutdown without registering anything
+        assert_eq!(session.readiness().register(&waker).unwrap(), Some(Ready::ShuttingDown));
+    }
+
+    #[test]
+    fn only_the_latest_waker_is_woken() {
+        let (fake, session) = session();
+        let mut readiness = session.readiness();
+        let (first, first_waker) = waker();
+        let (second, second_waker) = waker();
+        assert_eq!(readiness.register(&first_waker).unwrap(), None);
+        assert_eq!(readiness.register(&second_waker).unwrap(), None);
+
+        fake.inject_packet(&session, &[1]).unwrap();
+        second.wait_for(1);
+        assert_eq!(first.count(), 0);
+    }
+
+    #[test]
+    fn dropping_deregisters() {
+        let (fake, session) = session();
+        let mut readiness = session.readiness();
+        let (counter, waker) = waker();
+        assert_eq!(readiness.register(&waker).unwrap(), None);
+        drop(readiness);
+
+        fake.inject_packet(&session, &[1]).unwrap();
+        //The signal is left for whoever waits next instead of being consumed by the dropped wait
+        let read_event = session.get_read_wait_event().unwrap();
+        assert_eq!(event::wait_any(&[read_event], 1000).unwrap(), Some(0));
+        assert_eq!(counter.count(), 0);
+    }
+}
@@ -0,0 +1,451 @@
+//! Software implementation of the ring buffers shared between wintun.dll and the wintun driver.
+//!
+//! Mirrors the TUN_RING layout used by the driver: a head and tail offset into `capacity` bytes of
+//! data, where every packet is stored behind a 4 byte size header and padded to 4 byte alignment.
+//! Rings are followed by enough trailing bytes that a packet never wraps around the end of the
+//! data, even if it starts right before it.
+
+use crate::{
+    error::{Error, OutOfRangeData},
+    MAX_IP_PACKET_SIZE,
+};
+use windows_sys::Win32::Foundation::{
+    ERROR_BUFFER_OVERFLOW, ERROR_HANDLE_EOF, ERROR_INVALID_DATA, ERROR_INVALID_PARAMETER, ERROR_NO_MORE_ITEMS,
+    WIN32_ERROR,
+};
+
+/// Size of the header in front of every packet, and the alignment of packets in the ring
+const ALIGNMENT: u32 = std::mem::size_of::<u32>() as u32;
+
+/// Set in a packet's size header while it is still owned by the application
+const PACKET_RELEASE: u32 = 0x8000_0000;
+
+/// Space reserved after the end of the ring so that the largest packet never has to wrap
+const TRAILING_BYTES: u32 = align(ALIGNMENT + MAX_IP_PACKET_SIZE);
+
+const fn align(size: u32) -> u32 {
+    (size + (ALIGNMENT - 1)) & !(ALIGNMENT - 1)
+}
+
+/// Checks that `capacity` is a valid ring capacity, as required by WintunStartSession
+pub(crate) fn check_capacity(capacity: u32) -> Result<(), Error> {
+    let range = crate::MIN_RING_CAPACITY..=crate::MAX_RING_CAPACITY;
+    if !range.contains(&capacity) {
+        return Err(Error::CapacityOutOfRange(OutOfRangeData { range, value: capacity }));
+    }
+    if !capacity.is_power_of_two() {
+        return Err(Error::CapacityNotPowerOfTwo(capacity));
+    }
+    Ok(())
+}
+
+/// A single TUN_RING
+struct Ring {
+    /// Offset of the first packet the consumer has not finished with yet
+    head: u32,
+
+    /// Offset one past the last packet the producer has published
+    tail: u32,
+
+    /// Backing memory for `capacity + TRAILING_BYTES` bytes. Stored as u32s so that size headers
+    /// are always aligned
+    data: *mut u32,
+    len: usize,
+}
+
+impl Ring {
+    fn new(capacity: u32) -> Self {
+        let len = ((capacity + TRAILING_BYTES) / ALIGNMENT) as usize;
+        let data = Box::into_raw(vec![0u32; len].into_boxed_slice()) as *mut u32;
+        Self {
+            head: 0,
+            tail: 0,
+            data,
+            len,
+        }
+    }
+
+    fn packet(&self, offset: u32) -> *mut u8 {
+        unsafe { (self.data as *mut u8).add((offset + ALIGNMENT) as usize) }
+    }
+
+    fn header(&self, offset: u32) -> u32 {
+        debug_assert_eq!(offset & (ALIGNMENT - 1), 0);
+        unsafe { self.data.add((offset / ALIGNMENT) as usize).read() }
+    }
+
+    fn set_header(&mut self, offset: u32, value: u32) {
+        debug_assert_eq!(offset & (ALIGNMENT - 1), 0);
+        unsafe { self.data.add((offset / ALIGNMENT) as usize).write(value) };
+    }
+
+    /// Returns the offset of the header belonging to `packet`
+    ///
+    /// # Safety
+    /// `packet` must have been returned by [`Ring::packet`]
+    unsafe fn offset_of(&self, packet: *const u8) -> u32 {
+        (packet.offset_from(self.data as *const u8) as u32) - ALIGNMENT
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(self.data, self.len)) });
+    }
+}
+
+/// The pair of rings behind a wintun session, together with the bookkeeping wintun.dll keeps for
+/// each of them.
+///
+/// The `allocate_send_packet`, `send_packet`, `receive_packet` and `release_receive_packet`
+/// functions behave like their Wintun* counterparts, including failing with ERROR_BUFFER_OVERFLOW
+/// when the send ring is full, ERROR_NO_MORE_ITEMS when the receive ring is empty, and only handing
+/// space back to the other side once every older packet has been sent or released.
+///
+/// The driver's half is played by [`SessionRings::write_packet`], which queues a packet for the
+/// application to receive, and [`SessionRings::read_packet`], which takes a packet the application
+/// has sent.
+pub struct SessionRings {
+    capacity: u32,
+
+    /// Ring written by the driver and read by the application
+    receive: Ring,
+    receive_head: u32,
+    receive_head_release: u32,
+    receive_packets_to_release: u32,
+
+    /// Ring written by the application and read by the driver
+    send: Ring,
+    send_tail: u32,
+    send_tail_release: u32,
+    send_packets_to_release: u32,
+}
+
+/// SAFETY: The rings are only accessed through &mut self, and the packet memory handed out is never
+/// touched by the rings until it is sent or released
+unsafe impl Send for SessionRings {}
+unsafe impl Sync for SessionRings {}
+
+impl SessionRings {
+    /// Creates empty rings of `capacity` bytes each. Capacity must be a power of two between
+    /// [`crate::MIN_RING_CAPACITY`] and [`crate::MAX_RING_CAPACITY`] inclusive.
+    pub fn new(capacity: u32) -> Result<Self, Error> {
+        check_capacity(capacity)?;
+        Ok(Self {
+            capacity,
+            receive: Ring::new(capacity),
+            receive_head: 0,
+            receive_head_release: 0,
+            receive_packets_to_release: 0,
+            send: Ring::new(capacity),
+            send_tail: 0,
+            send_tail_release: 0,
+            send_packets_to_release: 0,
+        })
+    }
+
+    /// Returns the capacity of each ring in bytes
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn wrap(&self, value: u32) -> u32 {
+        value & (self.capacity - 1)
+    }
+
+    /// Reserves space for a packet of `size` bytes at the tail of the send ring. Maps to
+    /// WintunAllocateSendPacket
+    pub fn allocate_send_packet(&mut self, size: u32) -> Result<*mut u8, WIN32_ERROR> {
+        if self.send_tail >= self.capacity || self.send.head >= self.capacity {
+            return Err(ERROR_HANDLE_EOF);
+        }
+        if size > MAX_IP_PACKET_SIZE {
+            return Err(ERROR_INVALID_PARAMETER);
+        }
+        let aligned_size = align(ALIGNMENT + size);
+        let space = self.wrap(self.send.head.wrapping_sub(self.send_tail).wrapping_sub(ALIGNMENT));
+        if aligned_size > space {
+            return Err(ERROR_BUFFER_OVERFLOW);
+        }
+        let offset = self.send_tail;
+        self.send.set_header(offset, size | PACKET_RELEASE);
+        self.send_tail = self.wrap(offset + aligned_size);
+        self.send_packets_to_release += 1;
+        Ok(self.send.packet(offset))
+    }
+
+    /// Publishes a packet allocated with [`SessionRings::allocate_send_packet`] to the driver.
+    /// Maps to WintunSendPacket
+    ///
+    /// Packets become visible to [`SessionRings::read_packet`] in allocation order, so a packet
+    /// that is sent while an older one is still pending waits until the older one is sent too.
+    ///
+    /// # Safety
+    /// `packet` must have been returned by [`SessionRings::allocate_send_packet`] and not sent yet
+    pub unsafe fn send_packet(&mut self, packet: *const u8) {
+        let offset = self.send.offset_of(packet);
+        self.send.set_header(offset, self.send.header(offset) & !PACKET_RELEASE);
+        while self.send_packets_to_release > 0 {
+            let header = self.send.header(self.send_tail_release);
+            if header & PACKET_RELEASE != 0 {
+                break;
+            }
+            self.send_tail_release = self.wrap(self.send_tail_release + align(ALIGNMENT + header));
+            self.send_packets_to_release -= 1;
+        }
+        if self.send.tail < self.capacity {
+            self.send.tail = self.send_tail_release;
+        }
+    }
+
+    /// Takes the packet at the head of the receive ring, returning it together with its size.
+    /// Maps to WintunReceivePacket
+    pub fn receive_packet(&mut self) -> Result<(*mut u8, u32), WIN32_ERROR> {
+        let tail = self.receive.tail;
+        if self.receive_head >= self.capacity || tail >= self.capacity {
+            return Err(ERROR_HANDLE_EOF);
+        }
+        if self.receive_head == tail {
+            return Err(ERROR_NO_MORE_ITEMS);
+        }
+        let content = self.wrap(tail.wrapping_sub(self.receive_head));
+        if content < ALIGNMENT {
+            return Err(ERROR_INVALID_DATA);
+        }
+        let offset = self.receive_head;
+        let size = self.receive.header(offset);
+        if size > MAX_IP_PACKET_SIZE {
+            return Err(ERROR_INVALID_DATA);
+        }
+        let aligned_size = align(ALIGNMENT + size);
+        if aligned_size > content {
+            return Err(ERROR_INVALID_DATA);
+        }
+        self.receive_head = self.wrap(offset + aligned_size);
+        self.receive_packets_to_release += 1;
+        Ok((self.receive.packet(offset), size))
+    }
+
+    /// Hands a received packet back to the ring. Maps to WintunReleaseReceivePacket
+    ///
+    /// Space is returned to the driver in receive order, so releasing a packet while an older one
+    /// is still held does not free any space until the older one is released too.
+    ///
+    /// # Safety
+    /// `packet` must have been returned by [`SessionRings::receive_packet`] and not released yet
+    pub unsafe fn release_receive_packet(&mut self, packet: *const u8) {
+        let offset = self.receive.offset_of(packet);
+        self.receive
+            .set_header(offset, self.receive.header(offset) | PACKET_RELEASE);
+        while self.receive_packets_to_release > 0 {
+            let header = self.receive.header(self.receive_head_release);
+            if header & PACKET_RELEASE == 0 {
+                break;
+            }
+            let aligned_size = align(ALIGNMENT + (header & !PACKET_RELEASE));
+            self.receive_head_release = self.wrap(self.receive_head_release + aligned_size);
+            self.receive_packets_to_release -= 1;
+        }
+        if self.receive.head < self.capacity {
+            self.receive.head = self.receive_head_release;
+        }
+    }
+
+    /// Writes `packet` to the tail of the receive ring as the driver does when the system sends a
+    /// packet through the adapter. Fails with ERROR_BUFFER_OVERFLOW when there is not enough space
+    /// left, in which case the real driver drops the packet.
+    pub fn write_packet(&mut self, packet: &[u8]) -> Result<(), WIN32_ERROR> {
+        let (head, tail) = (self.receive.head, self.receive.tail);
+        if head >= self.capacity || tail >= self.capacity {
+            return Err(ERROR_HANDLE_EOF);
+        }
+        if packet.len() > MAX_IP_PACKET_SIZE as usize {
+            return Err(ERROR_INVALID_PARAMETER);
+        }
+        let size = packet.len() as u32;
+        let aligned_size = align(ALIGNMENT + size);
+        let space = self.wrap(head.wrapping_sub(tail).wrapping_sub(ALIGNMENT));
+        if aligned_size > space {
+            return Err(ERROR_BUFFER_OVERFLOW);
+        }
+        self.receive.set_header(tail, size);
+        //SAFETY: The space between tail and head belongs to the driver, and the trailing bytes
+        //guarantee that the whole packet fits behind tail
+        unsafe { std::ptr::copy_nonoverlapping(packet.as_ptr(), self.receive.packet(tail), packet.len()) };
+        self.receive.tail = self.wrap(tail + aligned_size);
+        Ok(())
+    }
+
+    /// Takes the oldest packet the application has sent, as the driver does when it passes the
+    /// packet on to the system. Returns `None` if no sent packets are waiting.
+    pub fn read_packet(&mut self) -> Option<Vec<u8>> {
+        let (head, tail) = (self.send.head, self.send.tail);
+        if head == tail || head >= self.capacity || tail >= self.capacity {
+            return None;
+        }
+        let size = self.send.header(head);
+        //SAFETY: Every packet between head and tail has been fully written and sent
+        let packet = unsafe { std::slice::from_raw_parts(self.send.packet(head), size as usize) }.to_vec();
+        self.send.head = self.wrap(head + align(ALIGNMENT + size));
+        Some(packet)
+    }
+
+    /// Marks both rings as closed, the way the driver does when the adapter goes away. All further
+    /// allocations and receives fail with ERROR_HANDLE_EOF
+    pub fn close(&mut self) {
+        self.receive.tail = u32::MAX;
+        self.send.head = u32::MAX;
+    }
+
+    /// Returns the number of received packets that have not been released yet
+    pub fn unreleased_packets(&self) -> usize {
+        let mut count = 0;
+        let mut offset = self.receive_head_release;
+        for _ in 0..self.receive_packets_to_release {
+            let header = self.receive.header(offset);
+            if header & PACKET_RELEASE == 0 {
+                count += 1;
+            }
+            offset = self.wrap(offset + align(ALIGNMENT + (header & !PACKET_RELEASE)));
+        }
+        count
+    }
+
+    /// Returns the number of allocated send packets that have not been sent yet
+    pub fn unsent_packets(&self) -> usize {
+        let mut count = 0;
+        let mut offset = self.send_tail_release;
+        for _ in 0..self.send_packets_to_release {
+            let header = self.send.header(offset);
+            if header & PACKET_RELEASE != 0 {
+                count += 1;
+            }
+            offset = self.wrap(offset + align(ALIGNMENT + (header & !PACKET_RELEASE)));
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MIN_RING_CAPACITY;
+
+    fn rings() -> SessionRings {
+        SessionRings::new(MIN_RING_CAPACITY).unwrap()
+    }
+
+    fn receive(rings: &mut SessionRings) -> (*mut u8, Vec<u8>) {
+        let (ptr, size) = rings.receive_packet().unwrap();
+        (ptr, unsafe { std::slice::from_raw_parts(ptr, size as usize) }.to_vec())
+    }
+
+    fn send(rings: &mut SessionRings, bytes: &[u8]) {
+        let ptr = rings.allocate_send_packet(bytes.len() as u32).unwrap();
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+            rings.send_packet(ptr);
+        }
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut rings = rings();
+        //Odd sizes so that packets start at every aligned offset and straddle the end of the ring
+        for i in 0..1000u32 {
+            let packet: Vec<u8> = (0..1000 + i % 7).map(|j| (i + j) as u8).collect();
+
+            rings.write_packet(&packet).unwrap();
+            let (ptr, received) = receive(&mut rings);
+            assert_eq!(received, packet);
+            unsafe { rings.release_receive_packet(ptr) };
+
+            send(&mut rings, &packet);
+            assert_eq!(rings.read_packet().unwrap(), packet);
+        }
+        assert_eq!(rings.receive_packet(), Err(ERROR_NO_MORE_ITEMS));
+        assert_eq!(rings.read_packet(), None);
+    }
+
+    #[test]
+    fn full() {
+        let mut rings = rings();
+        let packet = vec![1; MAX_IP_PACKET_SIZE as usize];
+        //One aligned slot is always kept free, so a second packet of the largest size does not fit
+        rings.write_packet(&packet).unwrap();
+        assert_eq!(rings.write_packet(&packet), Err(ERROR_BUFFER_OVERFLOW));
+        send(&mut rings, &packet);
+        assert_eq!(
+            rings.allocate_send_packet(MAX_IP_PACKET_SIZE),
+            Err(ERROR_BUFFER_OVERFLOW)
+        );
+        assert_eq!(
+            rings.allocate_send_packet(MAX_IP_PACKET_SIZE + 1),
+            Err(ERROR_INVALID_PARAMETER)
+        );
+
+        //Space comes back once the other side is done with the packet
+        let (ptr, _) = receive(&mut rings);
+        assert_eq!(rings.write_packet(&packet), Err(ERROR_BUFFER_OVERFLOW));
+        unsafe { rings.release_receive_packet(ptr) };
+        rings.write_packet(&packet).unwrap();
+        assert_eq!(rings.read_packet().unwrap(), packet);
+        send(&mut rings, &packet);
+    }
+
+    #[test]
+    fn out_of_order_release() {
+        let mut rings = rings();
+        let packet = vec![2; 0x8000];
+        for _ in 0..3 {
+            rings.write_packet(&packet).unwrap();
+        }
+        assert_eq!(rings.write_packet(&packet), Err(ERROR_BUFFER_OVERFLOW));
+        let (a, _) = receive(&mut rings);
+        let (b, _) = receive(&mut rings);
+        let (c, _) = receive(&mut rings);
+        assert_eq!(rings.unreleased_packets(), 3);
+
+        //Releasing a newer packet frees nothing while an older one is held
+        unsafe { rings.release_receive_packet(b) };
+        assert_eq!(rings.unreleased_packets(), 2);
+        assert_eq!(rings.write_packet(&packet), Err(ERROR_BUFFER_OVERFLOW));
+
+        unsafe { rings.release_receive_packet(a) };
+        assert_eq!(rings.unreleased_packets(), 1);
+        rings.write_packet(&packet).unwrap();
+        rings.write_packet(&packet).unwrap();
+        assert_eq!(rings.write_packet(&packet), Err(ERROR_BUFFER_OVERFLOW));
+        unsafe { rings.release_receive_packet(c) };
+        assert_eq!(rings.unreleased_packets(), 0);
+    }
+
+    #[test]
+    fn out_of_order_send() {
+        let mut rings = rings();
+        let a = rings.allocate_send_packet(1).unwrap();
+        let b = rings.allocate_send_packet(2).unwrap();
+        unsafe {
+            a.write(1);
+            b.copy_from_nonoverlapping([2, 2].as_ptr(), 2);
+            rings.send_packet(b);
+        }
+        //The newer packet waits for the older one
+        assert_eq!(rings.unsent_packets(), 1);
+        assert_eq!(rings.read_packet(), None);
+        unsafe { rings.send_packet(a) };
+        assert_eq!(rings.unsent_packets(), 0);
+        assert_eq!(rings.read_packet(), Some(vec![1]));
+        assert_eq!(rings.read_packet(), Some(vec![2, 2]));
+    }
+
+    #[test]
+    fn closed() {
+        let mut rings = rings();
+        rings.write_packet(&[1]).unwrap();
+        rings.close();
+        assert_eq!(rings.receive_packet(), Err(ERROR_HANDLE_EOF));
+        assert_eq!(rings.allocate_send_packet(1), Err(ERROR_HANDLE_EOF));
+        assert_eq!(rings.write_packet(&[1]), Err(ERROR_HANDLE_EOF));
+    }
+}
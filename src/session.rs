@@ -1,20 +1,23 @@
 use crate::{
-    packet,
-    util::{self, UnsafeHandle},
-    wintun_raw, Adapter, Error, Wintun,
+    event, metrics,
+    packet::{self, OwnedPacket, RecvPacket, SendPacket, UnsentPacketPolicy},
+    stats::Counters,
+    util::UnsafeHandle,
+    Adapter, Error, FakeWintun, SessionHandle, Wintun,
 };
-use std::{ptr, slice, sync::Arc, sync::OnceLock};
-use windows_sys::Win32::{
-    Foundation::{
-        CloseHandle, GetLastError, ERROR_NO_MORE_ITEMS, FALSE, HANDLE, WAIT_EVENT, WAIT_FAILED, WAIT_OBJECT_0,
-    },
-    System::Threading::{SetEvent, WaitForMultipleObjects, INFINITE},
+use std::{
+    ptr, slice,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    sync::Arc,
+    sync::OnceLock,
+    time::{Duration, Instant},
 };
+use windows_sys::Win32::Foundation::{ERROR_INVALID_PARAMETER, ERROR_NO_MORE_ITEMS, HANDLE};
 
 /// Wrapper around a <https://git.zx2c4.com/wintun/about/#wintun_session_handle>
 pub struct Session {
     /// The session handle given to us by WintunStartSession
-    pub(crate) session: UnsafeHandle<wintun_raw::WINTUN_SESSION_HANDLE>,
+    pub(crate) session: UnsafeHandle<SessionHandle>,
 
     /// Shared dll for required wintun driver functions
     pub(crate) wintun: Wintun,
@@ -24,14 +27,58 @@ pub struct Session {
     pub(crate) read_event: OnceLock<HANDLE>,
 
     /// Windows event handle that is signaled when [`Session::shutdown`] is called force blocking
-    /// readers to exit
+    /// readers to exit. Manual reset, so it stays signaled
     pub(crate) shutdown_event: HANDLE,
 
+    /// Set by [`Session::shutdown`]
+    pub(crate) shut_down: AtomicBool,
+
+    /// The [`UnsentPacketPolicy`] as a u8
+    pub(crate) unsent_policy: AtomicU8,
+
+    /// Traffic counters returned by [`Session::stats`]. Weakly shared with the registry behind
+    /// [`crate::render_prometheus_metrics`]
+    pub(crate) counters: Arc<Counters>,
+
     /// The adapter that owns this session
     pub(crate) adapter: Arc<Adapter>,
 }
 
+/// Ring capacity of the sessions created by [`Session::pair`]
+const PAIR_RING_CAPACITY: u32 = 0x10_0000;
+
+/// How long to wait before checking again for space in a full send ring. Wintun does not signal
+/// when the driver frees up space, so senders have to poll
+pub(crate) const SEND_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
 impl Session {
+    /// Creates two connected in memory sessions: every packet sent through one is received by the
+    /// other. Backed by a [`FakeWintun`], so neither the driver nor administrator rights are needed.
+    /// Packets follow the same rules as with a real session
+    ///
+    /// # Example
+    /// ```
+    /// let (a, b) = wintun::Session::pair().unwrap();
+    ///
+    /// let mut packet = a.allocate_send_packet(3).unwrap();
+    /// packet.bytes_mut().copy_from_slice(&[1, 2, 3]);
+    /// a.send_packet(packet);
+    ///
+    /// assert_eq!(b.receive_blocking().unwrap().bytes(), &[1, 2, 3]);
+    /// assert!(a.try_receive().unwrap().is_none());
+    /// ```
+    pub fn pair() -> Result<(Arc<Session>, Arc<Session>), Error> {
+        let fake = Arc::new(FakeWintun::new());
+        let wintun: Wintun = fake.clone();
+        let start = |name| -> Result<_, Error> {
+            let adapter = Adapter::create(&wintun, name, "Loopback", None)?;
+            Ok(Arc::new(adapter.start_session(PAIR_RING_CAPACITY)?))
+        };
+        let (a, b) = (start("Pair A")?, start("Pair B")?);
+        fake.connect_sessions(&a, &b)?;
+        Ok((a, b))
+    }
+
     pub fn get_adapter(&self) -> Arc<Adapter> {
         self.adapter.clone()
     }
@@ -43,54 +90,245 @@ impl Session {
     /// Therefore if a packet is allocated using this function, and then never sent, it will hold
     /// up the send queue for all other packets allocated in the future. It is okay for the session
     /// to shutdown with allocated packets that have not yet been sent
-    pub fn allocate_send_packet(self: &Arc<Self>, size: u16) -> Result<packet::Packet, Error> {
-        let ptr = unsafe { self.wintun.WintunAllocateSendPacket(self.session.0, size as u32) };
-        if ptr.is_null() {
-            Err(util::get_last_error()?.into())
-        } else {
-            Ok(packet::Packet {
-                //SAFETY: ptr is non null, aligned for u8, and readable for up to size bytes (which
-                //must be less than isize::MAX because bytes is a u16
-                bytes: unsafe { slice::from_raw_parts_mut(ptr, size as usize) },
-                session: self.clone(),
-                kind: packet::Kind::SendPacketPending,
-            })
+    ///
+    /// Returns Err([`Error::RingFull`]) if the send ring has no room for the packet, and
+    /// Err([`Error::ShuttingDown`]) after [`Session::shutdown`] is called
+    pub fn allocate_send_packet(self: &Arc<Self>, size: u16) -> Result<SendPacket, Error> {
+        let ptr = self.allocate_raw(size)?;
+        Ok(SendPacket::new(
+            //SAFETY: ptr is non null, aligned for u8, and readable for up to size bytes (which
+            //must be less than isize::MAX because bytes is a u16
+            unsafe { slice::from_raw_parts_mut(ptr, size as usize) },
+            packet::Owner::Session(self.clone()),
+        ))
+    }
+
+    /// Allocates `size` bytes in the send ring, which must be sent before the session is dropped
+    pub(crate) fn allocate_raw(&self, size: u16) -> Result<*mut u8, Error> {
+        if self.is_shut_down() {
+            return Err(Error::ShuttingDown);
         }
+        self.allocate_unchecked(size)
     }
 
-    /// Sends a packet previously allocated with [`Session::allocate_send_packet`]
-    pub fn send_packet(&self, mut packet: packet::Packet) {
-        assert!(matches!(packet.kind, packet::Kind::SendPacketPending));
+    /// Like [`Session::allocate_raw`] without checking for shutdown
+    fn allocate_unchecked(&self, size: u16) -> Result<*mut u8, Error> {
+        unsafe { self.wintun.allocate_send_packet(self.session.0, size as u32) }.map_err(|e| {
+            let err = Error::wintun_packet("WintunAllocateSendPacket", e, size as usize);
+            self.counters.allocation_failed(&err);
+            err
+        })
+    }
 
-        unsafe { self.wintun.WintunSendPacket(self.session.0, packet.bytes.as_ptr()) };
-        //Mark the packet at sent
-        packet.kind = packet::Kind::SendPacketSent;
+    /// Submits `len` bytes at `ptr`, which were allocated in the send ring of this session
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by [`Session::allocate_raw`] and not submitted yet
+    pub(crate) unsafe fn submit(&self, ptr: *const u8, len: usize) {
+        self.wintun.send_packet(self.session.0, ptr);
+        self.counters.sent(len);
+    }
+
+    /// Converts the length of a packet to the size passed to WintunAllocateSendPacket, failing with
+    /// [`Error::InvalidPacketSize`] if it is larger than [`crate::MAX_IP_PACKET_SIZE`]
+    pub(crate) fn packet_size(&self, len: usize) -> Result<u16, Error> {
+        match u16::try_from(len) {
+            Ok(size) if size <= crate::MAX_IP_PACKET_SIZE as u16 => Ok(size),
+            _ => {
+                self.counters.oversized_packets.fetch_add(1, Ordering::Relaxed);
+                Err(Error::InvalidPacketSize {
+                    operation: "WintunAllocateSendPacket",
+                    size: len,
+                    code: ERROR_INVALID_PARAMETER,
+                })
+            }
+        }
+    }
+
+    /// Like [`Session::allocate_send_packet`], but waits for space to free up if the send ring is
+    /// full. Returns Err([`Error::ShuttingDown`]) if [`Session::shutdown`] is called while waiting
+    pub fn allocate_send_packet_blocking(self: &Arc<Self>, size: u16) -> Result<SendPacket, Error> {
+        match self.allocate_send_packet_until(size, None)? {
+            Some(packet) => Ok(packet),
+            None => unreachable!("An infinite wait cannot time out"),
+        }
+    }
+
+    /// Like [`Session::allocate_send_packet_blocking`], but gives up and returns Ok(None) if the
+    /// send ring is still full after `timeout`
+    pub fn allocate_send_packet_timeout(
+        self: &Arc<Self>,
+        size: u16,
+        timeout: Duration,
+    ) -> Result<Option<SendPacket>, Error> {
+        self.allocate_send_packet_until(size, Instant::now().checked_add(timeout))
+    }
+
+    fn allocate_send_packet_until(
+        self: &Arc<Self>,
+        size: u16,
+        deadline: Option<Instant>,
+    ) -> Result<Option<SendPacket>, Error> {
+        let retry_ms = SEND_RETRY_INTERVAL.as_millis() as u32;
+        let mut blocked = false;
+        loop {
+            match self.allocate_send_packet(size) {
+                Ok(packet) => return Ok(Some(packet)),
+                Err(Error::RingFull { .. }) => {}
+                Err(e) => return Err(e),
+            }
+            let timeout = match event::timeout_ms(deadline) {
+                0 => return Ok(None),
+                remaining => remaining.min(retry_ms),
+            };
+            //Wintun has no event for space in the send ring, so poll, waiting on the shutdown
+            //event so that shutdown interrupts us. The whole call counts as one blocked wait
+            if !blocked {
+                blocked = true;
+                self.counters.blocked_waits.fetch_add(1, Ordering::Relaxed);
+            }
+            if event::wait_any(&[self.shutdown_event], timeout)?.is_some() {
+                self.counters.shutdown_wakeups.fetch_add(1, Ordering::Relaxed);
+                return Err(Error::ShuttingDown);
+            }
+        }
+    }
+
+    /// Sends a packet previously allocated with [`Session::allocate_send_packet`] on this session
+    pub fn send_packet(&self, packet: SendPacket) {
+        assert!(packet.belongs_to(self), "Packet was not allocated by this session");
+
+        let data = packet.into_data();
+        unsafe { self.submit(data.bytes.as_ptr(), data.bytes.len()) };
+    }
+
+    /// Sends a copy of `packet`. Fails with [`Error::RingFull`] if the send ring has no room for it,
+    /// in which case it can be sent again later
+    pub fn send_owned(&self, packet: &OwnedPacket) -> Result<(), Error> {
+        let bytes = packet.bytes();
+        let size = self.packet_size(bytes.len())?;
+        let ptr = self.allocate_raw(size)?;
+        //SAFETY: ptr is writable for bytes.len() bytes and does not overlap bytes
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+            self.submit(ptr, bytes.len());
+        }
+        Ok(())
+    }
+
+    /// Sends each of `packets` in order, returning how many were sent.
+    ///
+    /// Space for the whole batch is reserved in the send ring first, then the packets are copied
+    /// and submitted in order. Sending is not all or nothing: wintun cannot take back reserved
+    /// space, so if the send ring fills up partway through, the packets that got space are still
+    /// sent and their count is returned. The remaining packets can be retried later, which may mean
+    /// that Ok(0) is returned. Any other allocation failure, such as [`Error::AdapterGone`], is
+    /// returned even though the packets reserved before it were sent. Fails without sending
+    /// anything if a packet is larger than [`crate::MAX_IP_PACKET_SIZE`]
+    pub fn send_batch(&self, packets: &[&[u8]]) -> Result<usize, Error> {
+        if self.is_shut_down() {
+            return Err(Error::ShuttingDown);
+        }
+        let sizes = packets
+            .iter()
+            .map(|packet| self.packet_size(packet.len()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut reserved = Vec::with_capacity(packets.len());
+        let mut error = None;
+        for size in sizes {
+            match self.allocate_unchecked(size) {
+                Ok(ptr) => reserved.push(ptr),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        //Packets that were allocated must be sent, otherwise they hold up the send queue
+        for (ptr, bytes) in reserved.iter().zip(packets) {
+            //SAFETY: ptr is writable for bytes.len() bytes and does not overlap bytes
+            unsafe {
+                ptr::copy_nonoverlapping(bytes.as_ptr(), *ptr, bytes.len());
+                self.submit(*ptr, bytes.len());
+            }
+        }
+        match error {
+            None | Some(Error::RingFull { .. }) => Ok(reserved.len()),
+            Some(e) => Err(e),
+        }
     }
 
     /// Attempts to receive a packet from the virtual interface without blocking.
     /// If there are no packets currently in the receive queue, this function returns Ok(None)
     /// without blocking. If blocking until a packet is desirable, use [`Session::receive_blocking`]
-    pub fn try_receive(self: &Arc<Self>) -> Result<Option<packet::Packet>, Error> {
-        let mut size = 0u32;
+    ///
+    /// Returns Err([`Error::ShuttingDown`]) after [`Session::shutdown`] is called
+    pub fn try_receive(self: &Arc<Self>) -> Result<Option<RecvPacket>, Error> {
+        if self.is_shut_down() {
+            return Err(Error::ShuttingDown);
+        }
+        self.receive_packet()
+    }
 
-        let ptr = unsafe { self.wintun.WintunReceivePacket(self.session.0, &mut size as *mut u32) };
+    /// Receives the next packet from the ring without checking for shutdown
+    fn receive_packet(self: &Arc<Self>) -> Result<Option<RecvPacket>, Error> {
+        Ok(self.receive_raw()?.map(|(ptr, size)| {
+            RecvPacket::new(
+                //SAFETY: ptr is non null, aligned for u8, and readable for up to size bytes (which
+                //must be less than isize::MAX because bytes is a u16
+                unsafe { slice::from_raw_parts_mut(ptr, size) },
+                packet::Owner::Session(self.clone()),
+            )
+        }))
+    }
 
-        debug_assert!(size <= u16::MAX as u32);
-        if ptr.is_null() {
+    /// Takes the next packet out of the receive ring without checking for shutdown, returning its
+    /// address and size. It must be released before the session is dropped
+    pub(crate) fn receive_raw(&self) -> Result<Option<(*mut u8, usize)>, Error> {
+        match unsafe { self.wintun.receive_packet(self.session.0) } {
             //Wintun returns ERROR_NO_MORE_ITEMS instead of blocking if packets are not available
-            match unsafe { GetLastError() } {
-                ERROR_NO_MORE_ITEMS => Ok(None),
-                e => Err(std::io::Error::from_raw_os_error(e as i32).into()),
+            Err(ERROR_NO_MORE_ITEMS) => Ok(None),
+            Err(e) => Err(Error::wintun("WintunReceivePacket", e)),
+            Ok((ptr, size)) => {
+                debug_assert!(size <= u16::MAX as u32);
+                self.counters.received(size as usize);
+                Ok(Some((ptr, size as usize)))
+            }
+        }
+    }
+
+    /// Moves up to `max` packets that are already in the receive queue to the end of `packets`
+    /// without blocking, returning how many were received.
+    ///
+    /// Returns Err([`Error::ShuttingDown`]) after [`Session::shutdown`] is called
+    pub fn receive_batch(self: &Arc<Self>, packets: &mut Vec<RecvPacket>, max: usize) -> Result<usize, Error> {
+        if self.is_shut_down() {
+            return Err(Error::ShuttingDown);
+        }
+        let start = packets.len();
+        while packets.len() - start < max {
+            match self.receive_packet() {
+                Ok(Some(packet)) => packets.push(packet),
+                Ok(None) => break,
+                //Keep the packets we already have, the error will most likely be reported again
+                //on the next call
+                Err(_) if packets.len() > start => break,
+                Err(err) => return Err(err),
             }
-        } else {
-            Ok(Some(packet::Packet {
-                kind: packet::Kind::ReceivePacket,
-                //SAFETY: ptr is non null, aligned for u8, and readable for up to size bytes (which
-                //must be less than isize::MAX because bytes is a u16
-                bytes: unsafe { slice::from_raw_parts_mut(ptr, size as usize) },
-                session: self.clone(),
-            }))
         }
+        Ok(packets.len() - start)
+    }
+
+    /// Blocks until at least one packet is available like [`Session::receive_blocking`], then
+    /// moves it and up to `max - 1` more queued packets to the end of `packets`, returning how many
+    /// were received. Returns Ok(0) immediately if `max` is zero
+    pub fn receive_batch_blocking(self: &Arc<Self>, packets: &mut Vec<RecvPacket>, max: usize) -> Result<usize, Error> {
+        if max == 0 {
+            return Ok(0);
+        }
+        packets.push(self.receive_blocking()?);
+        //We already have a packet, so leave errors to be reported by the next call
+        Ok(1 + self.receive_batch(packets, max - 1).unwrap_or(0))
     }
 
     /// Returns the low level read event handle that is signaled when more data becomes available
@@ -98,20 +336,45 @@ impl Session {
     pub fn get_read_wait_event(&self) -> Result<HANDLE, Error> {
         Ok(*self
             .read_event
-            .get_or_init(|| unsafe { self.wintun.WintunGetReadWaitEvent(self.session.0) as _ }))
+            .get_or_init(|| unsafe { self.wintun.get_read_wait_event(self.session.0) }))
     }
 
     /// Blocks until a packet is available, returning the next packet in the receive queue once this happens.
-    /// If the session is closed via [`Session::shutdown`] all threads currently blocking inside this function
-    /// will return Err(())
-    pub fn receive_blocking(self: &Arc<Self>) -> Result<packet::Packet, Error> {
+    /// If the session is closed via [`Session::shutdown`] all threads currently blocking inside this function,
+    /// and any that call it later, will return Err([`Error::ShuttingDown`])
+    pub fn receive_blocking(self: &Arc<Self>) -> Result<RecvPacket, Error> {
+        match self.receive_until(None, || self.try_receive())? {
+            Some(packet) => Ok(packet),
+            None => unreachable!("An infinite wait cannot time out"),
+        }
+    }
+
+    /// Like [`Session::receive_blocking`], but gives up and returns Ok(None) if no packet arrives
+    /// within `timeout`
+    pub fn receive_timeout(self: &Arc<Self>, timeout: Duration) -> Result<Option<RecvPacket>, Error> {
+        //A timeout too large to represent is as good as waiting forever
+        self.receive_until(Instant::now().checked_add(timeout), || self.try_receive())
+    }
+
+    /// Like [`Session::receive_blocking`], but gives up and returns Ok(None) if no packet arrives
+    /// before `deadline`
+    pub fn receive_deadline(self: &Arc<Self>, deadline: Instant) -> Result<Option<RecvPacket>, Error> {
+        self.receive_until(Some(deadline), || self.try_receive())
+    }
+
+    /// Calls `try_receive` until it returns a packet, waiting for the read event in between
+    pub(crate) fn receive_until<P>(
+        &self,
+        deadline: Option<Instant>,
+        mut try_receive: impl FnMut() -> Result<Option<P>, Error>,
+    ) -> Result<Option<P>, Error> {
         loop {
             //Try 5 times to receive without blocking so we don't have to issue a syscall to wait
             //for the event if packets are being received at a rapid rate
             for _ in 0..5 {
-                match self.try_receive() {
+                match try_receive() {
                     Err(err) => return Err(err),
-                    Ok(Some(packet)) => return Ok(packet),
+                    Ok(Some(packet)) => return Ok(Some(packet)),
                     Ok(None) => {
                         //Try again
                         continue;
@@ -120,47 +383,392 @@ impl Session {
             }
             //Wait on both the read handle and the shutdown handle so that we stop when requested
             let handles = [self.get_read_wait_event()?, self.shutdown_event];
-            let result = unsafe {
-                //SAFETY: We abide by the requirements of WaitForMultipleObjects, handles is a
-                //pointer to valid, aligned, stack memory
-                WaitForMultipleObjects(handles.len() as u32, &handles as _, FALSE, INFINITE)
-            };
-            const WAIT_OBJECT_1: WAIT_EVENT = WAIT_OBJECT_0 + 1;
-            match result {
-                WAIT_FAILED => return Err(util::get_last_error()?.into()),
-                WAIT_OBJECT_0 => {
+            self.counters.blocked_waits.fetch_add(1, Ordering::Relaxed);
+            match event::wait_any(&handles, event::timeout_ms(deadline))? {
+                Some(0) => {
                     //We have data!
                     continue;
                 }
-                WAIT_OBJECT_1 => {
+                Some(_) => {
                     //Shutdown event triggered
+                    self.counters.shutdown_wakeups.fetch_add(1, Ordering::Relaxed);
                     return Err(Error::ShuttingDown);
                 }
-                _ => {
-                    //This should never happen
-                    panic!("WaitForMultipleObjects returned unexpected value {:?}", result);
+                None if event::timeout_ms(deadline) > 0 => {
+                    //Waits are capped at about 49 days, so a later deadline has not passed yet
+                    continue;
+                }
+                None => {
+                    //Timed out
+                    return Ok(None);
                 }
             }
         }
     }
 
     /// Cancels any active calls to [`Session::receive_blocking`] making them instantly return Err(_) so that session can be shutdown cleanly
+    ///
+    /// Shutdown is permanent: afterwards receiving and allocating send packets fail with
+    /// [`Error::ShuttingDown`]. Packets that were already allocated can still be sent
     pub fn shutdown(&self) -> Result<(), Error> {
-        if FALSE == unsafe { SetEvent(self.shutdown_event) } {
-            return Err(util::get_last_error()?.into());
+        self.shut_down.store(true, Ordering::SeqCst);
+        Ok(event::set(self.shutdown_event)?)
+    }
+
+    /// Returns true once [`Session::shutdown`] has been called
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Sets what happens to send packets allocated by this session that are dropped without being
+    /// sent. Defaults to [`UnsentPacketPolicy::default`]
+    pub fn set_unsent_packet_policy(&self, policy: UnsentPacketPolicy) {
+        self.unsent_policy.store(policy as u8, Ordering::Relaxed);
+    }
+
+    /// Returns what happens to send packets that are dropped without being sent
+    pub fn unsent_packet_policy(&self) -> UnsentPacketPolicy {
+        match self.unsent_policy.load(Ordering::Relaxed) {
+            p if p == UnsentPacketPolicy::Submit as u8 => UnsentPacketPolicy::Submit,
+            p if p == UnsentPacketPolicy::Panic as u8 => UnsentPacketPolicy::Panic,
+            _ => UnsentPacketPolicy::Count,
         }
-        Ok(())
+    }
+
+    /// Returns how many send packets allocated by this session were dropped without being sent
+    pub fn unsent_packets_dropped(&self) -> u64 {
+        self.counters.unsent_packets_dropped.load(Ordering::Relaxed)
     }
 }
 
 impl Drop for Session {
     fn drop(&mut self) {
-        if FALSE == unsafe { CloseHandle(self.shutdown_event) } {
-            let err = util::get_last_error();
+        metrics::retire(&self.counters, &self.adapter);
+
+        if let Err(err) = event::close(self.shutdown_event) {
             log::error!("Failed to close handle of shutdown event: {:?}", err);
         }
 
-        unsafe { self.wintun.WintunEndSession(self.session.0) };
+        unsafe { self.wintun.end_session(self.session.0) };
         self.session.0 = ptr::null_mut();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FakeCall, MIN_RING_CAPACITY};
+    use windows_sys::Win32::Foundation::{ERROR_HANDLE_EOF, ERROR_INVALID_DATA};
+
+    /// Starts a session with the smallest ring on a new fake adapter
+    fn session() -> (Arc<FakeWintun>, Arc<Session>) {
+        let fake = Arc::new(FakeWintun::new());
+        let wintun: Wintun = fake.clone();
+        let adapter = Adapter::create(&wintun, "Test", "Test", None).unwrap();
+        (fake, Arc::new(adapter.start_session(MIN_RING_CAPACITY).unwrap()))
+    }
+
+    #[test]
+    fn shutdown_wakes_every_blocked_reader() {
+        let (_fake, session) = session();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let session = session.clone();
+                std::thread::spawn(move || session.receive_blocking().map(drop))
+            })
+            .collect();
+        //Give the readers time to block
+        std::thread::sleep(Duration::from_millis(50));
+        session.shutdown().unwrap();
+        for reader in readers {
+            assert!(matches!(reader.join().unwrap(), Err(Error::ShuttingDown)));
+        }
+    }
+
+    #[test]
+    fn shutdown_is_sticky() {
+        let (fake, session) = session();
+        fake.inject_packet(&session, &[1, 2, 3]).unwrap();
+        let packet = session.allocate_send_packet(2).unwrap();
+        session.shutdown().unwrap();
+        session.shutdown().unwrap();
+        assert!(session.is_shut_down());
+
+        assert!(matches!(session.receive_blocking(), Err(Error::ShuttingDown)));
+        assert!(matches!(session.try_receive(), Err(Error::ShuttingDown)));
+        assert!(matches!(session.allocate_send_packet(2), Err(Error::ShuttingDown)));
+        //Packets allocated before the shutdown can still be sent
+        session.send_packet(packet);
+        assert_eq!(fake.take_sent_packets(&session).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn receive_timeout() {
+        let (fake, session) = session();
+        let start = Instant::now();
+        assert!(session.receive_timeout(Duration::from_millis(50)).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        fake.inject_packet(&session, &[1, 2, 3]).unwrap();
+        let packet = session.receive_timeout(Duration::from_secs(10)).unwrap().unwrap();
+        assert_eq!(packet.bytes(), &[1, 2, 3]);
+        //Deadlines in the past still pick up packets that are already queued
+        fake.inject_packet(&session, &[4]).unwrap();
+        assert_eq!(session.receive_deadline(start).unwrap().unwrap().bytes(), &[4]);
+        assert!(session.receive_deadline(Instant::now()).unwrap().is_none());
+
+        //A timeout too large for an Instant waits forever instead of overflowing
+        let receiver = session.clone();
+        let reader = std::thread::spawn(move || receiver.receive_timeout(Duration::MAX).map(drop));
+        std::thread::sleep(Duration::from_millis(50));
+        session.shutdown().unwrap();
+        assert!(matches!(reader.join().unwrap(), Err(Error::ShuttingDown)));
+    }
+
+    fn contents(packets: &[RecvPacket]) -> Vec<&[u8]> {
+        packets.iter().map(|packet| packet.bytes()).collect()
+    }
+
+    #[test]
+    fn receive_batch() {
+        let (fake, session) = session();
+        for i in 0..5 {
+            fake.inject_packet(&session, &[i]).unwrap();
+        }
+        let mut packets = Vec::new();
+        assert_eq!(session.receive_batch(&mut packets, 0).unwrap(), 0);
+        assert_eq!(session.receive_batch(&mut packets, 3).unwrap(), 3);
+        assert_eq!(contents(&packets), [[0], [1], [2]]);
+
+        //Fewer packets than max are queued, and they are appended
+        assert_eq!(session.receive_batch(&mut packets, 10).unwrap(), 2);
+        assert_eq!(contents(&packets), [[0], [1], [2], [3], [4]]);
+        assert_eq!(session.receive_batch(&mut packets, 10).unwrap(), 0);
+        assert_eq!(fake.unreleased_packets(&session).unwrap(), 5);
+        drop(packets);
+        assert_eq!(fake.unreleased_packets(&session).unwrap(), 0);
+    }
+
+    #[test]
+    fn receive_batch_keeps_packets_received_before_an_error() {
+        let (fake, session) = session();
+        for i in 0..3 {
+            fake.inject_packet(&session, &[i]).unwrap();
+        }
+        let mut packets = Vec::new();
+        fake.fail_after(FakeCall::ReceivePacket, 1, ERROR_INVALID_DATA);
+        assert_eq!(session.receive_batch(&mut packets, 10).unwrap(), 1);
+        assert_eq!(session.receive_batch(&mut packets, 10).unwrap(), 2);
+        assert_eq!(contents(&packets), [[0], [1], [2]]);
+
+        //Errors are returned when nothing was received
+        fake.inject_packet(&session, &[3]).unwrap();
+        fake.fail_next(FakeCall::ReceivePacket, ERROR_INVALID_DATA);
+        assert!(matches!(
+            session.receive_batch(&mut packets, 10),
+            Err(Error::Win32 {
+                code: ERROR_INVALID_DATA,
+                ..
+            })
+        ));
+        assert_eq!(packets.len(), 3);
+    }
+
+    #[test]
+    fn receive_batch_blocking() {
+        let (fake, session) = session();
+        let mut packets = Vec::new();
+        assert_eq!(session.receive_batch_blocking(&mut packets, 0).unwrap(), 0);
+
+        //Waits for the first packet
+        let receiver = session.clone();
+        let reader = std::thread::spawn(move || {
+            let mut packets = Vec::new();
+            receiver.receive_batch_blocking(&mut packets, 2).map(|_| packets.len())
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        fake.inject_packet(&session, &[0]).unwrap();
+        assert_eq!(reader.join().unwrap().unwrap(), 1);
+
+        //Then drains up to max
+        for i in 1..4 {
+            fake.inject_packet(&session, &[i]).unwrap();
+        }
+        assert_eq!(session.receive_batch_blocking(&mut packets, 2).unwrap(), 2);
+        assert_eq!(session.receive_batch_blocking(&mut packets, 2).unwrap(), 1);
+        assert_eq!(contents(&packets), [[1], [2], [3]]);
+    }
+
+    #[test]
+    fn receive_batch_blocking_shutdown() {
+        let (_fake, session) = session();
+        let receiver = session.clone();
+        let reader = std::thread::spawn(move || {
+            let mut packets = Vec::new();
+            receiver.receive_batch_blocking(&mut packets, 8).map(|_| packets.len())
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        session.shutdown().unwrap();
+        assert!(matches!(reader.join().unwrap(), Err(Error::ShuttingDown)));
+        assert!(matches!(
+            session.receive_batch(&mut Vec::new(), 8),
+            Err(Error::ShuttingDown)
+        ));
+    }
+
+    #[test]
+    fn send_batch_partially_fills_the_ring() {
+        let (fake, session) = session();
+        let large = vec![7; crate::MAX_IP_PACKET_SIZE as usize];
+        let small = [1, 2, 3];
+        //Only one packet of the largest size fits in the smallest ring
+        assert_eq!(session.send_batch(&[&small, &large, &large, &small]).unwrap(), 2);
+        assert_eq!(
+            fake.take_sent_packets(&session).unwrap(),
+            vec![small.to_vec(), large.clone()]
+        );
+
+        //Nothing fits until the driver takes the packets, and then the rest can be retried
+        session.send_batch(&[&large]).unwrap();
+        assert_eq!(session.send_batch(&[&large, &small]).unwrap(), 0);
+        fake.take_sent_packets(&session).unwrap();
+        assert_eq!(session.send_batch(&[&large, &small]).unwrap(), 2);
+        assert_eq!(fake.unsent_packets(&session).unwrap(), 0);
+
+        //Oversized packets fail the whole batch before anything is allocated
+        let oversized = vec![0; crate::MAX_IP_PACKET_SIZE as usize + 1];
+        fake.take_sent_packets(&session).unwrap();
+        assert!(session.send_batch(&[&small, &oversized]).is_err());
+        assert!(fake.take_sent_packets(&session).unwrap().is_empty());
+    }
+
+    #[test]
+    fn send_batch_returns_other_errors() {
+        let (fake, session) = session();
+        let small: &[u8] = &[1, 2, 3];
+        fake.fail_next(FakeCall::AllocateSendPacket, ERROR_HANDLE_EOF);
+        assert!(matches!(
+            session.send_batch(&[small, small]),
+            Err(Error::AdapterGone { .. })
+        ));
+        assert!(fake.take_sent_packets(&session).unwrap().is_empty());
+
+        let oversized = vec![0; crate::MAX_IP_PACKET_SIZE as usize + 1];
+        assert!(matches!(
+            session.send_batch(&[small, &oversized]),
+            Err(Error::InvalidPacketSize { .. })
+        ));
+
+        //Packets that were reserved before the error are still sent
+        fake.fail_after(FakeCall::AllocateSendPacket, 2, ERROR_HANDLE_EOF);
+        assert!(matches!(
+            session.send_batch(&[small, &[4], &[5, 6]]),
+            Err(Error::AdapterGone { .. })
+        ));
+        assert_eq!(fake.take_sent_packets(&session).unwrap(), vec![small.to_vec(), vec![4]]);
+        assert_eq!(fake.unsent_packets(&session).unwrap(), 0);
+
+        fake.close_session_rings(&session).unwrap();
+        assert!(matches!(session.send_batch(&[small]), Err(Error::AdapterGone { .. })));
+    }
+
+    /// Sends a packet of the largest size, after which the smallest ring has no room for another
+    fn fill(session: &Arc<Session>) {
+        let packet = session.allocate_send_packet(MAX_SIZE).unwrap();
+        session.send_packet(packet);
+        assert!(matches!(
+            session.allocate_send_packet(MAX_SIZE),
+            Err(Error::RingFull { .. })
+        ));
+    }
+
+    const MAX_SIZE: u16 = crate::MAX_IP_PACKET_SIZE as u16;
+
+    #[test]
+    fn allocate_send_packet_timeout() {
+        let (_fake, session) = session();
+        fill(&session);
+        let start = Instant::now();
+        let packet = session.allocate_send_packet_timeout(MAX_SIZE, Duration::from_millis(50));
+        assert!(packet.unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        //The ring was polled many times, but that is one blocked wait
+        assert_eq!(session.stats().blocked_waits, 1);
+
+        //Packets that fit are allocated without waiting
+        let packet = session.allocate_send_packet_timeout(1, Duration::ZERO).unwrap();
+        session.send_packet(packet.unwrap());
+        assert_eq!(session.stats().blocked_waits, 1);
+    }
+
+    #[test]
+    fn allocate_send_packet_blocking_waits_for_space() {
+        let (fake, session) = session();
+        fill(&session);
+        let driver = std::thread::spawn({
+            let (fake, session) = (fake.clone(), session.clone());
+            move || {
+                std::thread::sleep(Duration::from_millis(50));
+                fake.take_sent_packets(&session).unwrap().len()
+            }
+        });
+        let packet = session.allocate_send_packet_blocking(MAX_SIZE).unwrap();
+        assert_eq!(driver.join().unwrap(), 1);
+        session.send_packet(packet);
+        assert_eq!(fake.take_sent_packets(&session).unwrap().len(), 1);
+        assert_eq!(session.stats().blocked_waits, 1);
+    }
+
+    #[test]
+    fn allocate_send_packet_blocking_shutdown() {
+        let (_fake, session) = session();
+        fill(&session);
+        let sender = session.clone();
+        let waiter = std::thread::spawn(move || sender.allocate_send_packet_blocking(MAX_SIZE).map(drop));
+        std::thread::sleep(Duration::from_millis(50));
+        session.shutdown().unwrap();
+        assert!(matches!(waiter.join().unwrap(), Err(Error::ShuttingDown)));
+        assert_eq!(session.stats().shutdown_wakeups, 1);
+    }
+
+    fn send(session: &Arc<Session>, bytes: &[u8]) {
+        let mut packet = session.allocate_send_packet(bytes.len() as u16).unwrap();
+        packet.bytes_mut().copy_from_slice(bytes);
+        session.send_packet(packet);
+    }
+
+    #[test]
+    fn unsent_packet_policy_count() {
+        let (fake, session) = session();
+        session.set_unsent_packet_policy(UnsentPacketPolicy::Count);
+        drop(session.allocate_send_packet(3).unwrap());
+        assert_eq!(session.unsent_packets_dropped(), 1);
+        assert_eq!(fake.unsent_packets(&session).unwrap(), 1);
+
+        //The dropped packet holds up everything allocated after it
+        send(&session, &[1, 2]);
+        assert!(fake.take_sent_packets(&session).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unsent_packet_policy_submit() {
+        let (fake, session) = session();
+        session.set_unsent_packet_policy(UnsentPacketPolicy::Submit);
+        let mut packet = session.allocate_send_packet(3).unwrap();
+        packet.bytes_mut().copy_from_slice(&[1, 2, 3]);
+        drop(packet);
+        assert_eq!(session.unsent_packets_dropped(), 1);
+        assert_eq!(fake.unsent_packets(&session).unwrap(), 0);
+
+        send(&session, &[1, 2]);
+        assert_eq!(fake.take_sent_packets(&session).unwrap(), vec![vec![0; 3], vec![1, 2]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Packet was never sent!")]
+    fn unsent_packet_policy_panic() {
+        let (_fake, session) = session();
+        session.set_unsent_packet_policy(UnsentPacketPolicy::Panic);
+        drop(session.allocate_send_packet(3).unwrap());
+    }
+}
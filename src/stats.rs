@@ -0,0 +1,112 @@
+//! Traffic counters kept by every [`Session`].
+
+use crate::{Error, Session};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A snapshot of the counters of a session, returned by [`Session::stats`]
+///
+/// Counters only ever increase. They are updated with relaxed atomics, so a snapshot taken while
+/// other threads use the session may be slightly inconsistent between fields
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct SessionStats {
+    /// Packets taken out of the receive ring
+    pub packets_received: u64,
+
+    /// Bytes of the packets taken out of the receive ring
+    pub bytes_received: u64,
+
+    /// Packets submitted to the send ring
+    pub packets_sent: u64,
+
+    /// Bytes of the packets submitted to the send ring
+    pub bytes_sent: u64,
+
+    /// Send packet allocations that failed for any reason other than shutdown, including
+    /// [`SessionStats::ring_full`]
+    pub allocation_failures: u64,
+
+    /// Send packet allocations that failed with [`Error::RingFull`]
+    pub ring_full: u64,
+
+    /// Times a blocking receive waited for the read event, plus blocking allocations that had to
+    /// wait for space in the send ring
+    pub blocked_waits: u64,
+
+    /// Times a blocking receive or allocation was woken by [`Session::shutdown`]
+    pub shutdown_wakeups: u64,
+
+    /// Packets rejected with [`Error::InvalidPacketSize`]
+    pub oversized_packets: u64,
+
+    /// Send packets dropped without being sent, see [`crate::UnsentPacketPolicy`]
+    pub unsent_packets_dropped: u64,
+}
+
+/// The live counters behind [`SessionStats`]
+#[derive(Default)]
+pub(crate) struct Counters {
+    packets_received: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    allocation_failures: AtomicU64,
+    ring_full: AtomicU64,
+    pub(crate) blocked_waits: AtomicU64,
+    pub(crate) shutdown_wakeups: AtomicU64,
+    pub(crate) oversized_packets: AtomicU64,
+    pub(crate) unsent_packets_dropped: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn received(&self, bytes: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn allocation_failed(&self, err: &Error) {
+        self.allocation_failures.fetch_add(1, Ordering::Relaxed);
+        match err {
+            Error::RingFull { .. } => self.ring_full.fetch_add(1, Ordering::Relaxed),
+            Error::InvalidPacketSize { .. } => self.oversized_packets.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn snapshot(&self) -> SessionStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        SessionStats {
+            packets_received: load(&self.packets_received),
+            bytes_received: load(&self.bytes_received),
+            packets_sent: load(&self.packets_sent),
+            bytes_sent: load(&self.bytes_sent),
+            allocation_failures: load(&self.allocation_failures),
+            ring_full: load(&self.ring_full),
+            blocked_waits: load(&self.blocked_waits),
+            shutdown_wakeups: load(&self.shutdown_wakeups),
+            oversized_packets: load(&self.oversized_packets),
+            unsent_packets_dropped: load(&self.unsent_packets_dropped),
+        }
+    }
+}
+
+impl Session {
+    /// Returns a snapshot of this session's traffic counters
+    ///
+    /// # Example
+    /// ```
+    /// let (a, b) = wintun::Session::pair().unwrap();
+    /// a.send_batch(&[&[0x45, 0, 0, 4]]).unwrap();
+    /// b.receive_blocking().unwrap();
+    ///
+    /// assert_eq!(a.stats().packets_sent, 1);
+    /// assert_eq!(b.stats().bytes_received, 4);
+    /// ```
+    pub fn stats(&self) -> SessionStats {
+        self.counters.snapshot()
+    }
+}
@@ -0,0 +1,136 @@
+//! [`futures_core::Stream`] and [`futures_sink::Sink`] adapters for a [`Session`].
+
+use crate::{session::SEND_RETRY_INTERVAL, timer, Error, Readiness, RecvPacket, Session};
+use bytes::Bytes;
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// The packets received by a session, created with [`Session::into_stream`]
+///
+/// Ends once the session is shut down
+pub struct PacketStream {
+    readiness: Readiness,
+    done: bool,
+}
+
+impl Stream for PacketStream {
+    type Item = Result<RecvPacket, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        match self.readiness.poll_recv(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(Error::ShuttingDown)) => {
+                self.done = true;
+                Poll::Ready(None)
+            }
+            Poll::Ready(result) => Poll::Ready(Some(result)),
+        }
+    }
+}
+
+/// Sends each item as one packet through a session, created with [`Session::into_sink`]
+///
+/// While the send ring is full, [`Sink::poll_ready`] returns `Poll::Pending` until it has room for
+/// the packet that did not fit
+pub struct PacketSink {
+    session: Arc<Session>,
+    /// A packet that did not fit in the send ring
+    pending: Option<Bytes>,
+}
+
+impl PacketSink {
+    /// Tries to send `bytes`, returning them back if the send ring is full
+    fn try_send(&self, bytes: Bytes) -> Result<Option<Bytes>, Error> {
+        let size = self.session.packet_size(bytes.len())?;
+        match self.session.allocate_send_packet(size) {
+            Ok(mut packet) => {
+                packet.bytes_mut().copy_from_slice(&bytes);
+                self.session.send_packet(packet);
+                Ok(None)
+            }
+            Err(Error::RingFull { .. }) => Ok(Some(bytes)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let Some(bytes) = self.pending.take() else {
+            return Poll::Ready(Ok(()));
+        };
+        self.pending = self.try_send(bytes)?;
+        if self.pending.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        timer::wake_after(SEND_RETRY_INTERVAL, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Sink<Bytes> for PacketSink {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        assert!(this.pending.is_none(), "start_send called without poll_ready");
+        this.pending = this.try_send(item)?;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+}
+
+impl Session {
+    /// Returns a stream of the packets received by this session. The stream ends once
+    /// [`Session::shutdown`] is called
+    ///
+    /// # Example
+    /// ```
+    /// use futures::{SinkExt, StreamExt};
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), wintun::Error> {
+    /// let (a, b) = wintun::Session::pair()?;
+    /// let mut sink = a.into_sink();
+    /// let mut stream = b.clone().into_stream();
+    ///
+    /// sink.send(bytes::Bytes::from_static(&[0x45, 0, 0, 20])).await?;
+    /// assert_eq!(stream.next().await.unwrap()?.bytes(), &[0x45, 0, 0, 20]);
+    ///
+    /// b.shutdown()?;
+    /// assert!(stream.next().await.is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_stream(self: Arc<Self>) -> PacketStream {
+        PacketStream {
+            readiness: self.readiness(),
+            done: false,
+        }
+    }
+
+    /// Returns a sink that sends each item as a packet through this session
+    pub fn into_sink(self: Arc<Self>) -> PacketSink {
+        PacketSink {
+            session: self,
+            pending: None,
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Delayed wakeups that do not depend on an async runtime.
+//!
+//! Wintun does not signal when space frees up in the send ring, so futures waiting for it poll
+//! again after a short delay. A single background thread, started on first use, wakes them.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    sync::{Condvar, Mutex, Once},
+    task::Waker,
+    time::{Duration, Instant},
+};
+
+struct Timer {
+    deadline: Instant,
+    /// Keeps timers with the same deadline in the order they were added
+    seq: u64,
+    waker: Waker,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        (self.deadline, self.seq) == (other.deadline, other.seq)
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
+
+struct Timers {
+    next_seq: u64,
+    queue: BinaryHeap<Reverse<Timer>>,
+}
+
+static TIMERS: Mutex<Timers> = Mutex::new(Timers {
+    next_seq: 0,
+    queue: BinaryHeap::new(),
+});
+static CHANGED: Condvar = Condvar::new();
+static START: Once = Once::new();
+
+fn run() {
+    let mut timers = TIMERS.lock().unwrap_or_else(|e| e.into_inner());
+    loop {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        while timers.queue.peek().is_some_and(|timer| timer.0.deadline <= now) {
+            expired.push(timers.queue.pop().unwrap().0.waker);
+        }
+        if !expired.is_empty() {
+            //Wake outside of the lock in case a waker adds a new timer
+            drop(timers);
+            expired.into_iter().for_each(Waker::wake);
+            timers = TIMERS.lock().unwrap_or_else(|e| e.into_inner());
+            continue;
+        }
+        timers = match timers.queue.peek() {
+            Some(timer) => {
+                let timeout = timer.0.deadline - now;
+                CHANGED
+                    .wait_timeout(timers, timeout)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0
+            }
+            None => CHANGED.wait(timers).unwrap_or_else(|e| e.into_inner()),
+        };
+    }
+}
+
+/// Wakes `waker` once `delay` has passed
+pub(crate) fn wake_after(delay: Duration, waker: Waker) {
+    START.call_once(|| {
+        std::thread::Builder::new()
+            .name("wintun-timer".to_owned())
+            .spawn(run)
+            .expect("Failed to spawn timer thread");
+    });
+    let mut timers = TIMERS.lock().unwrap_or_else(|e| e.into_inner());
+    let seq = timers.next_seq;
+    timers.next_seq += 1;
+    timers.queue.push(Reverse(Timer {
+        deadline: Instant::now() + delay,
+        seq,
+        waker,
+    }));
+    CHANGED.notify_one();
+}
@@ -1,8 +1,9 @@
 use crate::Error;
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
-use windows_sys::{
-    core::GUID,
-    Win32::{
+use windows_sys::core::GUID;
+#[cfg(windows)]
+use {
+    std::net::{IpAddr, Ipv6Addr, SocketAddr},
+    windows_sys::Win32::{
         Foundation::{
             GetLastError, LocalFree, ERROR_BUFFER_OVERFLOW, ERROR_INSUFFICIENT_BUFFER, ERROR_SUCCESS, NO_ERROR,
             WIN32_ERROR,
@@ -30,6 +31,37 @@ pub(crate) const fn win_guid_to_u128(guid: &GUID) -> u128 {
     ((guid.data1 as u128) << 96) | ((guid.data2 as u128) << 80) | ((guid.data3 as u128) << 64) | (data4_u64 as u128)
 }
 
+/// Formats `guid` like `{6BA7B810-9DAD-11D1-80B4-00C04FD430C8}`, the way Windows displays it
+pub(crate) fn guid_to_string(guid: u128) -> String {
+    format!(
+        "{{{:08X}-{:04X}-{:04X}-{:04X}-{:012X}}}",
+        guid >> 96,
+        (guid >> 80) & 0xFFFF,
+        (guid >> 64) & 0xFFFF,
+        (guid >> 48) & 0xFFFF,
+        guid & 0xFFFF_FFFF_FFFF
+    )
+}
+
+/// Generates a new random GUID
+#[cfg(windows)]
+pub(crate) fn new_guid() -> u128 {
+    let mut guid: GUID = unsafe { std::mem::zeroed() };
+    unsafe { windows_sys::Win32::System::Rpc::UuidCreate(&mut guid as *mut GUID) };
+    win_guid_to_u128(&guid)
+}
+
+/// Generates a new random GUID
+#[cfg(not(windows))]
+pub(crate) fn new_guid() -> u128 {
+    use std::hash::{BuildHasher, RandomState};
+    //Each RandomState is seeded with fresh random keys, which is plenty for adapters that only
+    //ever exist in a FakeWintun
+    let (high, low) = (RandomState::new().hash_one(0u8), RandomState::new().hash_one(1u8));
+    ((high as u128) << 64) | low as u128
+}
+
+#[cfg(windows)]
 pub(crate) unsafe fn win_pstr_to_string(pstr: ::windows_sys::core::PSTR) -> Result<String, Error> {
     Ok(std::ffi::CStr::from_ptr(pstr as *const std::ffi::c_char)
         .to_str()
@@ -49,11 +81,17 @@ pub(crate) unsafe fn win_pwstr_to_string(pwstr: ::windows_sys::core::PWSTR) -> R
 
     let slice = std::slice::from_raw_parts(pwstr, len);
 
-    use std::os::windows::ffi::OsStringExt;
-    let os_string = std::ffi::OsString::from_wide(slice);
-    os_string
-        .into_string()
-        .map_err(|e| format!("Invalid UTF-8 sequence: {:?}", e).into())
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStringExt;
+        let os_string = std::ffi::OsString::from_wide(slice);
+        os_string
+            .into_string()
+            .map_err(|e| format!("Invalid UTF-8 sequence: {:?}", e).into())
+    }
+    //OsStringExt only exists on Windows
+    #[cfg(not(windows))]
+    String::from_utf16(slice).map_err(|e| format!("Invalid UTF-16 sequence: {:?}", e).into())
 }
 
 /// A wrapper struct that allows a type to be Send and Sync
@@ -65,6 +103,7 @@ pub(crate) struct UnsafeHandle<T>(pub T);
 unsafe impl<T> Send for UnsafeHandle<T> {}
 unsafe impl<T> Sync for UnsafeHandle<T> {}
 
+#[cfg(windows)]
 pub(crate) fn guid_to_win_style_string(guid: &GUID) -> Result<String, Error> {
     let mut buffer = [0u16; 40];
     unsafe { StringFromGUID2(guid, &mut buffer as *mut u16, buffer.len() as i32) };
@@ -72,6 +111,7 @@ pub(crate) fn guid_to_win_style_string(guid: &GUID) -> Result<String, Error> {
     Ok(guid)
 }
 
+#[cfg(windows)]
 pub(crate) fn ipv6_netmask_for_prefix(prefix: u8) -> Result<Ipv6Addr, &'static str> {
     if prefix > 128 {
         return Err("Prefix value must be between 0 and 128.");
@@ -94,6 +134,7 @@ pub(crate) fn ipv6_netmask_for_prefix(prefix: u8) -> Result<Ipv6Addr, &'static s
 
 /// Returns the active network interface's gateway addresses,
 /// for convenience to user to configure routing table.
+#[cfg(windows)]
 pub fn get_active_network_interface_gateways() -> std::io::Result<Vec<IpAddr>> {
     let mut addrs = vec![];
     get_adapters_addresses(|adapter| {
@@ -117,6 +158,7 @@ pub fn get_active_network_interface_gateways() -> std::io::Result<Vec<IpAddr>> {
     Ok(addrs)
 }
 
+#[cfg(windows)]
 pub(crate) fn set_interface_dns_servers(interface: GUID, dns: &[IpAddr]) -> crate::Result<()> {
     // format L"1.1.1.1,8.8.8.8", or L"1.1.1.1 8.8.8.8".
     let dns = dns.iter().map(|ip| ip.to_string()).collect::<Vec<_>>().join(",");
@@ -148,6 +190,7 @@ pub(crate) fn set_interface_dns_servers(interface: GUID, dns: &[IpAddr]) -> crat
     }
 }
 
+#[cfg(windows)]
 pub(crate) fn set_adapter_dns_servers(adapter: &str, dns: &[IpAddr]) -> crate::Result<()> {
     if dns.is_empty() {
         return Ok(());
@@ -172,10 +215,12 @@ pub(crate) fn set_adapter_dns_servers(adapter: &str, dns: &[IpAddr]) -> crate::R
     Ok(())
 }
 
+#[cfg(windows)]
 pub(crate) fn retrieve_ipaddr_from_socket_address(address: &SOCKET_ADDRESS) -> Result<IpAddr, Error> {
     unsafe { Ok(sockaddr_to_socket_addr(address.lpSockaddr)?.ip()) }
 }
 
+#[cfg(windows)]
 pub(crate) unsafe fn sockaddr_to_socket_addr(sock_addr: *const SOCKADDR) -> std::io::Result<SocketAddr> {
     use std::io::{Error, ErrorKind};
     let address = match (*sock_addr).sa_family {
@@ -186,6 +231,7 @@ pub(crate) unsafe fn sockaddr_to_socket_addr(sock_addr: *const SOCKADDR) -> std:
     Ok(address)
 }
 
+#[cfg(windows)]
 pub(crate) unsafe fn sockaddr_in_to_socket_addr(sockaddr_in: &SOCKADDR_IN) -> SocketAddr {
     let ip_bytes = sockaddr_in.sin_addr.S_un.S_addr.to_ne_bytes();
     let ip = std::net::IpAddr::from(ip_bytes);
@@ -193,12 +239,14 @@ pub(crate) unsafe fn sockaddr_in_to_socket_addr(sockaddr_in: &SOCKADDR_IN) -> So
     SocketAddr::new(ip, port)
 }
 
+#[cfg(windows)]
 pub(crate) unsafe fn sockaddr_in6_to_socket_addr(sockaddr_in6: &SOCKADDR_IN6) -> SocketAddr {
     let ip = std::net::IpAddr::from(sockaddr_in6.sin6_addr.u.Byte);
     let port = u16::from_be(sockaddr_in6.sin6_port);
     SocketAddr::new(ip, port)
 }
 
+#[cfg(windows)]
 pub(crate) fn get_adapters_addresses<F>(mut callback: F) -> Result<(), Error>
 where
     F: FnMut(IP_ADAPTER_ADDRESSES_LH) -> Result<(), Error>,
@@ -238,6 +286,7 @@ where
     Ok(())
 }
 
+#[cfg(windows)]
 fn get_interface_info_sys<F>(mut callback: F) -> Result<(), Error>
 where
     F: FnMut(IP_ADAPTER_INDEX_MAP) -> Result<(), Error>,
@@ -317,6 +366,7 @@ where
     Ok(())
 }
 
+#[cfg(windows)]
 #[allow(dead_code)]
 pub(crate) fn get_interface_info() -> Result<Vec<(u32, String)>, Error> {
     let mut v = vec![];
@@ -335,6 +385,7 @@ pub(crate) fn get_interface_info() -> Result<Vec<(u32, String)>, Error> {
     Ok(v)
 }
 
+#[cfg(windows)]
 #[allow(non_snake_case)]
 #[inline]
 fn MAKELANGID(p: u32, s: u32) -> u32 {
@@ -342,6 +393,7 @@ fn MAKELANGID(p: u32, s: u32) -> u32 {
 }
 
 /// Returns a a human readable error message from a windows error code
+#[cfg(windows)]
 pub fn format_message(error_code: u32) -> Result<String, Box<dyn std::error::Error>> {
     let buf: *mut u16 = std::ptr::null_mut();
 
@@ -368,11 +420,13 @@ pub fn format_message(error_code: u32) -> Result<String, Box<dyn std::error::Err
     Ok(result)
 }
 
+#[cfg(windows)]
 pub(crate) fn get_last_error() -> std::io::Result<String> {
     get_os_error_from_id(unsafe { GetLastError() as _ })?;
     Ok("No error".to_string())
 }
 
+#[cfg(windows)]
 pub(crate) fn get_os_error_from_id(id: i32) -> std::io::Result<()> {
     match id {
         0 => Ok(()),
@@ -380,6 +434,7 @@ pub(crate) fn get_os_error_from_id(id: i32) -> std::io::Result<()> {
     }
 }
 
+#[cfg(windows)]
 pub(crate) fn set_adapter_mtu(name: &str, mtu: usize) -> std::io::Result<()> {
     // command line: `netsh interface ipv4 set subinterface "MyAdapter" mtu=1500 store=persistent`
     let args = &[
@@ -396,6 +451,7 @@ pub(crate) fn set_adapter_mtu(name: &str, mtu: usize) -> std::io::Result<()> {
 }
 
 /// Runs a command and returns an error if the command fails, just convenience for users.
+#[cfg(windows)]
 #[doc(hidden)]
 pub fn run_command(command: &str, args: &[&str]) -> std::io::Result<Vec<u8>> {
     let out = std::process::Command::new(command).args(args).output()?;
@@ -411,6 +467,7 @@ pub fn run_command(command: &str, args: &[&str]) -> std::io::Result<Vec<u8>> {
     Ok(out.stdout)
 }
 
+#[cfg(windows)]
 pub(crate) fn get_adapter_mtu(luid: &NET_LUID_LH) -> std::io::Result<usize> {
     unsafe {
         let mut if_table: *mut MIB_IF_TABLE2 = std::ptr::null_mut();
@@ -442,6 +499,7 @@ pub(crate) fn get_adapter_mtu(luid: &NET_LUID_LH) -> std::io::Result<usize> {
     }
 }
 
+#[cfg(windows)]
 #[repr(C, align(1))]
 #[derive(c2rust_bitfields::BitfieldStruct)]
 #[allow(non_snake_case)]